@@ -7,7 +7,7 @@ use parameter_store::*;
 #[derive(Parser, Debug)]
 struct Args {
     file: PathBuf,
-    /// Set the IR interface address
+    /// Set the IR interface address (0 to let the device pick one)
     #[arg(long)]
     ir_address: Option<u16>,
     /// Set the IR peer addresses
@@ -16,6 +16,25 @@ struct Args {
     /// Set the device's color r,g,b
     #[arg(long)]
     color: Option<String>,
+    /// Set the team secret used to authenticate sync traffic, as 64 hex digits
+    #[arg(long)]
+    team_secret: Option<String>,
+    /// Set the Wi-Fi network to join (an empty string to not join one)
+    #[arg(long)]
+    wifi_ssid: Option<String>,
+    /// Set the Wi-Fi password (leave unset for an open network)
+    #[arg(long, requires = "wifi_ssid")]
+    wifi_password: Option<String>,
+    /// Set the ESP-NOW channel, which must be the same for the whole team (1-13)
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=13))]
+    esp_now_channel: Option<u8>,
+    /// Set the ESP-NOW PHY rate in Mbps (1m, 2m, 5.5m, 11m, 6m, 9m, 12m, 18m, 24m, 36m, 48m or
+    /// 54m)
+    #[arg(long)]
+    esp_now_rate: Option<PhyRate>,
+    /// Set the most ESP-NOW transmit power in dBm (2-20)
+    #[arg(long, value_parser = clap::value_parser!(u8).range(2..=20))]
+    esp_now_tx_power: Option<u8>,
     #[arg(short, long)]
     create: bool,
     #[arg(short, long)]
@@ -65,6 +84,41 @@ pub fn main() -> anyhow::Result<()> {
             blue: components[2],
         }
     }
+    if let Some(team_secret) = args.team_secret {
+        params.team_secret = Some(parse_team_secret(&team_secret)?);
+        modified = true;
+    }
+    if let Some(ssid) = args.wifi_ssid {
+        params.wifi = if ssid.is_empty() {
+            None
+        } else {
+            Some(WifiCredentials {
+                ssid: ssid
+                    .as_str()
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("SSID must be at most 32 bytes"))?,
+                password: args
+                    .wifi_password
+                    .as_deref()
+                    .unwrap_or_default()
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("Wi-Fi password must be at most 64 bytes"))?,
+            })
+        };
+        modified = true;
+    }
+    if let Some(channel) = args.esp_now_channel {
+        params.radio.channel = channel;
+        modified = true;
+    }
+    if let Some(rate) = args.esp_now_rate {
+        params.radio.rate = rate;
+        modified = true;
+    }
+    if let Some(tx_power) = args.esp_now_tx_power {
+        params.radio.tx_power = tx_power;
+        modified = true;
+    }
 
     if modified {
         store.store(&params)?;
@@ -91,9 +145,44 @@ pub fn main() -> anyhow::Result<()> {
                 })
                 .unwrap_or(String::from("None"))
         );
-        println!("IR address: {}", params.address);
+        if params.address == 0 && params.auto_address == 0 {
+            println!("IR address: auto");
+        } else if params.address == 0 {
+            println!("IR address: auto ({})", params.auto_address);
+        } else {
+            println!("IR address: {}", params.address);
+        }
         println!("IR peer addresses: {:?}", params.peers);
         println!("Color: {:?}", params.color);
+        println!(
+            "Team secret: {}",
+            if params.team_secret.is_some() {
+                "set"
+            } else {
+                "not set"
+            }
+        );
+        match &params.wifi {
+            Some(wifi) if wifi.password.is_empty() => println!("Wi-Fi: {} (open)", wifi.ssid),
+            Some(wifi) => println!("Wi-Fi: {} (password set)", wifi.ssid),
+            None => println!("Wi-Fi: not set"),
+        }
+        println!(
+            "ESP-NOW radio: channel {}, rate {}, {} dBm",
+            params.radio.channel, params.radio.rate, params.radio.tx_power
+        );
+        println!("Power-on count: {}", params.boot_count);
     }
     Ok(())
 }
+
+fn parse_team_secret(s: &str) -> anyhow::Result<[u8; 32]> {
+    if s.len() != 64 || !s.is_ascii() {
+        anyhow::bail!("team secret must be 64 hex digits");
+    }
+    let mut secret = [0u8; 32];
+    for (i, b) in secret.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)?;
+    }
+    Ok(secret)
+}
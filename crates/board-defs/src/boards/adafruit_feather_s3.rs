@@ -28,6 +28,8 @@ macro_rules! board_def {
                 rx: $peripherals.GPIO38.degrade(),
                 en: $peripherals.GPIO8.degrade(),
             }),
+            // The TX and RX pins are taken by the IR transceiver
+            wired: None,
             indicators: $crate::IndicatorsPinDef {
                 tx_led: None,
                 rx_led: None,
@@ -18,6 +18,10 @@ macro_rules! board_def {
             },
             sd: None,
             ir: None,
+            wired: Some($crate::UartPinDef {
+                tx: $peripherals.GPIO5.degrade(),
+                rx: $peripherals.GPIO16.degrade(),
+            }),
             indicators: $crate::IndicatorsPinDef {
                 tx_led: None,
                 rx_led: None,
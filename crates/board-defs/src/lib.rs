@@ -44,6 +44,12 @@ pub struct IrPinDef {
     pub en: AnyPin,
 }
 
+/// A plain UART for cabling boards together.
+pub struct UartPinDef {
+    pub tx: AnyPin,
+    pub rx: AnyPin,
+}
+
 pub struct IndicatorsPinDef {
     pub tx_led: Option<AnyPin>,
     pub rx_led: Option<AnyPin>,
@@ -56,5 +62,6 @@ pub struct BoardDef {
     pub i2c: I2CPinDef,
     pub sd: Option<SdPinDef>,
     pub ir: Option<IrPinDef>,
+    pub wired: Option<UartPinDef>,
     pub indicators: IndicatorsPinDef,
 }
@@ -11,18 +11,22 @@ use core::fmt;
 use aranya_crypto::DeviceId;
 use aranya_policy_vm::Text;
 use aranya_runtime::{CmdId, VmEffect};
-use embassy_futures::select::{select3, Either3};
+use embassy_futures::select::{select4, Either4};
 use embassy_time::Instant;
 use esp_println::println;
 use spideroak_base58::ToBase58;
 
+#[cfg(feature = "net-esp-now")]
+use crate::net::radio;
 use crate::{
     application::serial::{SerialCommand, SerialResponse},
     aranya::{
-        daemon::{ACTION_IN_CHANNEL, EFFECT_OUT_CHANNEL},
-        policy,
+        daemon::{ACTION_IN_CHANNEL, EFFECT_OUT_CHANNEL, SYNC_EVENT_CHANNEL},
+        neighbors, policy,
+        stats::{self, SyncEventKind},
     },
     hardware::neopixel::{MessageState, NeopixelMessage, NEOPIXEL_SIGNAL},
+    net::impair,
     vm_action_owned,
 };
 
@@ -75,6 +79,8 @@ pub struct Application {
     chat_buffer: heapless::spsc::Queue<Box<ChatMessage>, 100>,
     unseen_count: usize,
     mentioned: bool,
+    /// Sync sessions currently running
+    syncing: usize,
 }
 
 impl Application {
@@ -84,6 +90,7 @@ impl Application {
             chat_buffer: heapless::spsc::Queue::new(),
             unseen_count: 0,
             mentioned: false,
+            syncing: 0,
         }
     }
 
@@ -91,18 +98,22 @@ impl Application {
         let mut effect_subscriber = EFFECT_OUT_CHANNEL
             .subscriber()
             .expect("application could not get subscriber slot");
+        let mut sync_subscriber = SYNC_EVENT_CHANNEL
+            .subscriber()
+            .expect("application could not get sync event subscriber slot");
         let truncated_device_id: heapless::String<8> =
             self.device_id.to_base58().chars().take(8).collect();
 
         loop {
-            let selected = select3(
+            let selected = select4(
                 effect_subscriber.next_message_pure(),
                 SERIAL_IN_CHANNEL.receive(),
                 BUTTON_CHANNEL.receive(),
+                sync_subscriber.next_message_pure(),
             )
             .await;
             match selected {
-                Either3::First(effect) => {
+                Either4::First(effect) => {
                     if effect.recalled {
                         continue;
                     }
@@ -142,7 +153,7 @@ impl Application {
                         _ => (),
                     };
                 }
-                Either3::Second(ser_cmd) => {
+                Either4::Second(ser_cmd) => {
                     println!("application received command: {ser_cmd:?}");
                     match ser_cmd {
                         SerialCommand::SendMessage(msg) => {
@@ -178,13 +189,68 @@ impl Application {
                             // TODO: send the action
                             SERIAL_OUT_CHANNEL.send(SerialResponse::Sent).await;
                         }
+                        SerialCommand::GetTopology => {
+                            SERIAL_OUT_CHANNEL
+                                .send(SerialResponse::Topology(neighbors::topology()))
+                                .await;
+                        }
+                        SerialCommand::GetStats => {
+                            SERIAL_OUT_CHANNEL
+                                .send(SerialResponse::Stats(stats::stats()))
+                                .await;
+                        }
+                        SerialCommand::Impair(settings) => {
+                            impair::update(|i| {
+                                if let Err(e) = i.apply(&settings) {
+                                    log::error!("impair: {e}");
+                                }
+                            });
+                            let impairment = impair::get();
+                            log::info!("network impairment: {impairment}");
+                            SERIAL_OUT_CHANNEL
+                                .send(SerialResponse::Impairment(impairment))
+                                .await;
+                        }
+                        #[cfg(feature = "net-esp-now")]
+                        SerialCommand::Radio(settings) => {
+                            let report = match radio::request(&settings).await {
+                                Ok(report) => report,
+                                Err(e) => {
+                                    log::error!("radio: {e}");
+                                    radio::RadioReport {
+                                        settings: radio::get(),
+                                        survey: None,
+                                    }
+                                }
+                            };
+                            log::info!("radio settings: {report}");
+                            SERIAL_OUT_CHANNEL.send(SerialResponse::Radio(report)).await;
+                        }
                     }
                 }
-                Either3::Third(_) => {
+                Either4::Third(_) => {
                     self.unseen_count = 0;
                     self.mentioned = false;
                     self.update_neopixel();
                 }
+                Either4::Fourth(event) => {
+                    log::info!(
+                        "sync {:?} with {} on {}",
+                        event.kind,
+                        event.peer,
+                        event.transport
+                    );
+                    let was_syncing = self.syncing > 0;
+                    match event.kind {
+                        SyncEventKind::Started => self.syncing += 1,
+                        SyncEventKind::Finished | SyncEventKind::Failed => {
+                            self.syncing = self.syncing.saturating_sub(1)
+                        }
+                    }
+                    if was_syncing != (self.syncing > 0) {
+                        self.update_neopixel();
+                    }
+                }
             }
             println!("application processing done");
         }
@@ -194,6 +260,7 @@ impl Application {
         NEOPIXEL_SIGNAL.signal(NeopixelMessage::MessageState(MessageState {
             unseen_count: self.unseen_count,
             mentioned: self.mentioned,
+            syncing: self.syncing > 0,
         }));
     }
 }
@@ -13,6 +13,8 @@ use embassy_futures::{
     select::{select, Either},
 };
 use embassy_time::Instant;
+#[cfg(feature = "net-usb")]
+use embassy_time::{with_timeout, Duration};
 use embassy_usb::{
     class::cdc_acm,
     driver::EndpointError,
@@ -24,14 +26,22 @@ use esp_hal::{gpio::GpioPin, otg_fs, peripherals::USB0};
 use esp_println::println;
 use spideroak_base58::ToBase58;
 
+#[cfg(feature = "net-esp-now")]
+use crate::net::radio::RadioReport;
 use crate::{
     application::{ChatMessage, SERIAL_IN_CHANNEL, SERIAL_OUT_CHANNEL},
-    aranya::policy,
+    aranya::{neighbors::NeighborReport, policy, stats::StatsReport},
+    net::impair::Impairment,
 };
 
 const MAX_SERIAL_PACKET_SIZE: u16 = 64;
+/// The most bytes of data a command can carry, which also caps the text of a chat message.
+pub(crate) const MAX_DATA_LEN: usize = 65;
 const WEB_SOURCE: &'static str = include_str!("../../web/client.html");
 const DEVICE_INTERFACE_GUIDS: &[&str] = &["{63788892-2A36-4357-AFD0-008A6570D80A}"];
+/// How long to wait for the host to take a packet on the network link before dropping it.
+#[cfg(feature = "net-usb")]
+const USB_LINK_WRITE_TIMEOUT: Duration = Duration::from_millis(100);
 
 #[derive(Debug)]
 pub enum SerialCommand {
@@ -39,6 +49,13 @@ pub enum SerialCommand {
     GetMessages(Instant),
     Rainbow,
     SetAmbientColor(policy::AmbientColor),
+    GetTopology,
+    GetStats,
+    /// Change the network impairment, or just report it if empty
+    Impair(String),
+    /// Change the radio settings, or just report them if empty
+    #[cfg(feature = "net-esp-now")]
+    Radio(String),
 }
 
 #[derive(Debug)]
@@ -47,6 +64,15 @@ pub enum SerialResponse {
     MessageData(Vec<ChatMessage>),
     // A message has been successfully sent
     Sent,
+    // Response from a 'topology' query
+    Topology(Vec<NeighborReport>),
+    // Response from a 'stats' query
+    Stats(Vec<StatsReport>),
+    // Response from an 'impair' command
+    Impairment(Impairment),
+    // Response from a 'radio' command
+    #[cfg(feature = "net-esp-now")]
+    Radio(RadioReport),
 }
 
 #[embassy_executor::task]
@@ -87,6 +113,8 @@ pub async fn usb_serial_task(
     let mut control_buf = [0u8; 64];
 
     let mut state = cdc_acm::State::new();
+    #[cfg(feature = "net-usb")]
+    let mut link_state = cdc_acm::State::new();
     let mut builder = Builder::new(
         driver,
         config,
@@ -112,6 +140,11 @@ pub async fn usb_serial_task(
             msos::PropertyData::RegMultiSz(DEVICE_INTERFACE_GUIDS),
         ));
 
+    // The network link is a second serial port, so the console is left alone
+    #[cfg(feature = "net-usb")]
+    let link_class =
+        cdc_acm::CdcAcmClass::new(&mut builder, &mut link_state, MAX_SERIAL_PACKET_SIZE);
+
     let mut usb = builder.build();
     let usb_fut = usb.run();
 
@@ -122,10 +155,57 @@ pub async fn usb_serial_task(
             sce.io_loop().await.expect("USB failure");
         }
     };
+    #[cfg(feature = "net-usb")]
+    let app_fut = join(app_fut, usb_link_loop(link_class));
 
     join(usb_fut, app_fut).await;
 }
 
+/// Moves the USB network link's bytes between its serial port and the engine's pipes.
+#[cfg(feature = "net-usb")]
+async fn usb_link_loop(class: cdc_acm::CdcAcmClass<'_, otg_fs::asynch::Driver<'_>>) {
+    use crate::net::usb::{FROM_HOST, TO_HOST};
+
+    let (mut tx, mut rx) = class.split();
+    let mut out = [0u8; MAX_SERIAL_PACKET_SIZE as usize];
+    let mut inp = [0u8; MAX_SERIAL_PACKET_SIZE as usize];
+    loop {
+        // Until a host is attached, what we send goes nowhere
+        let discard = async {
+            loop {
+                TO_HOST.read(&mut out).await;
+            }
+        };
+        select(rx.wait_connection(), discard).await;
+        log::info!("usb link: connected");
+
+        let to_host = async {
+            loop {
+                let n = TO_HOST.read(&mut out).await;
+                // Without DTR no program on the host has the port open
+                if !tx.dtr() {
+                    continue;
+                }
+                match with_timeout(USB_LINK_WRITE_TIMEOUT, tx.write_packet(&out[..n])).await {
+                    Ok(Ok(())) => (),
+                    Ok(Err(e)) => break e,
+                    Err(_) => log::debug!("usb link: host not reading; dropped {n} bytes"),
+                }
+            }
+        };
+        let from_host = async {
+            loop {
+                match rx.read_packet(&mut inp).await {
+                    Ok(n) => FROM_HOST.write_all(&inp[..n]).await,
+                    Err(e) => break e,
+                }
+            }
+        };
+        let (Either::First(e) | Either::Second(e)) = select(to_host, from_host).await;
+        log::info!("usb link: disconnected ({e:?})");
+    }
+}
+
 #[derive(Debug)]
 enum SerialCommandState {
     Idle,
@@ -229,7 +309,7 @@ impl<'d, 'a> SerialCommandEngine<'d, 'a> {
                                     self.handle_serial_command(&command, &data).await;
                                     scs = SerialCommandState::Idle;
                                 }
-                                c if valid_text_char(c) && data.len() <= 64 => {
+                                c if valid_text_char(c) && data.len() < MAX_DATA_LEN => {
                                     data.push(c.into()).ok();
                                 }
                                 _ => {
@@ -257,6 +337,87 @@ impl<'d, 'a> SerialCommandEngine<'d, 'a> {
                         self.send_response("msgdata", &msgbuf).await?;
                     }
                     SerialResponse::Sent => self.send_response("sent", &[]).await?,
+                    SerialResponse::Topology(neighbors) => {
+                        let now = Instant::now();
+                        let mut topobuf = BytesMut::with_capacity(256);
+                        for n in neighbors {
+                            // transport address age_ms rssi finished/started max_cut health neighbors
+                            write!(
+                                topobuf,
+                                "{} {} {} ",
+                                n.transport,
+                                n.address,
+                                (now - n.last_seen).as_millis()
+                            )
+                            .expect("topology should fit");
+                            match n.rssi {
+                                Some(rssi) => write!(topobuf, "{rssi} "),
+                                None => write!(topobuf, "- "),
+                            }
+                            .expect("topology should fit");
+                            write!(topobuf, "{}/{} ", n.syncs_finished, n.syncs_started)
+                                .expect("topology should fit");
+                            match n.head {
+                                Some(head) => write!(topobuf, "{} ", head.max_cut),
+                                None => write!(topobuf, "- "),
+                            }
+                            .expect("topology should fit");
+                            // Either the penalty or "ban" and the seconds left in the ban
+                            match n.banned_for {
+                                Some(left) => write!(topobuf, "ban{} ", left.as_secs()),
+                                None => write!(topobuf, "{} ", n.penalty),
+                            }
+                            .expect("topology should fit");
+                            if n.neighbors.is_empty() {
+                                write!(topobuf, "-{}", ETX as char)
+                            } else {
+                                write!(topobuf, "{}{}", n.neighbors.join(","), ETX as char)
+                            }
+                            .expect("topology should fit");
+                        }
+
+                        self.send_response("topology", &topobuf).await?;
+                    }
+                    SerialResponse::Stats(reports) => {
+                        let mut statsbuf = BytesMut::with_capacity(256);
+                        for r in reports {
+                            // transport peer hellos_sent/received started/completed/stalled
+                            // commands bytes_sent/received commit_ms bad_mac replayed
+                            let c = &r.counters;
+                            write!(
+                                statsbuf,
+                                "{} {} {}/{} {}/{}/{} {} {}/{} {} {} {}{}",
+                                r.transport,
+                                r.peer.as_deref().unwrap_or("*"),
+                                c.hellos_sent,
+                                c.hellos_received,
+                                c.sessions_started,
+                                c.sessions_completed,
+                                c.sessions_stalled,
+                                c.commands_received,
+                                c.bytes_sent,
+                                c.bytes_received,
+                                c.commit_time.as_millis(),
+                                c.bad_mac,
+                                c.replayed,
+                                ETX as char
+                            )
+                            .expect("stats should fit");
+                        }
+
+                        self.send_response("stats", &statsbuf).await?;
+                    }
+                    SerialResponse::Impairment(impairment) => {
+                        let mut impairbuf = BytesMut::with_capacity(64);
+                        write!(impairbuf, "{impairment}").expect("impairment should fit");
+                        self.send_response("impair", &impairbuf).await?;
+                    }
+                    #[cfg(feature = "net-esp-now")]
+                    SerialResponse::Radio(report) => {
+                        let mut radiobuf = BytesMut::with_capacity(160);
+                        write!(radiobuf, "{report}").expect("radio report should fit");
+                        self.send_response("radio", &radiobuf).await?;
+                    }
                 },
             }
         }
@@ -269,6 +430,11 @@ impl<'d, 'a> SerialCommandEngine<'d, 'a> {
                 u64::from_str_radix(data, 10).expect("bad instant"),
             )),
             "rainbow" => SerialCommand::Rainbow,
+            "topology" => SerialCommand::GetTopology,
+            "stats" => SerialCommand::GetStats,
+            "impair" => SerialCommand::Impair(data.to_string()),
+            #[cfg(feature = "net-esp-now")]
+            "radio" => SerialCommand::Radio(data.to_string()),
             "ambient" => {
                 let color = match data {
                     "black" => policy::AmbientColor::Black,
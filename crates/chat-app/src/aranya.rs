@@ -1,6 +1,11 @@
+pub mod auth;
 pub mod daemon;
 pub mod engine;
 mod error;
+pub mod health;
+pub mod memory;
+pub mod neighbors;
 pub(crate) mod policy;
 pub mod sink;
+pub mod stats;
 pub mod syncer;
@@ -14,18 +14,23 @@ use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_time::{with_timeout, Duration};
 use esp_println::println;
 
-use super::{engine::EmbeddedPolicyStore, error::*, sink::DebugSink};
+use super::{auth::TeamKey, engine::EmbeddedPolicyStore, error::*, sink::DebugSink};
 #[cfg(feature = "net-esp-now")]
 use crate::net::espnow::EspNowNetworkInterface;
 #[cfg(feature = "net-irda")]
 use crate::net::irda::IrNetworkInterface;
+#[cfg(feature = "net-uart")]
+use crate::net::uart::UartNetworkInterface;
+#[cfg(feature = "net-usb")]
+use crate::net::usb::UsbNetworkInterface;
+#[cfg(feature = "net-wifi")]
+use crate::net::wifi::WifiNetworkInterface;
 use crate::{
-    aranya::{sink::PubSubSink, syncer::SyncEngine},
+    aranya::{sink::PubSubSink, stats::SyncEvent, syncer::SyncEngine},
+    net::multi::{Link, MultiInterface},
     storage::imp::*,
 };
 
-const ACTION_BOOST: u8 = 7;
-
 // Use short names so we can more easily add generics.
 /// CE = Crypto Engine
 pub(crate) type CE = DefaultEngine;
@@ -59,13 +64,14 @@ const NULL_KEY: [u8; 32] = [0u8; 32];
 
 pub static ACTION_IN_CHANNEL: Channel<VmAction<'static>> = Channel::new();
 pub static EFFECT_OUT_CHANNEL: PubSubChannel<VmEffect> = PubSubChannel::new();
+/// Sync sessions starting and ending on any transport.
+pub static SYNC_EVENT_CHANNEL: PubSubChannel<SyncEvent> = PubSubChannel::new();
 
 pub struct Daemon<'a> {
     aranya: Client,
-    #[cfg(feature = "net-esp-now")]
-    syncer_esp_now: Option<SyncEngine<'a, EspNowNetworkInterface<'a>>>,
-    #[cfg(feature = "net-irda")]
-    syncer_ir: Option<SyncEngine<'a, IrNetworkInterface<'a>>>,
+    /// Links added before sync is enabled
+    links: MultiInterface<Link<'a>>,
+    syncer: Option<SyncEngine<'a, MultiInterface<Link<'a>>>>,
 }
 
 impl<'a> Daemon<'a> {
@@ -83,31 +89,62 @@ impl<'a> Daemon<'a> {
 
         Ok(Daemon {
             aranya,
-            #[cfg(feature = "net-esp-now")]
-            syncer_esp_now: None,
-            #[cfg(feature = "net-irda")]
-            syncer_ir: None,
+            links: MultiInterface::new(),
+            syncer: None,
         })
     }
 
+    /// Add an ESP-NOW link. Links added first are preferred when a peer is reachable over
+    /// more than one.
     #[cfg(feature = "net-esp-now")]
-    pub fn add_esp_now_interface(
-        &mut self,
-        network_interface: EspNowNetworkInterface<'a>,
-        graph_id: GraphId,
-    ) {
-        let syncer = SyncEngine::new(graph_id, network_interface);
-        self.syncer_esp_now = Some(syncer);
+    pub fn add_esp_now_interface(&mut self, network_interface: EspNowNetworkInterface<'a>) {
+        self.links.add_link(Link::EspNow(network_interface));
     }
 
+    /// Add an IR link. Links added first are preferred when a peer is reachable over more
+    /// than one.
     #[cfg(feature = "net-irda")]
-    pub fn add_irda_interface(
+    pub fn add_irda_interface(&mut self, network_interface: IrNetworkInterface<'a>) {
+        self.links.add_link(Link::Ir(network_interface));
+    }
+
+    /// Add a wired UART link. Links added first are preferred when a peer is reachable over
+    /// more than one.
+    #[cfg(feature = "net-uart")]
+    pub fn add_uart_interface(&mut self, network_interface: UartNetworkInterface<'a>) {
+        self.links.add_link(Link::Uart(network_interface));
+    }
+
+    /// Add a USB link to a host. Links added first are preferred when a peer is reachable over
+    /// more than one.
+    #[cfg(feature = "net-usb")]
+    pub fn add_usb_interface(&mut self, network_interface: UsbNetworkInterface<'a>) {
+        self.links.add_link(Link::Usb(network_interface));
+    }
+
+    /// Add a Wi-Fi link. Links added first are preferred when a peer is reachable over more
+    /// than one.
+    #[cfg(feature = "net-wifi")]
+    pub fn add_wifi_interface(&mut self, network_interface: WifiNetworkInterface<'a>) {
+        self.links.add_link(Link::Wifi(network_interface));
+    }
+
+    /// Start syncing `graph_id` over every link added so far. `device` is this device's tag,
+    /// from [`device_tag`](crate::net::address::device_tag). If `peers` is not empty, this will
+    /// only sync with those peers.
+    pub fn enable_sync(
         &mut self,
-        network_interface: IrNetworkInterface<'a>,
         graph_id: GraphId,
+        device: u64,
+        peers: &[u16],
+        team_key: TeamKey,
     ) {
-        let syncer = SyncEngine::new(graph_id, network_interface);
-        self.syncer_ir = Some(syncer);
+        let links = core::mem::replace(&mut self.links, MultiInterface::new());
+        if links.is_empty() {
+            log::error!("No network links configured; not syncing");
+            return;
+        }
+        self.syncer = Some(SyncEngine::new(graph_id, links, device, peers, team_key));
     }
 
     pub async fn create_team(&mut self) -> Result<GraphId> {
@@ -126,31 +163,17 @@ impl<'a> Daemon<'a> {
 
     pub async fn run(&mut self, graph_id: GraphId) -> Result<()> {
         let mut sink = PubSubSink::new();
-        #[cfg(feature = "net-esp-now")]
-        let syncer_esp_now = self
-            .syncer_esp_now
-            .as_mut()
-            .expect("No ESP Now syncer configured");
-        #[cfg(feature = "net-irda")]
-        let syncer_ir = self.syncer_ir.as_mut().expect("No IR syncer configured");
+        let syncer = self.syncer.as_mut().expect("No syncer configured");
 
         loop {
             match with_timeout(Duration::from_millis(100), ACTION_IN_CHANNEL.receive()).await {
                 Ok(action) => match self.aranya.action(graph_id, &mut sink, action) {
-                    Ok(_) => {
-                        #[cfg(feature = "net-esp-now")]
-                        syncer_esp_now.boost_hello(ACTION_BOOST, true);
-                        #[cfg(feature = "net-irda")]
-                        syncer_ir.boost_hello(ACTION_BOOST, true);
-                    }
+                    Ok(_) => syncer.reset_hello(),
                     Err(err) => println!("Error from action: {err}"),
                 },
                 Err(_) => (),
             }
-            #[cfg(feature = "net-esp-now")]
-            syncer_esp_now.process(&mut self.aranya).await;
-            #[cfg(feature = "net-irda")]
-            syncer_ir.process(&mut self.aranya).await;
+            syncer.process(&mut self.aranya).await;
         }
     }
 }
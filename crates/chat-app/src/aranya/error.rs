@@ -1,3 +1,5 @@
+use alloc::string::String;
+
 use aranya_crypto::keystore;
 use aranya_policy_vm::UnsupportedVersion;
 
@@ -21,6 +23,10 @@ pub enum Error {
     EffectsParse(#[from] aranya_policy_ifgen::EffectsParseError),
     #[error("Network error: {0}")]
     Network(#[from] crate::net::NetworkError),
+    #[error("Sync authentication error: {0}")]
+    Auth(#[from] crate::aranya::auth::AuthError),
+    #[error("Malformed sync message: {0}")]
+    Malformed(String),
     #[error("Crypto ID error: {0}")]
     Id(#[from] aranya_crypto::id::IdError),
     #[error("Key wrapping error: {0}")]
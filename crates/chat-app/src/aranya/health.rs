@@ -0,0 +1,232 @@
+//! Scores how well each peer behaves during sync.
+//!
+//! Every fault a peer commits adds to its penalty. A peer that has just failed is not synced
+//! with again until an exponentially growing backoff has passed, and a peer whose penalty
+//! crosses [`BAN_THRESHOLD`] is ignored entirely for a while. Each ban in a row lasts twice
+//! as long as the last one. A sync that completes clears the backoff and the ban history, and
+//! penalties slowly decay, so a peer that was merely unlucky recovers on its own.
+//!
+//! Only faults in messages that passed [authentication](super::auth) are counted. The sender
+//! address of anything else can't be trusted, and counting it would let anyone get a peer
+//! banned by forging its address.
+
+use alloc::collections::btree_map::BTreeMap;
+use core::fmt::Display;
+
+use embassy_time::{Duration, Instant};
+
+use super::{error::Error, neighbors::MAX_NEIGHBORS};
+
+/// A peer whose penalty reaches this is banned.
+const BAN_THRESHOLD: u16 = 12;
+/// How often a penalty decays by one point.
+const PENALTY_DECAY: Duration = Duration::from_secs(30);
+/// The backoff after a peer's first failure. It doubles with every failure in a row.
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// The length of a peer's first ban. It doubles with every ban in a row.
+const BASE_BAN: Duration = Duration::from_secs(30);
+const MAX_BAN: Duration = Duration::from_secs(600);
+/// The most peers we keep scores for.
+const MAX_TRACKED: usize = MAX_NEIGHBORS;
+
+/// Ways a peer can misbehave during sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// It sent something we could not decode.
+    Malformed,
+    /// It sent a response that doesn't belong to our sync session.
+    SessionMismatch,
+    /// A sync session with it stopped making progress.
+    Stalled,
+}
+
+impl Fault {
+    /// Work out whether an error handling a peer's message was the peer's fault. Errors of our
+    /// own, like failing to store or commit what the peer sent, are not.
+    pub fn classify(error: &Error) -> Option<Fault> {
+        match error {
+            Error::Malformed(_) => Some(Fault::Malformed),
+            Error::Sync(aranya_runtime::SyncError::SessionMismatch) => Some(Fault::SessionMismatch),
+            _ => None,
+        }
+    }
+
+    fn penalty(self) -> u16 {
+        match self {
+            Fault::Malformed => 4,
+            Fault::SessionMismatch => 2,
+            Fault::Stalled => 3,
+        }
+    }
+}
+
+/// The health of one peer.
+#[derive(Debug)]
+struct Health {
+    penalty: u16,
+    /// Failures since the last successful sync.
+    failures: u8,
+    /// Bans since the last successful sync.
+    bans: u8,
+    /// We won't start a sync with this peer until this time.
+    retry_at: Instant,
+    banned_until: Option<Instant>,
+    last_decay: Instant,
+}
+
+impl Health {
+    fn new(now: Instant) -> Health {
+        Health {
+            penalty: 0,
+            failures: 0,
+            bans: 0,
+            retry_at: now,
+            banned_until: None,
+            last_decay: now,
+        }
+    }
+
+    /// Is there anything worth remembering about this peer?
+    fn is_clean(&self, now: Instant) -> bool {
+        self.penalty == 0 && self.bans == 0 && self.banned_until.is_none() && self.retry_at <= now
+    }
+}
+
+/// The health of every peer on one transport.
+pub struct PeerHealth<A> {
+    peers: BTreeMap<A, Health>,
+    dirty: bool,
+}
+
+impl<A> PeerHealth<A>
+where
+    A: Copy + Ord + Display,
+{
+    pub fn new() -> PeerHealth<A> {
+        PeerHealth {
+            peers: BTreeMap::new(),
+            dirty: false,
+        }
+    }
+
+    /// Record that `address` committed `fault`, backing it off or banning it.
+    pub fn fault(&mut self, address: A, fault: Fault) {
+        let now = Instant::now();
+        if !self.peers.contains_key(&address) && self.peers.len() >= MAX_TRACKED {
+            // Forget whoever is in the best standing to make room
+            let healthiest = self
+                .peers
+                .iter()
+                .filter(|(_, h)| h.banned_until.is_none())
+                .min_by_key(|(_, h)| h.penalty)
+                .map(|(a, _)| *a);
+            match healthiest {
+                Some(a) => {
+                    self.peers.remove(&a);
+                }
+                // Everyone we know is banned already
+                None => return,
+            }
+        }
+        self.dirty = true;
+        let health = self
+            .peers
+            .entry(address)
+            .or_insert_with(|| Health::new(now));
+        health.penalty = health.penalty.saturating_add(fault.penalty());
+        health.failures = health.failures.saturating_add(1);
+        let delay = backoff(BASE_BACKOFF, health.failures - 1, MAX_BACKOFF);
+        health.retry_at = now + delay;
+        log::info!(
+            "peer {address}: {fault:?}, penalty {}, backing off {}ms",
+            health.penalty,
+            delay.as_millis()
+        );
+
+        if health.penalty >= BAN_THRESHOLD && health.banned_until.is_none() {
+            let ban = backoff(BASE_BAN, health.bans, MAX_BAN);
+            health.bans = health.bans.saturating_add(1);
+            health.penalty = 0;
+            health.banned_until = Some(now + ban);
+            log::warn!(
+                "peer {address}: banned for {}s (ban #{})",
+                ban.as_secs(),
+                health.bans
+            );
+        }
+    }
+
+    /// Record that a sync with `address` completed.
+    pub fn success(&mut self, address: A) {
+        let now = Instant::now();
+        if let Some(health) = self.peers.get_mut(&address) {
+            health.failures = 0;
+            health.bans = 0;
+            health.retry_at = now;
+            self.dirty = true;
+        }
+    }
+
+    /// Is `address` banned?
+    pub fn is_banned(&self, address: &A) -> bool {
+        self.peers
+            .get(address)
+            .is_some_and(|h| h.banned_until.is_some())
+    }
+
+    /// May we start a sync with `address` now?
+    pub fn can_sync(&self, address: &A) -> bool {
+        match self.peers.get(address) {
+            Some(health) => health.banned_until.is_none() && health.retry_at <= Instant::now(),
+            None => true,
+        }
+    }
+
+    /// Lift expired bans, decay penalties, and forget peers with a clean record.
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        let mut dirty = false;
+        for (address, health) in self.peers.iter_mut() {
+            if health.banned_until.is_some_and(|until| until <= now) {
+                health.banned_until = None;
+                dirty = true;
+                log::warn!("peer {address}: ban lifted");
+            }
+            while health.penalty > 0 && now - health.last_decay >= PENALTY_DECAY {
+                health.penalty -= 1;
+                health.last_decay += PENALTY_DECAY;
+                dirty = true;
+            }
+            if health.penalty == 0 {
+                health.last_decay = now;
+            }
+        }
+        self.peers.retain(|_, h| !h.is_clean(now));
+        self.dirty |= dirty;
+    }
+
+    /// The penalty of `address`, and how much longer it is banned for.
+    pub fn status(&self, address: &A) -> (u16, Option<Duration>) {
+        match self.peers.get(address) {
+            Some(health) => (
+                health.penalty,
+                health
+                    .banned_until
+                    .map(|until| until.saturating_duration_since(Instant::now())),
+            ),
+            None => (0, None),
+        }
+    }
+
+    /// Has anything changed since this was last called?
+    pub fn take_dirty(&mut self) -> bool {
+        core::mem::take(&mut self.dirty)
+    }
+}
+
+/// `base` doubled `doublings` times, up to `max`.
+fn backoff(base: Duration, doublings: u8, max: Duration) -> Duration {
+    let factor = 1u64.checked_shl(doublings as u32).unwrap_or(u64::MAX);
+    Duration::from_ticks(base.as_ticks().saturating_mul(factor)).min(max)
+}
@@ -0,0 +1,122 @@
+//! Memory management for sync.
+//!
+//! Sync messages are built in buffers of [`MAX_SYNC_MESSAGE_SIZE`] bytes. Allocating one for
+//! every message would put a lot of pressure on the internal heap, which is small and shared
+//! with the network engines. Instead, a few [`SendBuffer`]s are allocated from PSRAM at
+//! startup and reused.
+//!
+//! Responding to a sync request also needs heap for the messages themselves and for encoding
+//! them on the way out. [`can_afford`] lets the syncer put off that work while memory is tight
+//! rather than fail an allocation part way through.
+
+use alloc::{boxed::Box, vec};
+use core::{
+    alloc::Layout,
+    cell::RefCell,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    slice,
+};
+
+use aranya_runtime::MAX_SYNC_MESSAGE_SIZE;
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use esp_alloc::{MemoryCapability, HEAP};
+
+use crate::net::MAX_MESSAGE_LEN;
+
+/// How many send buffers to allocate. The syncer only builds one message at a time, so this
+/// leaves a spare.
+const SEND_BUFFERS: usize = 2;
+/// Heap that must be left free for everything else after sync takes what it needs.
+const HEAP_RESERVE: usize = 16 * 1024;
+
+type Pool = heapless::Vec<&'static mut [u8], SEND_BUFFERS>;
+
+static POOL: CriticalSectionMutex<RefCell<Pool>> =
+    CriticalSectionMutex::new(RefCell::new(heapless::Vec::new()));
+
+/// Allocate the send buffers. This must be called after the heaps are initialized.
+pub fn init() {
+    POOL.lock(|pool| {
+        let mut pool = pool.borrow_mut();
+        while !pool.is_full() {
+            pool.push(alloc_buffer()).ok();
+        }
+    });
+}
+
+/// Allocate a buffer that lives forever, from PSRAM if there is any.
+fn alloc_buffer() -> &'static mut [u8] {
+    let layout = Layout::array::<u8>(MAX_SYNC_MESSAGE_SIZE).expect("buffer layout is valid");
+    // SAFETY: the layout has a non-zero size.
+    let ptr = unsafe { HEAP.alloc_caps(MemoryCapability::External.into(), layout) };
+    match NonNull::new(ptr) {
+        Some(ptr) => {
+            // SAFETY: the allocation is valid for `MAX_SYNC_MESSAGE_SIZE` bytes, is zeroed
+            // before use, and is never freed.
+            unsafe {
+                ptr.as_ptr().write_bytes(0, MAX_SYNC_MESSAGE_SIZE);
+                slice::from_raw_parts_mut(ptr.as_ptr(), MAX_SYNC_MESSAGE_SIZE)
+            }
+        }
+        None => {
+            log::warn!("No PSRAM for sync buffers; using the internal heap");
+            Box::leak(vec![0u8; MAX_SYNC_MESSAGE_SIZE].into_boxed_slice())
+        }
+    }
+}
+
+/// A [`MAX_SYNC_MESSAGE_SIZE`] buffer borrowed from the pool. It goes back to the pool when
+/// dropped.
+pub struct SendBuffer(Option<&'static mut [u8]>);
+
+impl SendBuffer {
+    /// Borrow a buffer, if one is free.
+    pub fn take() -> Option<SendBuffer> {
+        POOL.lock(|pool| pool.borrow_mut().pop())
+            .map(|buf| SendBuffer(Some(buf)))
+    }
+}
+
+impl Deref for SendBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.0.as_deref().expect("buffer is present until dropped")
+    }
+}
+
+impl DerefMut for SendBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.0
+            .as_deref_mut()
+            .expect("buffer is present until dropped")
+    }
+}
+
+impl Drop for SendBuffer {
+    fn drop(&mut self) {
+        if let Some(buf) = self.0.take() {
+            POOL.lock(|pool| pool.borrow_mut().push(buf).ok());
+        }
+    }
+}
+
+/// Free internal heap, in bytes. PSRAM is left out, since it is the small internal heap that
+/// the network engines need and that runs short.
+pub fn heap_free() -> usize {
+    HEAP.free_caps(MemoryCapability::Internal.into())
+}
+
+/// Can we allocate `bytes` and still leave enough internal heap for everything else?
+pub fn can_afford(bytes: usize) -> bool {
+    heap_free().saturating_sub(bytes) >= HEAP_RESERVE
+}
+
+/// The longest message the links can afford to reconstruct right now, up to
+/// [`MAX_MESSAGE_LEN`]. Space for a message is set aside as soon as its first packet arrives.
+pub fn max_message_len() -> usize {
+    heap_free()
+        .saturating_sub(HEAP_RESERVE)
+        .min(MAX_MESSAGE_LEN)
+}
@@ -0,0 +1,227 @@
+//! Tracks the neighbors a [`SyncEngine`](super::syncer::SyncEngine) has heard from.
+//!
+//! Every hello carries a short digest of the sender's own neighbors, so each node can build a
+//! two-hop view of the mesh. The latest view from the syncer is published to [`TOPOLOGY`]
+//! where the application and serial interface can read it.
+
+use alloc::{
+    collections::btree_map::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{cell::RefCell, fmt::Display};
+
+use aranya_runtime::Address;
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_time::{Duration, Instant};
+use parameter_store::MAX_PEERS;
+
+use super::health::PeerHealth;
+
+/// The most neighbors we will track per syncer.
+pub const MAX_NEIGHBORS: usize = MAX_PEERS;
+/// The most neighbors described in a hello's digest.
+pub const MAX_DIGEST: usize = 8;
+/// How long before we forget a neighbor we haven't heard from. This is quite long because
+/// hellos from settled neighbors are suppressed.
+const NEIGHBOR_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The latest topology reported by every syncer.
+pub static TOPOLOGY: CriticalSectionMutex<RefCell<Vec<NeighborReport>>> =
+    CriticalSectionMutex::new(RefCell::new(Vec::new()));
+
+/// Get a copy of the current topology.
+pub fn topology() -> Vec<NeighborReport> {
+    TOPOLOGY.lock(|t| t.borrow().clone())
+}
+
+/// An entry in a hello's neighbor digest.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct NeighborDigest<A> {
+    pub address: A,
+    /// The neighbor's device tag, if we've had a hello from it.
+    pub device: Option<u64>,
+    pub rssi: Option<i8>,
+    /// The percentage of the neighbor's packets we lose, if the link measures it. This is how
+    /// the neighbor learns how much redundancy to send us.
+    pub loss: Option<u8>,
+}
+
+/// Everything we know about one neighbor.
+#[derive(Debug)]
+pub struct Neighbor<A> {
+    /// When we last received anything from this neighbor.
+    pub last_seen: Instant,
+    /// The device tag from this neighbor's most recent hello.
+    pub device: Option<u64>,
+    /// The head from this neighbor's most recent hello.
+    pub head: Option<Address>,
+    /// Signal strength of the last message received, if the transport reports it.
+    pub rssi: Option<i8>,
+    /// Sync sessions we have started with this neighbor.
+    pub syncs_started: u16,
+    /// Sync sessions with this neighbor that ran to completion.
+    pub syncs_finished: u16,
+    /// This neighbor's own neighbors, from its most recent hello.
+    pub neighbors: heapless::Vec<NeighborDigest<A>, MAX_DIGEST>,
+}
+
+impl<A> Neighbor<A> {
+    fn new() -> Neighbor<A> {
+        Neighbor {
+            last_seen: Instant::now(),
+            device: None,
+            head: None,
+            rssi: None,
+            syncs_started: 0,
+            syncs_finished: 0,
+            neighbors: heapless::Vec::new(),
+        }
+    }
+}
+
+/// A displayable snapshot of one neighbor.
+#[derive(Debug, Clone)]
+pub struct NeighborReport {
+    /// The name of the link we currently reach this neighbor over.
+    pub transport: &'static str,
+    pub address: String,
+    pub last_seen: Instant,
+    pub head: Option<Address>,
+    pub rssi: Option<i8>,
+    pub syncs_started: u16,
+    pub syncs_finished: u16,
+    /// The neighbor's penalty for misbehaving during sync.
+    pub penalty: u16,
+    /// How much longer the neighbor is banned for, if it is.
+    pub banned_for: Option<Duration>,
+    pub neighbors: Vec<String>,
+}
+
+/// A table of the neighbors heard on one transport.
+pub struct NeighborTable<A> {
+    neighbors: BTreeMap<A, Neighbor<A>>,
+    dirty: bool,
+}
+
+impl<A> NeighborTable<A>
+where
+    A: Copy + Ord + Display,
+{
+    pub fn new() -> NeighborTable<A> {
+        NeighborTable {
+            neighbors: BTreeMap::new(),
+            dirty: false,
+        }
+    }
+
+    /// The number of neighbors in the table.
+    pub fn len(&self) -> usize {
+        self.neighbors.len()
+    }
+
+    /// Note that we received something from `address`, evicting the stalest neighbor if the
+    /// table is full.
+    pub fn heard(&mut self, address: A, rssi: Option<i8>) -> &mut Neighbor<A> {
+        if !self.neighbors.contains_key(&address) && self.neighbors.len() >= MAX_NEIGHBORS {
+            let stalest = self
+                .neighbors
+                .iter()
+                .min_by_key(|(_, n)| n.last_seen)
+                .map(|(a, _)| *a);
+            if let Some(stalest) = stalest {
+                self.neighbors.remove(&stalest);
+            }
+        }
+        self.dirty = true;
+        let neighbor = self.neighbors.entry(address).or_insert_with(Neighbor::new);
+        neighbor.last_seen = Instant::now();
+        if rssi.is_some() {
+            neighbor.rssi = rssi;
+        }
+        neighbor
+    }
+
+    /// Record the contents of a hello from `address`.
+    pub fn hello(
+        &mut self,
+        address: A,
+        device: u64,
+        rssi: Option<i8>,
+        head: Address,
+        neighbors: heapless::Vec<NeighborDigest<A>, MAX_DIGEST>,
+    ) {
+        let neighbor = self.heard(address, rssi);
+        if neighbor.device.is_some_and(|d| d != device) {
+            // Either a device moved onto this address or two devices share it. If they can hear
+            // each other, one of them will move.
+            log::warn!("address {address} is now used by device {device:016x}");
+        }
+        neighbor.device = Some(device);
+        neighbor.head = Some(head);
+        neighbor.neighbors = neighbors;
+    }
+
+    pub fn sync_started(&mut self, address: A) {
+        let neighbor = self.heard(address, None);
+        neighbor.syncs_started = neighbor.syncs_started.saturating_add(1);
+    }
+
+    pub fn sync_finished(&mut self, address: A) {
+        let neighbor = self.heard(address, None);
+        neighbor.syncs_finished = neighbor.syncs_finished.saturating_add(1);
+    }
+
+    /// Forget neighbors we haven't heard from in a while.
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        let before = self.neighbors.len();
+        self.neighbors
+            .retain(|_, n| now - n.last_seen < NEIGHBOR_TIMEOUT);
+        if self.neighbors.len() != before {
+            self.dirty = true;
+        }
+    }
+
+    /// Build a digest of our neighbors for a hello, most recently heard first. Losses are left
+    /// for the caller to fill in from the network.
+    pub fn digest(&self) -> heapless::Vec<NeighborDigest<A>, MAX_DIGEST> {
+        let mut recent: Vec<(&A, &Neighbor<A>)> = self.neighbors.iter().collect();
+        recent.sort_by_key(|(_, n)| core::cmp::Reverse(n.last_seen));
+        recent
+            .into_iter()
+            .take(MAX_DIGEST)
+            .map(|(a, n)| NeighborDigest {
+                address: *a,
+                device: n.device,
+                rssi: n.rssi,
+                loss: None,
+            })
+            .collect()
+    }
+
+    /// Publish this table, along with each neighbor's health and the link it is reached over,
+    /// to [`TOPOLOGY`] if the table or health has changed since the last time.
+    pub fn publish(&mut self, health: &mut PeerHealth<A>, link: impl Fn(&A) -> &'static str) {
+        if !(health.take_dirty() | self.dirty) {
+            return;
+        }
+        self.dirty = false;
+        let reports = self.neighbors.iter().map(|(a, n)| {
+            let (penalty, banned_for) = health.status(a);
+            NeighborReport {
+                transport: link(a),
+                address: a.to_string(),
+                last_seen: n.last_seen,
+                head: n.head,
+                rssi: n.rssi,
+                syncs_started: n.syncs_started,
+                syncs_finished: n.syncs_finished,
+                penalty,
+                banned_for,
+                neighbors: n.neighbors.iter().map(|d| d.address.to_string()).collect(),
+            }
+        });
+        TOPOLOGY.lock(|t| *t.borrow_mut() = reports.collect());
+    }
+}
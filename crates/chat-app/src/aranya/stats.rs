@@ -0,0 +1,152 @@
+//! Counters and events describing what a [`SyncEngine`](super::syncer::SyncEngine) is doing.
+//!
+//! Each syncer keeps a set of [`SyncCounters`] for itself and one for every peer it talks to,
+//! and publishes them to [`STATS`] where the application and serial interface can read them.
+//! Sessions starting and ending are also announced on [`SYNC_EVENT_CHANNEL`] as they happen.
+
+use alloc::{
+    collections::btree_map::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{cell::RefCell, fmt::Display};
+
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_time::Duration;
+
+use super::{daemon::SYNC_EVENT_CHANNEL, neighbors::MAX_NEIGHBORS};
+
+/// The latest statistics reported by every syncer.
+pub static STATS: CriticalSectionMutex<RefCell<Vec<StatsReport>>> =
+    CriticalSectionMutex::new(RefCell::new(Vec::new()));
+
+/// Get a copy of the current statistics.
+pub fn stats() -> Vec<StatsReport> {
+    STATS.lock(|s| s.borrow().clone())
+}
+
+/// Sync activity counters.
+#[derive(Debug, Clone, Default)]
+pub struct SyncCounters {
+    pub hellos_sent: u32,
+    pub hellos_received: u32,
+    pub sessions_started: u32,
+    pub sessions_completed: u32,
+    pub sessions_stalled: u32,
+    pub commands_received: u32,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Total time spent committing received commands.
+    pub commit_time: Duration,
+    /// Frames dropped because their MAC did not verify. Only counted for the whole syncer.
+    pub bad_mac: u32,
+    /// Frames dropped because they were replayed. Copies of a broadcast heard on more than one
+    /// link count too.
+    pub replayed: u32,
+}
+
+/// A snapshot of the counters for a syncer or one of its peers.
+#[derive(Debug, Clone)]
+pub struct StatsReport {
+    /// The name of the transport the syncer runs on.
+    pub transport: &'static str,
+    /// The peer these counters are for, or `None` for the syncer as a whole.
+    pub peer: Option<String>,
+    pub counters: SyncCounters,
+}
+
+/// Something that happened to a sync session.
+#[derive(Debug, Clone)]
+pub enum SyncEventKind {
+    Started,
+    Finished,
+    /// The session was abandoned.
+    Failed,
+}
+
+/// A sync session event, published on [`SYNC_EVENT_CHANNEL`].
+#[derive(Debug, Clone)]
+pub struct SyncEvent {
+    pub transport: &'static str,
+    pub peer: String,
+    pub kind: SyncEventKind,
+}
+
+/// The counters for one syncer.
+pub struct SyncStats<A> {
+    transport: &'static str,
+    total: SyncCounters,
+    peers: BTreeMap<A, SyncCounters>,
+    dirty: bool,
+}
+
+impl<A> SyncStats<A>
+where
+    A: Copy + Ord + Display,
+{
+    pub fn new(transport: &'static str) -> SyncStats<A> {
+        SyncStats {
+            transport,
+            total: SyncCounters::default(),
+            peers: BTreeMap::new(),
+            dirty: false,
+        }
+    }
+
+    /// Update the syncer's counters and, if given, those of `peer`. Once we are tracking
+    /// [`MAX_NEIGHBORS`] peers, new peers are only counted in the total.
+    pub fn record(&mut self, peer: Option<A>, f: impl Fn(&mut SyncCounters)) {
+        f(&mut self.total);
+        if let Some(peer) = peer {
+            if self.peers.len() < MAX_NEIGHBORS || self.peers.contains_key(&peer) {
+                f(self.peers.entry(peer).or_default());
+            }
+        }
+        self.dirty = true;
+    }
+
+    /// Update the count of frames with bad MACs, which is kept by
+    /// [`SyncAuth`](super::auth::SyncAuth).
+    pub fn bad_mac(&mut self, bad_mac: u32) {
+        if self.total.bad_mac != bad_mac {
+            self.total.bad_mac = bad_mac;
+            self.dirty = true;
+        }
+    }
+
+    /// Announce a session event for `peer`.
+    pub fn event(&self, peer: A, kind: SyncEventKind) {
+        SYNC_EVENT_CHANNEL
+            .immediate_publisher()
+            .publish_immediate(SyncEvent {
+                transport: self.transport,
+                peer: peer.to_string(),
+                kind,
+            });
+    }
+
+    /// Publish the counters to [`STATS`] if they have changed since the last time.
+    pub fn publish(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        self.dirty = false;
+        let transport = self.transport;
+        let total = StatsReport {
+            transport,
+            peer: None,
+            counters: self.total.clone(),
+        };
+        let peers = self.peers.iter().map(|(a, c)| StatsReport {
+            transport,
+            peer: Some(a.to_string()),
+            counters: c.clone(),
+        });
+        STATS.lock(|s| {
+            let mut s = s.borrow_mut();
+            s.retain(|r| r.transport != transport);
+            s.push(total);
+            s.extend(peers);
+        });
+    }
+}
@@ -1,4 +1,4 @@
-use alloc::{boxed::Box, collections::btree_map::BTreeMap, string::String, vec};
+use alloc::{boxed::Box, collections::btree_map::BTreeMap, format, string::String, vec::Vec};
 use core::task::Poll;
 
 use aranya_crypto::Rng;
@@ -10,20 +10,50 @@ use aranya_runtime::{
 use embassy_futures::{poll_once, yield_now};
 use embassy_time::{Duration, Instant};
 use parameter_store::MAX_PEERS;
+use trickle::{TrickleConfig, TrickleTimer};
 
 use crate::{
+    application::serial::MAX_DATA_LEN,
     aranya::{
+        auth::{AuthError, SyncAuth, TeamKey},
         daemon::{Client, PS, SP},
-        error::Result,
+        error::{Error, Result},
+        health::{Fault, PeerHealth},
+        memory::{self, SendBuffer},
+        neighbors::{NeighborDigest, NeighborTable, MAX_DIGEST},
         sink::PubSubSink,
+        stats::{SyncEventKind, SyncStats},
     },
     net::{Message, NetworkInterface},
 };
 
 const SYNC_STALL_TIMEOUT: Duration = Duration::from_secs(8);
-const BASE_SYNC_DELAY: Duration = Duration::from_secs(4);
-const SYNC_RESPONSE_BOOST: u8 = 4;
-const SYNC_FINISH_BOOST: u8 = 4;
+/// How many times a stalled session is resumed before the peer goes to the back of the queue.
+const MAX_RESUMES: u16 = 3;
+/// Room for the sync message header and authentication around a response chunk.
+const SYNC_FRAME_OVERHEAD: usize = 32;
+/// Responses are never split smaller than this, whatever the link.
+const MIN_RESPONSE_CHUNK: usize = 512;
+/// Roughly what a chat message command adds to its text: the author's ID, the command's ID and
+/// parent, its signature, and the envelope and sync framing around them.
+const COMMAND_OVERHEAD: usize = 352;
+/// Room for the response header around the commands in a chunk.
+const RESPONSE_HEADER: usize = 64;
+// Commands are never split across responses, so a response chunk must have room for the largest
+// command the policy makes, or it could never be sent. That is a chat message with as much text
+// as the serial console takes. Chunks are never smaller than MIN_RESPONSE_CHUNK, so this holds on
+// every link.
+const _: () = assert!(MAX_DATA_LEN + COMMAND_OVERHEAD + RESPONSE_HEADER <= MIN_RESPONSE_CHUNK);
+/// Roughly how many copies of a response chunk are alive on the heap while it is serialized,
+/// sealed, and handed to the network.
+const RESPONSE_HEAP_FACTOR: usize = 4;
+/// Hellos are scheduled with Trickle. Intervals run from half a second up to 16 seconds, and
+/// we stay quiet if two neighbors have already announced our head in the current interval.
+const HELLO_TRICKLE: TrickleConfig = TrickleConfig {
+    i_min: 500,
+    i_max_doublings: 5,
+    k: 2,
+};
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub enum SyncMessageType {
@@ -38,8 +68,12 @@ where
     N: NetworkInterface,
 {
     address: N::Addr,
+    /// Our device tag, to detect other devices using our address
+    device: u64,
     head: Address,
     peer_count: u16,
+    /// Our most recently heard neighbors
+    neighbors: heapless::Vec<NeighborDigest<N::Addr>, MAX_DIGEST>,
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
@@ -53,19 +87,22 @@ impl SyncMessage {
         SyncMessage { t, bytes }
     }
 
-    pub fn into_message<A>(self, from: A, to: A) -> Result<Message<A>>
+    pub fn into_message<A>(self, from: A, to: A, auth: &mut SyncAuth<A>) -> Result<Message<A>>
     where
-        A: Default + Ord,
+        A: Copy + Default + Ord + serde::Serialize,
     {
         let ib = postcard::to_allocvec(&self)?;
-        Ok(Message::new(from, to, ib.into_boxed_slice()))
+        let frame = auth.seal(from, &ib)?;
+        Ok(Message::new(from, to, frame.into_boxed_slice()))
     }
 
-    pub fn from_message<A>(m: Message<A>) -> Result<(A, SyncMessage)>
+    /// Verify and decode a message. The message is not parsed unless it is authentic.
+    pub fn from_message<A>(m: Message<A>, auth: &mut SyncAuth<A>) -> Result<(A, SyncMessage)>
     where
-        A: Default + Ord,
+        A: Copy + Default + Ord + serde::Serialize,
     {
-        let sm = postcard::from_bytes(&m.contents)?;
+        let body = auth.open(m.sender, &m.contents)?;
+        let sm = postcard::from_bytes(body)?;
         Ok((m.sender, sm))
     }
 }
@@ -85,6 +122,33 @@ struct SyncSession<A> {
     trx: Option<Transaction<SP, PS>>,
     last_seen: Instant,
     peer_addr: A,
+    /// Commands received in `trx` that have not been committed yet
+    received: Vec<Address>,
+    /// How many times this session was resumed after stalling
+    resumes: u16,
+    /// The link the request went out on
+    link: &'static str,
+}
+
+impl<A> SyncSession<A> {
+    fn new(graph_id: GraphId, peer_addr: A, resumes: u16, link: &'static str) -> SyncSession<A> {
+        SyncSession {
+            requester: SyncRequester::new(graph_id, Rng),
+            trx: None,
+            last_seen: Instant::now(),
+            peer_addr,
+            received: Vec::new(),
+            resumes,
+            link,
+        }
+    }
+}
+
+/// A sync request we couldn't afford to answer yet.
+struct DeferredRequest<A> {
+    from: A,
+    request: SyncRequestMessage,
+    received: Instant,
 }
 
 /// Aranya client.
@@ -94,30 +158,54 @@ where
 {
     graph_id: GraphId,
     network: N,
+    /// This device's tag, sent in hellos. See [`address`](crate::net::address).
+    device: u64,
+    /// Configured peers. When this is empty, hellos are broadcast and accepted from anyone.
+    /// Otherwise hellos are unicast to these peers and sync messages from anyone else are
+    /// ignored.
+    peers: heapless::Vec<N::Addr, MAX_PEERS>,
     sync_queue: heapless::FnvIndexSet<N::Addr, MAX_PEERS>,
     sync_session: Option<SyncSession<N::Addr>>,
+    deferred: Option<DeferredRequest<N::Addr>>,
     peer_caches: BTreeMap<N::Addr, PeerCache>,
+    neighbors: NeighborTable<N::Addr>,
+    health: PeerHealth<N::Addr>,
+    auth: SyncAuth<N::Addr>,
+    stats: SyncStats<N::Addr>,
     sink: PubSubSink<'a>,
-    hello_boost: u8,
-    last_hello: Instant,
+    hello_timer: TrickleTimer,
     buffers: TraversalBuffers,
 }
 
 impl<N> SyncEngine<'_, N>
 where
     N: NetworkInterface,
+    N::Addr: Ord + serde::Serialize,
 {
-    /// Creates a new [`Client`].
-    pub fn new(graph_id: GraphId, network: N) -> Self {
+    /// Creates a new [`Client`]. If `peers` is not empty, this will only sync with those peers.
+    /// All sync messages are authenticated with `team_key`.
+    pub fn new(
+        graph_id: GraphId,
+        network: N,
+        device: u64,
+        peers: &[N::Addr],
+        team_key: TeamKey,
+    ) -> Self {
         SyncEngine {
             graph_id,
             network,
+            device,
+            peers: peers.iter().copied().take(MAX_PEERS).collect(),
             sync_queue: heapless::FnvIndexSet::new(),
             sync_session: None,
+            deferred: None,
             peer_caches: BTreeMap::new(),
+            neighbors: NeighborTable::new(),
+            health: PeerHealth::new(),
+            auth: SyncAuth::new(team_key, device),
+            stats: SyncStats::new(N::NAME),
             sink: PubSubSink::new(),
-            hello_boost: 0,
-            last_hello: Instant::from_ticks(0),
+            hello_timer: TrickleTimer::new(HELLO_TRICKLE, now_ms(), trickle_seed()),
             buffers: TraversalBuffers::new(),
         }
     }
@@ -132,33 +220,70 @@ where
     /// Aranya client sends a `SyncRequest` to peer. The `SyncResponse` is handled below in
     /// [`handle_message()`](Self::handle_message).
     async fn sync_peer(&mut self, peer_addr: N::Addr, client: &mut Client) -> Result<()> {
-        let mut send_buf = vec![0u8; MAX_SYNC_MESSAGE_SIZE];
+        let Some(mut send_buf) = SendBuffer::take() else {
+            log::warn!("sync_peer: no send buffer free");
+            return Ok(());
+        };
 
-        let (len, _) = {
-            let requester = match &mut self.sync_session {
-                None => {
-                    self.sync_session = Some(SyncSession {
-                        requester: SyncRequester::new(self.graph_id, Rng),
-                        trx: None,
-                        last_seen: Instant::now(),
-                        peer_addr,
-                    });
-                    &mut self.sync_session.as_mut().unwrap().requester
+        match &self.sync_session {
+            None => {
+                let link = self.network.link_name(&peer_addr);
+                self.sync_session = Some(SyncSession::new(self.graph_id, peer_addr, 0, link));
+                self.neighbors.sync_started(peer_addr);
+                self.stats
+                    .record(Some(peer_addr), |c| c.sessions_started += 1);
+                self.stats.event(peer_addr, SyncEventKind::Started);
+            }
+            Some(session) => {
+                if Instant::now() - session.last_seen <= SYNC_STALL_TIMEOUT {
+                    // Wait for this sync to proceed
+                    return Ok(());
                 }
-                Some(ref mut session) => {
-                    if Instant::now() - session.last_seen > SYNC_STALL_TIMEOUT {
-                        log::info!("sync_peer: sync stalled for {peer_addr}");
-                        // sync is stalled. Commit any progress so far and close the session
-                        if let Some(trx) = session.trx.take() {
-                            client.commit(trx, &mut self.sink, &mut self.buffers.primary)?;
-                        }
-                        self.sync_session = None;
-                        self.sync_queue.remove(&peer_addr);
-                    }
-                    // Otherwise, we return and wait for this sync to proceed
+                log::info!("sync_peer: sync stalled for {peer_addr}");
+                self.stats
+                    .record(Some(peer_addr), |c| c.sessions_stalled += 1);
+                // The link we sent the request on may have failed since, and we have moved to
+                // another. That's not the peer's fault, so ask again over the new one.
+                let link_changed = session.link != self.network.link_name(&peer_addr);
+                if session.received.is_empty() && !link_changed {
+                    // Nothing arrived since the last request, so give up on this peer for now
+                    self.end_session(client)?;
+                    self.penalize(peer_addr, Fault::Stalled);
+                    self.stats.event(peer_addr, SyncEventKind::Failed);
                     return Ok(());
                 }
-            };
+                if session.resumes >= MAX_RESUMES {
+                    // It keeps stalling, so let everyone else have a turn. What we have so far
+                    // is kept, and the next session with it picks up from there.
+                    log::info!("sync_peer: giving up on {peer_addr} for now after resuming");
+                    self.end_session(client)?;
+                    self.sync_queue.insert(peer_addr).ok();
+                    self.stats.event(peer_addr, SyncEventKind::Failed);
+                    return Ok(());
+                }
+                // We got somewhere before the stall, or are about to try another link. Keep
+                // what we have and ask again from there, rather than starting over.
+                let mut session = self.sync_session.take().unwrap();
+                log::info!(
+                    "sync_peer: resuming sync with {peer_addr} after {} commands (resume {})",
+                    session.received.len(),
+                    session.resumes + 1
+                );
+                self.commit_session(&mut session, client)?;
+                self.sync_session = Some(SyncSession::new(
+                    self.graph_id,
+                    peer_addr,
+                    session.resumes.saturating_add(1),
+                    self.network.link_name(&peer_addr),
+                ));
+            }
+        }
+
+        let (len, _) = {
+            // SAFETY: a session was either already running or was just created
+            let requester = &mut self.sync_session.as_mut().unwrap().requester;
+            // The peer cache holds the heads of everything we've committed from this peer, so
+            // a resumed request only asks for what's still missing.
             let peer_cache = self.peer_caches.entry(peer_addr).or_default();
             log::info!("peer_cache for {peer_addr}: {peer_cache:?}");
             requester.poll(
@@ -169,16 +294,67 @@ where
             )?
         };
         log::info!("sync_peer: sending Request len {len} to {peer_addr}");
-        send_buf.truncate(len);
-        let sm = SyncMessage::new(SyncMessageType::Request, send_buf.into());
-        let m = sm.into_message(self.network.my_address(), peer_addr)?;
+        let sm = SyncMessage::new(SyncMessageType::Request, send_buf[..len].into());
+        drop(send_buf);
+        self.send(sm, peer_addr).await
+    }
+
+    /// Seal a message and send it to `to`.
+    async fn send(&mut self, sm: SyncMessage, to: N::Addr) -> Result<()> {
+        let m = sm.into_message(self.network.my_address(), to, &mut self.auth)?;
+        let len = m.contents.len() as u64;
         self.network.send_message(m).await?;
+        let peer = (to != N::BROADCAST).then_some(to);
+        self.stats.record(peer, |c| c.bytes_sent += len);
+        Ok(())
+    }
+
+    /// Commit the commands received so far in `session`, and record them in the peer cache.
+    fn commit_session(
+        &mut self,
+        session: &mut SyncSession<N::Addr>,
+        client: &mut Client,
+    ) -> Result<()> {
+        let Some(trx) = session.trx.take() else {
+            return Ok(());
+        };
+        let start = Instant::now();
+        client.commit(trx, &mut self.sink, &mut self.buffers.primary)?;
+        let elapsed = Instant::now() - start;
+        self.stats
+            .record(Some(session.peer_addr), |c| c.commit_time += elapsed);
+        let peer_cache = self.peer_caches.entry(session.peer_addr).or_default();
+        update_peer_cache(
+            &session.received,
+            peer_cache,
+            client,
+            self.graph_id,
+            &mut self.buffers.primary,
+        )?;
+        session.received.clear();
         Ok(())
     }
 
+    /// Commit any progress made in the current sync session and close it.
+    fn end_session(&mut self, client: &mut Client) -> Result<()> {
+        if let Some(mut session) = self.sync_session.take() {
+            self.sync_queue.remove(&session.peer_addr);
+            self.commit_session(&mut session, client)?;
+        }
+        Ok(())
+    }
+
+    /// Count `fault` against `peer`. Banned peers are dropped from the sync queue.
+    fn penalize(&mut self, peer: N::Addr, fault: Fault) {
+        self.health.fault(peer, fault);
+        if self.health.is_banned(&peer) {
+            self.sync_queue.remove(&peer);
+        }
+    }
+
     /// Execute one iteration of syncer logic, handling incoming messages and sending responses
     pub async fn process(&mut self, client: &mut Client) {
-        if Instant::now() - self.last_hello > self.hello_timeout() {
+        if self.hello_timer.poll(now_ms()) {
             if let Err(err) = self.send_hello(client).await {
                 log::error!("initiate: could not send hello {err}");
             }
@@ -186,9 +362,27 @@ where
         if let Err(err) = self.handle_messages(client).await {
             log::error!("sync handle_message: {err}");
         }
-        // we have to make a copy of this list otherwise we're borrowing
-        // &self inside the loop where we need to do self.sync_peer()
-        if let Some(peer) = self.sync_queue.first().cloned() {
+        self.neighbors.expire();
+        self.health.expire();
+        let network = &self.network;
+        self.neighbors
+            .publish(&mut self.health, |peer| network.link_name(peer));
+        self.stats.bad_mac(self.auth.bad_mac);
+        self.stats.publish();
+        // Keep driving the current session. Otherwise, sync with the first peer in the queue
+        // that isn't backing off.
+        let peer = match &self.sync_session {
+            Some(session) => Some(session.peer_addr),
+            None => self
+                .sync_queue
+                .iter()
+                .find(|peer| self.health.can_sync(peer))
+                .copied(),
+        };
+        if let Err(err) = self.retry_deferred(client).await {
+            log::error!("Could not answer deferred sync request: {err}");
+        }
+        if let Some(peer) = peer {
             if let Err(err) = self.sync_peer(peer, client).await {
                 log::error!("Could not initiate sync with {peer}: {err}");
                 self.sync_queue.remove(&peer);
@@ -196,22 +390,38 @@ where
         }
     }
 
-    fn hello_timeout(&mut self) -> Duration {
-        Duration::from_millis(BASE_SYNC_DELAY.as_millis() >> self.hello_boost)
+    /// Is `addr` someone we are allowed to sync with?
+    fn is_peer(&self, addr: &N::Addr) -> bool {
+        self.peers.is_empty() || self.peers.contains(addr)
     }
 
-    pub fn boost_hello(&mut self, factor: u8, immediate: bool) {
-        self.hello_boost = factor;
-
-        if immediate {
-            self.last_hello = Instant::from_ticks(0)
+    /// Check whether `device` is using `address` as well as us. Of two devices sharing an
+    /// address, the one with the greater device tag moves to a new one, so exactly one of them
+    /// does.
+    fn check_address(&mut self, address: N::Addr, device: u64) {
+        if address != self.network.my_address() || device == self.device {
+            return;
+        }
+        if self.device > device {
+            let new_address = self.network.reassign_address();
+            log::warn!(
+                "address {address} is also used by device {device:016x}; moved to {new_address}"
+            );
+            // Announce the new address soon
+            self.reset_hello();
+        } else {
+            log::warn!("address {address} is also used by device {device:016x}; it will move");
         }
     }
 
-    async fn send_hello(&mut self, client: &mut Client) -> Result<()> {
-        log::info!("send_hello");
-        // BUG: check if it the same as our head before accessing storage.
+    /// Our state has changed (or a peer's has), so let everyone know soon. This resets the
+    /// hello interval to its minimum.
+    pub fn reset_hello(&mut self) {
+        self.hello_timer.inconsistent(now_ms());
+    }
 
+    /// Get the address of the head of our graph.
+    fn head(&self, client: &mut Client) -> Result<Address> {
         let provider = client.provider();
         let storage = provider.get_storage(self.graph_id)?;
         let head = storage.get_head()?;
@@ -219,46 +429,114 @@ where
         let segment = storage.get_segment(head)?;
         let command = segment.get_command(head).expect("location must exist");
 
-        let address = Address {
+        Ok(Address {
             id: command.id(),
             //BUG: can this really not fail?
             max_cut: command.max_cut().expect("BUG: Why can it fail?"),
-        };
+        })
+    }
 
+    async fn send_hello(&mut self, client: &mut Client) -> Result<()> {
+        log::info!(
+            "send_hello (interval {}ms, {} suppressed)",
+            self.hello_timer.interval(),
+            self.hello_timer.suppressed()
+        );
+
+        let mut neighbors = self.neighbors.digest();
+        for n in &mut neighbors {
+            n.loss = self.network.rx_loss(&n.address);
+        }
         let hello: HelloMessage<N> = HelloMessage {
             address: self.network.my_address(),
-            peer_count: 0,
-            head: address,
+            device: self.device,
+            peer_count: self.neighbors.len() as u16,
+            head: self.head(client)?,
+            neighbors,
         };
 
-        let hello_bytes = postcard::to_allocvec(&hello)?;
-        let sm = SyncMessage::new(SyncMessageType::Hello, hello_bytes.into());
-        let m = sm.into_message(self.network.my_address(), N::BROADCAST)?;
-        self.network.send_message(m).await?;
-
-        if self.hello_boost > 0 {
-            self.hello_boost -= 1;
+        let hello_bytes: Box<[u8]> = postcard::to_allocvec(&hello)?.into();
+        if self.peers.is_empty() {
+            let sm = SyncMessage::new(SyncMessageType::Hello, hello_bytes);
+            self.send(sm, N::BROADCAST).await?;
+            self.stats.record(None, |c| c.hellos_sent += 1);
+        } else {
+            for peer in self.peers.clone() {
+                let sm = SyncMessage::new(SyncMessageType::Hello, hello_bytes.clone());
+                self.send(sm, peer).await?;
+                self.stats.record(Some(peer), |c| c.hellos_sent += 1);
+            }
         }
-        self.last_hello = Instant::now();
 
         Ok(())
     }
 
+    /// How much of a response to send to `peer` in one message, based on the link's MTU.
+    fn response_chunk(&self, peer: &N::Addr) -> usize {
+        self.network
+            .mtu(peer)
+            .saturating_sub(SYNC_FRAME_OVERHEAD)
+            .clamp(MIN_RESPONSE_CHUNK, MAX_SYNC_MESSAGE_SIZE)
+    }
+
+    /// Answer the deferred sync request if there is one and memory allows. Requests that have
+    /// waited so long that the requester will have given up are dropped.
+    async fn retry_deferred(&mut self, client: &mut Client) -> Result<()> {
+        let Some(deferred) = &self.deferred else {
+            return Ok(());
+        };
+        if Instant::now() - deferred.received > SYNC_STALL_TIMEOUT {
+            log::info!("dropping deferred sync request from {}", deferred.from);
+            self.deferred = None;
+            return Ok(());
+        }
+        if !memory::can_afford(self.response_chunk(&deferred.from) * RESPONSE_HEAP_FACTOR) {
+            return Ok(());
+        }
+        // SAFETY: we just checked that it's there
+        let deferred = self.deferred.take().unwrap();
+        self.sync_respond(deferred.from, deferred.request, client)
+            .await
+    }
+
     async fn sync_respond(
         &mut self,
         from: N::Addr,
         request: SyncRequestMessage,
         client: &mut Client,
     ) -> Result<()> {
+        let chunk = self.response_chunk(&from);
+        let heap_needed = chunk * RESPONSE_HEAP_FACTOR;
+        let msg_buf = SendBuffer::take();
+        let Some(mut msg_buf) = msg_buf.filter(|_| memory::can_afford(heap_needed)) else {
+            // Only the latest request is kept. An older one's requester has probably moved on.
+            log::warn!(
+                "sync_respond: deferring request from {from}, {} bytes free",
+                memory::heap_free()
+            );
+            self.deferred = Some(DeferredRequest {
+                from,
+                request,
+                received: Instant::now(),
+            });
+            return Ok(());
+        };
         let mut responder = SyncResponder::new();
-        responder.receive(request)?;
+        responder.receive(request).map_err(malformed)?;
         let mut c = 0;
         while responder.ready() {
-            let mut msg_buf = vec![0u8; MAX_SYNC_MESSAGE_SIZE];
+            if !memory::can_afford(heap_needed) {
+                // The requester will stall and resume from whatever it received so far
+                log::warn!(
+                    "sync_respond: low on memory ({} bytes free); cutting response to {from} short",
+                    memory::heap_free()
+                );
+                break;
+            }
             let len = {
                 let peer_cache = self.peer_caches.entry(from).or_default();
                 responder.poll(
-                    &mut msg_buf,
+                    &mut msg_buf[..chunk],
                     client.provider(),
                     peer_cache,
                     &mut self.buffers,
@@ -270,15 +548,13 @@ where
                 c
             );
             c += 1;
-            msg_buf.truncate(len);
             let response_message =
-                SyncMessage::new(SyncMessageType::Response, msg_buf.into_boxed_slice());
-            let msg = response_message.into_message(self.network.my_address(), from)?;
-            self.network.send_message(msg).await?;
+                SyncMessage::new(SyncMessageType::Response, msg_buf[..len].into());
+            self.send(response_message, from).await?;
         }
-        // Peers only add us to their queue again if they get another hello message. Boost it a
-        // bit to make it more likely that happens.
-        self.boost_hello(SYNC_RESPONSE_BOOST, false);
+        // Peers only add us to their queue again if they get another hello message. The peer
+        // was behind us, which is an inconsistency, so hello again soon.
+        self.reset_hello();
 
         Ok(())
     }
@@ -303,13 +579,27 @@ where
         req_session.last_seen = Instant::now();
         let requester = &mut req_session.requester;
 
-        let cmds = requester.receive(bytes)?;
+        let cmds = match requester.receive(bytes) {
+            Ok(cmds) => cmds,
+            // A response still on its way when we resumed belongs to the session we replaced.
+            // It is only a fault if there was no such session.
+            Err(SyncError::SessionMismatch) if req_session.resumes > 0 => {
+                log::info!("Dropping late response from {from} to its previous session");
+                return Ok(());
+            }
+            Err(SyncError::SessionMismatch) => return Err(SyncError::SessionMismatch.into()),
+            Err(e) => return Err(malformed(e)),
+        };
         if let Some(cmds) = cmds {
             if !cmds.is_empty() {
+                let count = cmds.len() as u32;
+                self.stats
+                    .record(Some(from), |c| c.commands_received += count);
                 let peer_cache = self.peer_caches.entry(from).or_default();
                 add_commands(
                     &cmds,
                     &mut req_session.trx,
+                    &mut req_session.received,
                     peer_cache,
                     &mut self.sink,
                     client,
@@ -323,16 +613,20 @@ where
             log::info!("process_response: sync ended with {from}");
             // SAFETY: we know the session exists because we've been using it
             let mut req_session = self.sync_session.take().unwrap();
-            if let Some(trx) = req_session.trx {
+            if req_session.trx.is_some() {
                 log::info!("process_response: commiting");
-                client.commit(trx, &mut self.sink, &mut self.buffers.primary)?;
+                self.commit_session(&mut req_session, client)?;
                 log::info!("process_response: done commiting");
             } else {
                 log::error!("process_response: No transaction!!")
             }
             self.sync_queue.remove(&from);
-            // Boost hello after we've finished a sync
-            self.boost_hello(SYNC_FINISH_BOOST, true);
+            self.neighbors.sync_finished(from);
+            self.health.success(from);
+            self.stats.record(Some(from), |c| c.sessions_completed += 1);
+            self.stats.event(from, SyncEventKind::Finished);
+            // Our head has likely changed after we've finished a sync
+            self.reset_hello();
         }
 
         Ok(())
@@ -347,29 +641,143 @@ where
     }
 
     async fn handle_message(&mut self, msg: Message<N::Addr>, client: &mut Client) -> Result<()> {
-        let (from, sm) = SyncMessage::from_message(msg)?;
+        let rssi = msg.rssi;
+        let sender = msg.sender;
+        let len = msg.contents.len() as u64;
+        let (from, sm) = match SyncMessage::from_message(msg, &mut self.auth) {
+            Ok(v) => {
+                self.network.authenticated(sender);
+                v
+            }
+            // Broadcasts go out on every link, so we expect to hear most of them twice
+            Err(Error::Auth(AuthError::Replay(_))) => {
+                log::debug!("dropping duplicate sync message from {sender}");
+                self.stats.record(Some(sender), |c| c.replayed += 1);
+                return Ok(());
+            }
+            Err(Error::Auth(e)) => {
+                log::warn!(
+                    "dropping sync message from {sender}: {e} ({} bad MAC, {} replayed)",
+                    self.auth.bad_mac,
+                    self.auth.replayed
+                );
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        self.stats.record(Some(from), |c| c.bytes_received += len);
         log::info!(
             "received SyncMessage {:?} from {from}, len {}",
             sm.t,
             sm.bytes.len()
         );
+        // Hellos from anyone are still checked for address conflicts, in dispatch_message
+        if !matches!(sm.t, SyncMessageType::Hello) && !self.is_peer(&from) {
+            log::debug!(
+                "ignoring sync {:?} from {from}; not a configured peer",
+                sm.t
+            );
+            return Ok(());
+        }
+        let result = self.dispatch_message(from, rssi, sm, client).await;
+        if let Some(fault) = result.as_ref().err().and_then(Fault::classify) {
+            self.penalize(from, fault);
+            // A session with a peer that sends garbage is not going to finish
+            if fault == Fault::Malformed
+                && self
+                    .sync_session
+                    .as_ref()
+                    .is_some_and(|s| s.peer_addr == from)
+            {
+                self.end_session(client)?;
+                self.stats.event(from, SyncEventKind::Failed);
+            }
+        }
+        result
+    }
+
+    async fn dispatch_message(
+        &mut self,
+        from: N::Addr,
+        rssi: Option<i8>,
+        sm: SyncMessage,
+        client: &mut Client,
+    ) -> Result<()> {
         match sm.t {
             SyncMessageType::Request => {
-                let st: SyncType = postcard::from_bytes(&sm.bytes)?;
+                self.neighbors.heard(from, rssi);
+                if self.health.is_banned(&from) {
+                    log::debug!("ignoring sync request from banned peer {from}");
+                    return Ok(());
+                }
+                let st: SyncType = postcard::from_bytes(&sm.bytes).map_err(malformed)?;
                 match st {
                     SyncType::Poll { request, .. } => {
                         self.sync_respond(from, request, client).await?
                     }
-                    _ => unimplemented!(),
+                    _ => return Err(Error::Malformed("unsupported sync type".into())),
                 };
             }
             SyncMessageType::Response => {
+                self.neighbors.heard(from, rssi);
                 self.process_response(from, &sm.bytes, client).await?;
             }
             SyncMessageType::Hello => {
-                let hello: HelloMessage<N> = postcard::from_bytes(&sm.bytes)?;
+                let hello: HelloMessage<N> = postcard::from_bytes(&sm.bytes).map_err(malformed)?;
+                self.stats.record(Some(from), |c| c.hellos_received += 1);
+
+                // Another device using our address shows up either as the sender of a hello or
+                // in the neighbor digest of a hello from someone who can hear both of us.
+                for d in &hello.neighbors {
+                    if let Some(device) = d.device {
+                        self.check_address(d.address, device);
+                    }
+                }
+                if hello.address == self.network.my_address() {
+                    // Either our own hello came back or another device has our address. We
+                    // can't sync with it until one of us moves.
+                    self.check_address(hello.address, hello.device);
+                    return Ok(());
+                }
 
-                // BUG: check if it the same as our head before accessing storage.
+                // Filter on who sent the hello, not on who it claims to be from
+                if !self.is_peer(&from) {
+                    log::debug!("ignoring hello from {from}; not a configured peer");
+                    return Ok(());
+                }
+                if hello.address != from {
+                    log::warn!(
+                        "ignoring hello from {from} claiming to be {}",
+                        hello.address
+                    );
+                    return Ok(());
+                }
+                // How many of our packets they lose tells the link how much redundancy to send
+                let my_address = self.network.my_address();
+                let loss = hello
+                    .neighbors
+                    .iter()
+                    .find(|n| n.address == my_address)
+                    .and_then(|n| n.loss);
+                if let Some(loss) = loss {
+                    self.network.set_tx_loss(hello.address, loss);
+                }
+                self.neighbors.hello(
+                    hello.address,
+                    hello.device,
+                    rssi,
+                    hello.head,
+                    hello.neighbors,
+                );
+
+                if hello.head == self.head(client)? {
+                    // They agree with us. Enough of these and we can skip our own hello.
+                    self.hello_timer.consistent();
+                    self.sync_queue.remove(&hello.address);
+                    return Ok(());
+                }
+                // One of us is behind, so hellos should speed up until we agree again.
+                self.reset_hello();
 
                 let has_address = {
                     let provider = client.provider();
@@ -382,6 +790,8 @@ where
                 if has_address {
                     // We're already caught up; remove this from the queue
                     self.sync_queue.remove(&hello.address);
+                } else if self.health.is_banned(&hello.address) {
+                    log::debug!("not syncing with banned peer {}", hello.address);
                 } else {
                     // If there is not enough space, we intentionally drop the hello
                     self.sync_queue.insert(hello.address).ok();
@@ -395,6 +805,7 @@ where
 async fn add_commands(
     cmds: &[impl Command + core::fmt::Debug],
     trx: &mut Option<Transaction<SP, PS>>,
+    received: &mut Vec<Address>,
     peer_cache: &mut PeerCache,
     sink: &mut PubSubSink<'_>,
     client: &mut Client,
@@ -408,13 +819,24 @@ async fn add_commands(
         yield_now().await;
     }
 
-    // Update peer cache
-    let addresses = cmds.iter().filter_map(|cmd| cmd.address().ok());
+    let addresses: Vec<Address> = cmds.iter().filter_map(|cmd| cmd.address().ok()).collect();
+    received.extend_from_slice(&addresses);
+    update_peer_cache(&addresses, peer_cache, client, graph_id, buffer)
+}
+
+/// Add the commands at `addresses` that are in storage to `peer_cache`.
+fn update_peer_cache(
+    addresses: &[Address],
+    peer_cache: &mut PeerCache,
+    client: &mut Client,
+    graph_id: GraphId,
+    buffer: &mut TraversalBuffer,
+) -> Result<()> {
     let storage = client
         .provider()
         .get_storage(graph_id)
         .map_err(|e| ClientError::StorageError(e))?;
-    for addr in addresses {
+    for &addr in addresses {
         if let Some(cmd_loc) = storage
             .get_location(addr, buffer)
             .map_err(|e| ClientError::StorageError(e))?
@@ -428,6 +850,22 @@ async fn add_commands(
     Ok(())
 }
 
+/// Blame an error decoding a peer's data on the peer. See [`Fault::classify`].
+fn malformed(e: impl core::fmt::Display) -> Error {
+    Error::Malformed(format!("{e}"))
+}
+
+fn now_ms() -> u64 {
+    Instant::now().as_millis()
+}
+
+/// Seed for the hello timer, so that boards powered on together do not hello in lockstep.
+fn trickle_seed() -> u32 {
+    let mut seed = [0u8; 4];
+    getrandom::getrandom(&mut seed).ok();
+    u32::from_le_bytes(seed)
+}
+
 fn dump_commands(cmds: &[impl Command]) {
     for c in cmds {
         log::info!(
@@ -0,0 +1,44 @@
+//! Counting boots without writing to flash on every one.
+//!
+//! Sync replay counters have to keep increasing across reboots (see
+//! [`auth`](crate::aranya::auth)), so each boot gets a larger epoch than the last. Power-ons are
+//! counted in the parameters. Resets in between are counted here, in RTC RAM, which keeps its
+//! contents across a reset but not when the power goes.
+
+use core::ptr::addr_of_mut;
+
+use esp_hal::ram;
+
+/// Checks that [`RESETS`] was written by us, rather than left over from power-on.
+const MAGIC: u32 = 0x5a17_b007;
+
+struct ResetCount {
+    count: u16,
+    /// `MAGIC` xor `count`
+    check: u32,
+}
+
+#[ram(rtc_fast, persistent)]
+static mut RESETS: ResetCount = ResetCount { count: 0, check: 0 };
+
+/// Count this boot, and return how many resets there have been since the count started over.
+/// `None` means it has just started over, because the device powered on or the count ran out,
+/// and the power-on count in the parameters should go up.
+pub fn count_reset() -> Option<u16> {
+    // SAFETY: This runs once, early in `main`, before anything else could touch `RESETS`.
+    let resets = unsafe { &mut *addr_of_mut!(RESETS) };
+    let next = if resets.check == MAGIC ^ resets.count as u32 {
+        resets.count.checked_add(1)
+    } else {
+        None
+    };
+    resets.count = next.unwrap_or(0);
+    resets.check = MAGIC ^ resets.count as u32;
+    next
+}
+
+/// The epoch for this boot, which increases with every boot until the power-on count wraps
+/// after 65536 power-ons.
+pub fn epoch(power_ons: u32, resets: u16) -> u32 {
+    power_ons << 16 | resets as u32
+}
@@ -7,6 +7,8 @@ use crate::aranya::policy;
 pub struct MessageState {
     pub unseen_count: usize,
     pub mentioned: bool,
+    /// A sync session is running
+    pub syncing: bool,
 }
 
 #[derive(Debug)]
@@ -1,13 +1,13 @@
 #![no_std]
 #![no_main]
 #![feature(impl_trait_in_assoc_type)]
-#![feature(core_io_borrowed_buf)]
 #![feature(new_zeroed_alloc)]
 
 extern crate alloc;
 
 mod application;
 pub mod aranya;
+mod boot;
 mod built;
 mod hardware;
 mod net;
@@ -18,7 +18,7 @@ mod watchdog;
 use aranya::daemon::Daemon;
 use aranya_crypto::{id::IdExt, DeviceId, Rng};
 use embassy_executor::Spawner;
-#[cfg(feature = "net-esp-now")]
+use embassy_futures::select::{select, Either};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
 use embassy_time::{Duration, Timer};
 use esp_backtrace as _;
@@ -36,22 +36,26 @@ use esp_hal_embassy::{main, InterruptExecutor};
 use esp_irda_transceiver::IrdaTransceiver;
 use esp_rmt_neopixel::{Neopixel, RgbU8};
 use esp_storage::FlashStorage;
-#[cfg(feature = "net-esp-now")]
-use esp_wifi::{esp_now::EspNowManager, init, EspWifiController};
+#[cfg(any(feature = "net-esp-now", feature = "net-wifi"))]
+use esp_wifi::{init, EspWifiController};
 use hardware::neopixel::NEOPIXEL_SIGNAL;
 use log::info;
-use net::NetworkEngine;
+use net::{address::ADDRESS_CHANGED, NetworkEngine};
 use parameter_store::{EmbeddedStorageIO, ParameterStore, ParameterStoreError, Parameters};
 use static_cell::StaticCell;
 
 use crate::{
     application::BUTTON_CHANNEL,
-    aranya::policy,
+    aranya::{auth::TeamKey, policy},
     hardware::neopixel::{rainbow_at, MessageState, NeopixelMessage},
     watchdog::Watchdog,
 };
 
-const MAX_NETWORK_ENGINES: usize = 2;
+const MAX_NETWORK_ENGINES: usize = 4;
+
+/// The parameters, shared by the tasks that change them.
+type SharedParameters =
+    Mutex<CriticalSectionRawMutex, ParameterStore<Parameters, EmbeddedStorageIO<FlashStorage>>>;
 
 //static NET_STACK: StaticCell<Stack<8192>> = StaticCell::new();
 
@@ -69,6 +73,7 @@ async fn main(spawner: Spawner) {
     // Initialize heaps
     esp_alloc::heap_allocator!(96 * 1024);
     esp_alloc::psram_allocator!(peripherals.PSRAM, esp_hal::psram);
+    aranya::memory::init();
 
     esp_println::logger::init_logger_from_env();
     info!("Embassy initialized!");
@@ -100,17 +105,32 @@ async fn main(spawner: Spawner) {
     let parameter_values = match parameters.fetch() {
         Ok(p) => p,
         Err(e) => match e {
+            // Only a block that fails its checksum, like erased flash, is replaced. Defaults have
+            // no graph ID, so writing them erases the graph below.
             ParameterStoreError::Corrupt => {
                 log::info!("Parameters corrupt; writing defaults");
                 parameters
                     .store(&Parameters::default())
                     .expect("could not store parameters")
             }
-            e => panic!("{e}"),
+            // Intact parameters we can't read, like ones written by newer firmware, are left for
+            // the config tool to fix rather than wiping the device.
+            e => panic!("could not read parameters: {e}"),
         },
     };
     log::info!("p: {parameter_values:?}");
 
+    // The boot epoch keeps sync replay counters increasing across reboots. Only power-ons are
+    // counted in flash.
+    let resets = boot::count_reset();
+    let parameter_values = match resets {
+        Some(_) => parameter_values,
+        None => parameters
+            .update(|p| p.boot_count = p.boot_count.wrapping_add(1))
+            .expect("could not update boot count"),
+    };
+    let boot_epoch = boot::epoch(parameter_values.boot_count, resets.unwrap_or(0));
+
     // Auto-erase the storage when the parameters' graph ID is none
     #[cfg(feature = "storage-internal")]
     if parameter_values.graph_id.is_none() {
@@ -166,10 +186,67 @@ async fn main(spawner: Spawner) {
         Some(id) => id.into(),
     };
     log::info!("Device ID is {device_id}");
+    let device_id_bytes: [u8; 32] = device_id.into();
+
+    // An address of 0 (broadcast) means we should pick our own, and keep it from now on. It is
+    // kept apart from the configured address, so that only a picked address is ever reassigned.
+    // Older firmware saved the address it derived as the configured one, so move that back.
+    let derived = net::address::derive(&device_id_bytes);
+    let parameter_values =
+        if parameter_values.address == derived && parameter_values.auto_address == 0 {
+            parameters
+                .update(|p| {
+                    p.address = 0;
+                    p.auto_address = derived;
+                })
+                .expect("could not update address")
+        } else {
+            parameter_values
+        };
+    let address = match (parameter_values.address, parameter_values.auto_address) {
+        (0, 0) => {
+            parameters
+                .update(|p| p.auto_address = derived)
+                .expect("could not update address");
+            derived
+        }
+        (0, picked) => picked,
+        (configured, _) => configured,
+    };
+    net::address::init(address, parameter_values.address == 0);
+    log::info!("Link address is {address}");
+    if !parameter_values.peers.is_empty() {
+        log::info!("Syncing only with peers {:?}", parameter_values.peers);
+    }
+    let team_key = TeamKey::derive(parameter_values.team_secret.as_ref(), graph_id, boot_epoch);
 
     let mut network_engines: heapless::Vec<&'static dyn NetworkEngine, MAX_NETWORK_ENGINES> =
         heapless::Vec::new();
 
+    // A cable is the most reliable link, so add it first to make it preferred
+    #[cfg(feature = "net-uart")]
+    if let Some(wired) = board_def.wired {
+        let engine = net::uart::start(peripherals.UART2, wired.tx, wired.rx);
+
+        daemon.add_uart_interface(net::uart::UartNetworkInterface::new(engine));
+
+        if network_engines.push(engine).is_err() {
+            log::info!("could not start wired network engine");
+        }
+    }
+
+    // A host on USB is only reachable over USB, so its place in the order doesn't matter much
+    #[cfg(feature = "net-usb")]
+    {
+        let engine = net::usb::start();
+
+        daemon.add_usb_interface(net::usb::UsbNetworkInterface::new(engine));
+
+        if network_engines.push(engine).is_err() {
+            log::info!("could not start USB network engine");
+        }
+    }
+
     #[cfg(feature = "net-esp-now")]
     {
         use esp_hal::gpio::{Level, Output};
@@ -185,38 +262,67 @@ async fn main(spawner: Spawner) {
         log::info!("esp-now version {}", esp_now.version().unwrap());
 
         let (manager, sender, receiver) = esp_now.split();
-        let _manager: &'static mut EspNowManager<'static> =
-            mk_static!(EspNowManager<'static>, manager);
         let receiver = Mutex::<CriticalSectionRawMutex, _>::new(receiver);
         let sender = Mutex::<CriticalSectionRawMutex, _>::new(sender);
         let tx_led = board_def.indicators.tx_led.map(|pin| Output::new(pin, Level::Low));
         let rx_led = board_def.indicators.rx_led.map(|pin| Output::new(pin, Level::Low));
 
-        let engine =
-            net::espnow::start(sender, receiver, parameter_values.address, tx_led, rx_led).await;
+        let engine = net::espnow::start(
+            manager,
+            sender,
+            receiver,
+            &parameter_values.radio,
+            parameter_values.team_secret.as_ref(),
+            tx_led,
+            rx_led,
+        )
+        .await;
 
-        daemon.add_esp_now_interface(engine.interface(), graph_id);
+        daemon.add_esp_now_interface(engine.interface());
 
         if network_engines.push(engine).is_err() {
             log::info!("could not start ESP Now network engine");
         }
     };
 
+    // The Wi-Fi tasks run on this executor, so there is no engine to start
+    #[cfg(feature = "net-wifi")]
+    match parameter_values.wifi.clone() {
+        Some(credentials) => {
+            let rng = esp_hal::rng::Rng::new(peripherals.RNG);
+            let init = &*mk_static!(
+                EspWifiController<'static>,
+                init(timer_g0.timer0, rng, peripherals.RADIO_CLK).unwrap()
+            );
+            let engine = net::wifi::start(spawner, init, peripherals.WIFI, credentials);
+
+            daemon.add_wifi_interface(engine.interface());
+        }
+        None => log::info!("No Wi-Fi network set; not joining one"),
+    }
+
     #[cfg(feature = "net-irda")]
     if let Some(ir) = board_def.ir {
         if let Some(acc_power) = &mut acc_power {
             acc_power.set_high();
         }
         let irts = IrdaTransceiver::new(peripherals.UART1, ir.tx, ir.rx, ir.en);
-        let engine = net::irda::start(irts, parameter_values.address).await;
+        let engine = net::irda::start(irts).await;
 
-        daemon.add_irda_interface(engine.interface(), graph_id);
+        daemon.add_irda_interface(engine.interface());
 
         if network_engines.push(engine).is_err() {
             log::info!("could not start IR network engine");
         }
     }
 
+    daemon.enable_sync(
+        graph_id,
+        net::address::device_tag(&device_id_bytes),
+        &parameter_values.peers,
+        team_key,
+    );
+
     /* TODO(chip): re-enable this when esp-storage works multi-core
     // Spawn a task on the second CPU to run the network engines
     let mut cpu_control = CpuControl::new(peripherals.CPU_CTRL);
@@ -259,6 +365,8 @@ async fn main(spawner: Spawner) {
         device_id,
     ));
 
+    let parameters = &*mk_static!(SharedParameters, Mutex::new(parameters));
+    spawner.must_spawn(parameter_task(parameters));
     spawner.must_spawn(button_task(board_def.button, parameters));
     spawner.must_spawn(led_task(neopixel));
 
@@ -279,21 +387,55 @@ async fn net_task(
     spawner.must_spawn(watchdog::idle_task1(wdt));
 }
 
+/// Holding the button this long and releasing it toggles radio silence.
+const SILENCE_HOLD: Duration = Duration::from_secs(3);
+/// Holding the button this long erases storage.
+const NUKE_HOLD: Duration = Duration::from_secs(10);
+
+/// Saves address and radio changes made while running to the parameters.
 #[embassy_executor::task]
-async fn button_task(
-    pin: AnyPin,
-    mut parameters: ParameterStore<Parameters, EmbeddedStorageIO<FlashStorage>>,
-) {
+async fn parameter_task(parameters: &'static SharedParameters) {
+    loop {
+        #[cfg(feature = "net-esp-now")]
+        let radio_changed = net::radio::RADIO_CHANGED.wait();
+        #[cfg(not(feature = "net-esp-now"))]
+        let radio_changed = core::future::pending::<parameter_store::RadioSettings>();
+        match select(ADDRESS_CHANGED.wait(), radio_changed).await {
+            Either::First(address) => {
+                // Only picked addresses are reassigned
+                if let Err(e) = parameters.lock().await.update(|p| p.auto_address = address) {
+                    log::error!("could not store address {address}: {e}");
+                }
+            }
+            Either::Second(radio) => {
+                if let Err(e) = parameters.lock().await.update(|p| p.radio = radio) {
+                    log::error!("could not store radio settings: {e}");
+                }
+            }
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn button_task(pin: AnyPin, parameters: &'static SharedParameters) {
     let mut driver = Input::new(pin, Pull::Up);
     loop {
         driver.wait_for_falling_edge().await;
-        match embassy_time::with_timeout(Duration::from_secs(10), driver.wait_for_high()).await {
+        if embassy_time::with_timeout(SILENCE_HOLD, driver.wait_for_high())
+            .await
+            .is_ok()
+        {
+            BUTTON_CHANNEL.send(()).await;
+            continue;
+        }
+        match embassy_time::with_timeout(NUKE_HOLD - SILENCE_HOLD, driver.wait_for_high()).await {
             Ok(_) => {
-                BUTTON_CHANNEL.send(()).await;
+                net::impair::update(|i| i.silent = !i.silent);
+                log::info!("network impairment: {}", net::impair::get());
             }
             Err(_te) => {
                 // Button has been held for five seconds; DESTROY THE WORLD
-                parameters.update(|p| p.graph_id = None).ok();
+                parameters.lock().await.update(|p| p.graph_id = None).ok();
                 #[cfg(feature = "storage-internal")]
                 storage::internal::nuke().expect("could not nuke!?");
                 log::info!("Storage nuked. Release button to reset.");
@@ -311,6 +453,23 @@ async fn button_task(
 
 const MENTION_CURVE: [u8; 8] = [5, 15, 25, 34, 50, 40, 25, 10];
 const MESSAGES_CURVE: [u8; 4] = [10, 20, 10, 0];
+const SYNCING_COLOR: RgbU8 = RgbU8 {
+    red: 0,
+    green: 3,
+    blue: 8,
+};
+/// Shown while idle with the network impaired
+const IMPAIRED_COLOR: RgbU8 = RgbU8 {
+    red: 8,
+    green: 4,
+    blue: 0,
+};
+/// Shown while idle in radio silence
+const SILENT_COLOR: RgbU8 = RgbU8 {
+    red: 8,
+    green: 0,
+    blue: 0,
+};
 
 #[embassy_executor::task]
 async fn led_task(mut neopixel: Neopixel<'static>) {
@@ -401,7 +560,16 @@ async fn led_task(mut neopixel: Neopixel<'static>) {
                 match phase {
                     // Idle
                     0 => {
-                        new_color = ambient_color;
+                        let impairment = net::impair::get();
+                        new_color = if impairment.silent {
+                            SILENT_COLOR
+                        } else if state.syncing {
+                            SYNCING_COLOR
+                        } else if impairment.is_active() {
+                            IMPAIRED_COLOR
+                        } else {
+                            ambient_color
+                        };
                     }
                     1 => {
                         if state.mentioned {
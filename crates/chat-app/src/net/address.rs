@@ -0,0 +1,89 @@
+//! This device's link address.
+//!
+//! Devices use the same 16-bit address on every link. An address of `0` in the parameters means
+//! "pick one automatically": the address is derived from the device ID and written back to the
+//! parameters, so it stays the same across reboots. `0` itself is the broadcast address on
+//! every link, so it is never chosen.
+//!
+//! Derived addresses can collide. Hellos carry a [`device_tag`] so that the
+//! [`SyncEngine`](crate::aranya::syncer::SyncEngine) can tell when another device is using our
+//! address. When that happens, the device with the greater tag calls [`reassign`] to move to a
+//! new random address, and the new address is published on [`ADDRESS_CHANGED`] to be persisted.
+//!
+//! Only derived addresses are reassigned. An address set by hand may be in other boards'
+//! configured peer lists, so a device keeps it through a conflict and the conflict is left for
+//! whoever configured it to fix.
+
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use sha2::{Digest, Sha256};
+
+/// The broadcast address on every link.
+const BROADCAST: u16 = 0;
+const DERIVE_INFO: &[u8] = b"aranya-embedded link address v1";
+
+static ADDRESS: AtomicU16 = AtomicU16::new(BROADCAST);
+/// Whether [`ADDRESS`] was derived rather than set by hand.
+static DERIVED: AtomicBool = AtomicBool::new(false);
+
+/// Signaled with the new address whenever it is reassigned.
+pub static ADDRESS_CHANGED: Signal<CriticalSectionRawMutex, u16> = Signal::new();
+
+/// Derive an address from a device ID.
+pub fn derive(device_id: &[u8; 32]) -> u16 {
+    let mut hash = Sha256::new();
+    hash.update(DERIVE_INFO);
+    hash.update(device_id);
+    let hash = hash.finalize();
+    // Try successive pairs of bytes until we get one that isn't the broadcast address. 16 zero
+    // pairs in a row won't happen, but fall back to 1 just in case.
+    hash.chunks_exact(2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .find(|a| *a != BROADCAST)
+        .unwrap_or(1)
+}
+
+/// A short identifier for a device, sent in hellos to detect address conflicts.
+pub fn device_tag(device_id: &[u8; 32]) -> u64 {
+    u64::from_be_bytes(device_id[..8].try_into().expect("8 bytes"))
+}
+
+/// Set the address at startup. `derived` is whether it was picked automatically, and so may be
+/// reassigned.
+pub fn init(address: u16, derived: bool) {
+    ADDRESS.store(address, Ordering::Relaxed);
+    DERIVED.store(derived, Ordering::Relaxed);
+}
+
+/// The current address.
+pub fn get() -> u16 {
+    ADDRESS.load(Ordering::Relaxed)
+}
+
+/// The current address, for link crates that read it themselves.
+#[cfg(any(feature = "net-uart", feature = "net-usb"))]
+pub fn shared() -> &'static AtomicU16 {
+    &ADDRESS
+}
+
+/// Move to a new random address after a conflict, and return it. An address set by hand is
+/// kept and returned as is.
+pub fn reassign() -> u16 {
+    let old = get();
+    if !DERIVED.load(Ordering::Relaxed) {
+        log::warn!("address {old} is in use by another device; not changing a configured address");
+        return old;
+    }
+    let address = loop {
+        let mut buf = [0u8; 2];
+        getrandom::getrandom(&mut buf).ok();
+        let address = u16::from_be_bytes(buf);
+        if address != BROADCAST && address != old {
+            break address;
+        }
+    };
+    ADDRESS.store(address, Ordering::Relaxed);
+    ADDRESS_CHANGED.signal(address);
+    address
+}
@@ -4,43 +4,59 @@
 //!
 //! ## Theory of Operation
 //!
-//! A [`Message`]'s payload is split up into chunks with [`raptorq`], which adds redundancy to
-//! allow reconstruction from damaged packets. Then each chunk is packaged in an [`EspNowPacket`] and
-//! sent over the EspNow Interface.
+//! Messages are framed with [`fountain_framing`], which splits them into redundant chunks so they
+//! can be reconstructed from damaged or missing packets. Each frame is sent as one ESP-NOW
+//! datagram.
 //!
-//! On the receiving end, it reads the Esp Now interface until a valid packet header is found,
-//! then the packet is read and given to an [`EspNowMessageReconstructor`], which collects packets
-//! sent to this address until it can reconstruct the original [`Message`]. Once a packet is
-//! successfully reconstructed, it is returned to the caller.
+//! The engine learns each neighbor's MAC address from the frames it receives, and registers the
+//! most recently heard neighbors as ESP-NOW peers. A MAC address is only learned from a message
+//! that the syncer has [authenticated](NetworkInterface::authenticated), so a forged frame can't
+//! redirect a neighbor's traffic. Frames for a registered peer are unicast, so
+//! the radio retries them until they are acknowledged. Only frames for the broadcast address, or
+//! for neighbors we haven't heard from yet, are broadcast. When the team has a secret, unicast
+//! frames are also encrypted with a local master key derived from it.
 //!
-//! ## [`EspNowPacket`] on-wire format
+//! Each side registers its own most recent neighbors, so a neighbor may have dropped us while we
+//! still have it registered. It then acknowledges our encrypted frames but can't decrypt them.
+//! So if we send a peer [`MAX_UNANSWERED`] messages without hearing back, we broadcast to it
+//! instead until we do, which lets it register us again. If the radio gives up on
+//! several frames in a row to a peer, the next message to it fails to send, so that a
+//! [`MultiInterface`](super::multi::MultiInterface) can try another link.
 //!
-//! The packet is a header followed by the payload bytes and finally by a 16-bit CRC. The header
-//! is 12 bytes and looks like this:
+//! On the receiving end, each datagram is parsed as a frame, and frames sent to this address are
+//! given to a [`Decoder`], which collects packets until it can reconstruct the original
+//! [`Message`]. Once a message is successfully reconstructed, it is returned to the caller.
 //!
-//! ```
-//! |  0  |  1  |  2  |  3  |  4  |  5  |  6  |  7          |  8  |  9  | 10  | 11  |
-//! | magic           | recipient |  sender   | message_seq | chunk_len | total_len |
-//! | F0h | 0Fh | F0h |    u16    |    u16    |      u8     |    u16    |    u16    |
-//! ```
+//! A message that hasn't finished after its packets stop arriving is asked about again with a
+//! [`RepairRequest`] to its sender, which answers with more repair packets from an
+//! [`EncoderCache`] of its last few messages. Requests are only answered for neighbors whose
+//! messages have been authenticated.
 //!
+//! The engine owns the radio, and sets its channel, PHY rate and transmit power as the
+//! [`radio`](super::radio) settings say, at startup and whenever they change.
 
-use alloc::{collections::btree_map::BTreeMap, vec::Vec};
-use core::{
-    io::BorrowedBuf,
-    mem::MaybeUninit,
-    sync::atomic::{AtomicU32, Ordering},
-};
+use alloc::collections::btree_map::BTreeMap;
+use core::sync::atomic::{AtomicU32, Ordering};
 
-use crc::{self, Crc};
+use embassy_futures::select::{select, Either};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embassy_time::{Instant, Timer};
+use embassy_time::{Duration, Instant, Timer};
 use esp_hal::gpio::Output;
-use esp_wifi::esp_now::{EspNowReceiver, EspNowSender, BROADCAST_ADDRESS};
-use raptorq::{EncodingPacket, ObjectTransmissionInformation};
-
-use super::{Message, NetworkEngine, NetworkError, NetworkInterface};
-use crate::{mk_static, util::SliceCursor};
+use esp_wifi::esp_now::{EspNowManager, EspNowReceiver, EspNowSender, PeerInfo, BROADCAST_ADDRESS};
+use fountain_framing::{
+    max_frame_size, Decoder, Encoder, EncoderCache, FecConfig, FrameError, LossReports, Packet,
+    RepairRequest,
+};
+use hkdf::Hkdf;
+use parameter_store::RadioSettings;
+use sha2::Sha256;
+
+use super::{
+    address,
+    radio::{self, RadioReport},
+    Message, NetworkEngine, NetworkError, NetworkInterface, MAX_MESSAGE_LEN,
+};
+use crate::{aranya::memory, mk_static};
 
 const ESP_NOW_PACKET_QUEUE_SIZE: usize = 2;
 type Mutex<T> = embassy_sync::mutex::Mutex<CriticalSectionRawMutex, T>;
@@ -51,177 +67,230 @@ type Sender<'a, T> =
 type Receiver<'a, T> =
     embassy_sync::channel::Receiver<'a, CriticalSectionRawMutex, T, ESP_NOW_PACKET_QUEUE_SIZE>;
 
-const CRC: Crc<u16> = Crc::<u16>::new(&crc::CRC_16_XMODEM); // XMODEM seems appropriate. :D
-const ESP_NOW_MAGIC: [u8; 3] = [0xF0, 0x0F, 0xF0];
-const ESP_NOW_CHUNK_SIZE: usize = 64; // needs to be less than 65536 because this will become the raptorq MTU which is u16
-const ESP_NOW_HEADER_SIZE: usize = 9; // recipient, sender, chunk_seq, chunk_len, total_len
-const ESP_NOW_CRC_SIZE: usize = (CRC.algorithm.width / 8) as usize;
-const RAPTORQ_OVERHEAD: usize = 4; // determined empirically - I don't know if there's a way to ask raptorq for this
-const ESP_NOW_PACKET_SIZE: usize = ESP_NOW_MAGIC.len()
-    + ESP_NOW_HEADER_SIZE
-    + ESP_NOW_CHUNK_SIZE
-    + ESP_NOW_CRC_SIZE
-    + RAPTORQ_OVERHEAD;
-
-/// The minimum time to wait between packets.
-const RANDOM_MIN: u32 = 25;
-/// The distance between the minimum and maximum times to wait. Time between packets is then
-/// unformly distributed between `RANDOM_MIN` and `RANDOM_MIN + RANDOM_SPREAD`.
-const RANDOM_SPREAD: u32 = 100;
+const ESP_NOW_CHUNK_SIZE: usize = 64;
+/// See [`NetworkInterface::mtu`].
+const ESP_NOW_MTU: usize = 8 * 1024;
+const ESP_NOW_FRAME_SIZE: usize = max_frame_size(ESP_NOW_CHUNK_SIZE);
+/// Unicast frames are already retried by the radio, so less repair is needed than on IR.
+const ESP_NOW_FEC: FecConfig = FecConfig {
+    min_overhead: 10,
+    max_overhead: 100,
+    min_repair: 2,
+};
+
 /// How long to wait to retry after a failed send.
 const SEND_RETRY_DELAY_MS: u64 = 50;
+/// How often to ask for repairs to messages that have stopped arriving. Comfortably longer than
+/// the gap between two packets of a message.
+const REPAIR_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// The most neighbors we register as ESP-NOW peers. ESP-NOW allows 7 encrypted peers by
+/// default.
+const MAX_UNICAST_PEERS: usize = 6;
+/// After this many unicast frames in a row to a peer go unacknowledged, we call it unreachable.
+const MAX_UNACKED_FRAMES: u8 = 4;
+/// After this many messages to a peer without an answer, we broadcast to it in case it has
+/// dropped us as a peer.
+const MAX_UNANSWERED: u8 = 3;
+const KDF_INFO: &[u8] = b"aranya-embedded esp-now keys v1";
 
 #[derive(Debug, thiserror::Error)]
 pub enum EspNowError {
     #[error("EspNow Error")]
     EspNow,
+    #[error("framing error: {0}")]
+    Frame(#[from] FrameError),
+    #[error("{0} is not acknowledging frames")]
+    Unreachable(u16),
 }
 
-/// `EspNowPacket` is one link-layer packet on the ESP Now interface
-pub struct EspNowPacket {
-    /// Recipient address.
-    pub recipient: u16,
-    /// Sender address.
-    pub sender: u16,
-    /// Identifier for this sequence of packets. All packets in the same message have the
-    /// same `message_seq`.
-    pub message_seq: u8,
-    // `chunk_len` is never read but the field exists for documentary purposes and because it
-    // will be used in the likely event we refactor to bincode 2.0.
-    #[allow(dead_code)]
-    /// Length of the `contents`` in this packet.
-    pub chunk_len: u16,
-    /// Total length of the message encoded by these packets.
-    pub total_len: u16,
-    /// The encoded payload of this packet.
-    pub contents: heapless::Vec<u8, { ESP_NOW_CHUNK_SIZE + RAPTORQ_OVERHEAD }>,
-}
-
-/// An EspNowMessageReconstructor consumes a series of packets to reconstruct the message
-/// encoded within.
-pub struct EspNowMessageReconstructor {
-    decoder: raptorq::Decoder,
-    message_seq: u8,
-    total_len: u16,
-    packets_recvd: usize,
-    finished: bool,
+/// A packet received over ESP-NOW.
+struct ReceivedPacket {
+    packet: Packet,
+    /// Received signal strength in dBm.
+    rssi: i8,
+    /// The MAC address it came from.
+    mac: [u8; 6],
 }
 
-impl EspNowMessageReconstructor {
-    /// Create a new message reconstructor. The `initial_packet` argument is just used to
-    /// set up some decoder parameters. You should still call [`add_packet`](Self::add_packet)
-    /// after creating the reconstructor.
-    pub fn new(initial_packet: &EspNowPacket) -> EspNowMessageReconstructor {
-        EspNowMessageReconstructor {
-            decoder: raptorq::Decoder::new(ObjectTransmissionInformation::with_defaults(
-                initial_packet.total_len as u64,
-                ESP_NOW_CHUNK_SIZE as u16,
-            )),
-            message_seq: initial_packet.message_seq,
-            total_len: initial_packet.total_len,
-            packets_recvd: 0,
-            finished: false,
-        }
-    }
-
-    /// Add a packet to the reconstructor. When enough packets are added to reproduce the
-    /// original message, this will return `Some(data)`. Until then it will return `None`.
-    pub fn add_packet(&mut self, packet: EspNowPacket) -> Option<Vec<u8>> {
-        if packet.message_seq != self.message_seq || packet.total_len != self.total_len {
-            // sequence or length id different; this is a new packet sequence.
-            // Reset our state.
-            if !self.finished {
-                log::info!(
-                    "reconstructor reset with {}/{} est. packets",
-                    self.packets_recvd,
-                    (self.total_len - 1) / ESP_NOW_CHUNK_SIZE as u16 + 1
-                );
-            }
-            *self = EspNowMessageReconstructor::new(&packet);
-        } else if self.finished {
-            // We are done but this is part of a message we've already completed
-            return None;
-        }
-        self.packets_recvd += 1;
-        self.decoder
-            .decode(EncodingPacket::deserialize(&packet.contents))
-            .inspect(|_| self.finished = true)
-    }
+/// A neighbor registered as an ESP-NOW peer.
+struct UnicastPeer {
+    mac: [u8; 6],
+    last_used: Instant,
+    /// How many messages we have sent it since we last heard from it.
+    unanswered: u8,
 }
 
 /// `EspNowNetworkEngine` manages turning a message into a series of packets and back again.
 pub(crate) struct EspNowNetworkEngine<'a> {
+    manager: EspNowManager<'a>,
     sender: Mutex<EspNowSender<'a>>,
     receiver: Mutex<EspNowReceiver<'a>>,
-    my_address: u16,
-    send_channel: Channel<EspNowPacket>,
-    receive_channel: Channel<EspNowPacket>,
+    /// Neighbors registered as ESP-NOW peers, by address.
+    peers: Mutex<BTreeMap<u16, UnicastPeer>>,
+    /// How many unicast frames in a row to each peer the radio gave up on.
+    unacked: Mutex<BTreeMap<u16, u8>>,
+    /// The local master key unicast frames are encrypted with, if the team has a secret.
+    lmk: Option<[u8; 16]>,
+    send_channel: Channel<Packet>,
+    receive_channel: Channel<ReceivedPacket>,
+    /// Repair requests from other devices for messages we sent.
+    repair_channel: Channel<RepairRequest>,
+    /// Addresses and the MAC addresses that authenticated messages from them came from.
+    learn_channel: Channel<(u16, [u8; 6])>,
+    /// The encoders of the last few messages we sent, to answer repair requests.
+    encoders: Mutex<EncoderCache<ESP_NOW_CHUNK_SIZE>>,
     last_rx: AtomicU32,
     tx_led: Option<Mutex<Output<'a>>>,
     rx_led: Option<Mutex<Output<'a>>>,
 }
 
 impl<'o> EspNowNetworkEngine<'o> {
-    /// Create a new `EspNowNetworkInterface`.
+    /// Create a new `EspNowNetworkInterface`, with the radio set up as `radio_settings` say. If
+    /// `team_secret` is given, unicast frames are encrypted with keys derived from it.
     fn new(
+        manager: EspNowManager<'o>,
         sender: Mutex<EspNowSender<'o>>,
         receiver: Mutex<EspNowReceiver<'o>>,
-        my_address: u16,
+        radio_settings: &RadioSettings,
+        team_secret: Option<&[u8; 32]>,
         tx_led: Option<Output<'o>>,
         rx_led: Option<Output<'o>>,
     ) -> EspNowNetworkEngine<'o> {
+        radio::apply(&manager, radio_settings);
+        let lmk = team_secret.and_then(|secret| {
+            let (pmk, lmk) = derive_keys(secret);
+            match manager.set_pmk(&pmk) {
+                Ok(()) => Some(lmk),
+                Err(e) => {
+                    log::error!("EspNow: could not set PMK; not encrypting: {e:?}");
+                    None
+                }
+            }
+        });
         EspNowNetworkEngine {
+            manager,
             sender,
             receiver,
-            my_address,
+            peers: Mutex::new(BTreeMap::new()),
+            unacked: Mutex::new(BTreeMap::new()),
+            lmk,
             send_channel: Channel::new(),
             receive_channel: Channel::new(),
+            repair_channel: Channel::new(),
+            learn_channel: Channel::new(),
+            encoders: Mutex::new(EncoderCache::new()),
             last_rx: AtomicU32::new(0),
             tx_led: tx_led.map(Mutex::new),
             rx_led: rx_led.map(Mutex::new),
         }
     }
 
-    fn random_delay(crc: u16) -> u32 {
-        let crc_extended: u32 = crc.into();
-        RANDOM_MIN + crc_extended % RANDOM_SPREAD
-    }
-
-    /// Send a message to a recipient
-    async fn send_packet(&self, packet: EspNowPacket) -> Result<u16, EspNowError> {
+    /// Send a packet, returning how long to wait before sending the next one.
+    async fn send_packet(&self, packet: Packet) -> Result<u32, EspNowError> {
         if let Some(tx_led) = &self.tx_led {
             tx_led.lock().await.set_high();
         }
 
-        let mut output_buf: [MaybeUninit<u8>; ESP_NOW_PACKET_SIZE] =
-            [MaybeUninit::uninit(); ESP_NOW_PACKET_SIZE];
-        let mut bb = BorrowedBuf::from(&mut output_buf[..]);
-        {
-            let mut bc = bb.unfilled();
-            // SAFETY: This shouldn't overflow as we should be writing at most `ESP_NOW_PACKET_SIZE`
-            // bytes.
-            bc.append(&ESP_NOW_MAGIC);
-            bc.append(&u16::to_be_bytes(packet.recipient));
-            bc.append(&u16::to_be_bytes(self.my_address));
-            bc.append(&u8::to_be_bytes(packet.message_seq));
-            bc.append(&u16::to_be_bytes(packet.chunk_len));
-            bc.append(&u16::to_be_bytes(packet.total_len));
-            bc.append(&packet.contents);
+        let mut frame = [0u8; ESP_NOW_FRAME_SIZE];
+        let len = packet.encode(&mut frame)?;
+        let frame = &frame[..len];
+
+        // Unicast sends only complete once the peer acknowledges the frame, so this fails if the
+        // radio gave up retrying.
+        let dst = self
+            .peer_mac(packet.header.recipient)
+            .await
+            .unwrap_or(BROADCAST_ADDRESS);
+        let result = self.sender.lock().await.send_async(&dst, frame).await;
+        if dst != BROADCAST_ADDRESS {
+            let mut unacked = self.unacked.lock().await;
+            match result {
+                Ok(()) => {
+                    unacked.remove(&packet.header.recipient);
+                }
+                Err(_) => {
+                    let count = unacked.entry(packet.header.recipient).or_default();
+                    *count = count.saturating_add(1);
+                }
+            }
         }
-        let crc = CRC.checksum(&bb.filled()[3..]); // do not CRC magic bytes
-        {
-            let mut bc = bb.unfilled();
-            bc.append(&u16::to_be_bytes(crc));
+        result.map_err(|_| EspNowError::EspNow)?;
+
+        Ok(fountain_framing::send_delay_ms(frame))
+    }
+
+    /// The MAC address of the peer at `address`, if it is registered and answering us.
+    async fn peer_mac(&self, address: u16) -> Option<[u8; 6]> {
+        if address == EspNowNetworkInterface::BROADCAST {
+            return None;
+        }
+        let mut peers = self.peers.lock().await;
+        let peer = peers.get_mut(&address)?;
+        if peer.unanswered >= MAX_UNANSWERED {
+            return None;
         }
+        peer.last_used = Instant::now();
+        Some(peer.mac)
+    }
 
-        self.sender
-            .lock()
-            .await
-            .send_async(&BROADCAST_ADDRESS, bb.filled())
-            .await
-            .map_err(|_| EspNowError::EspNow)?;
+    /// Note that `address` sent us an authenticated message from `mac`, registering it as a peer
+    /// if it isn't already. The least recently used peer is dropped to make room.
+    async fn learn_peer(&self, address: u16, mac: [u8; 6]) {
+        if address == EspNowNetworkInterface::BROADCAST || mac == BROADCAST_ADDRESS {
+            return;
+        }
+        let mut peers = self.peers.lock().await;
+        if let Some(peer) = peers.get_mut(&address) {
+            if peer.mac == mac {
+                peer.last_used = Instant::now();
+                peer.unanswered = 0;
+                return;
+            }
+        }
+
+        // Forget whatever was at this address or MAC before, since one of them has moved
+        peers.retain(|a, p| {
+            let stale = *a == address || p.mac == mac;
+            if stale && p.mac != mac {
+                self.manager.remove_peer(&p.mac).ok();
+            }
+            !stale
+        });
+        if peers.len() >= MAX_UNICAST_PEERS {
+            let lru = peers
+                .iter()
+                .min_by_key(|(_, p)| p.last_used)
+                .map(|(a, _)| *a);
+            if let Some(peer) = lru.and_then(|a| peers.remove(&a)) {
+                self.manager.remove_peer(&peer.mac).ok();
+            }
+        }
 
-        Ok(crc)
+        let info = PeerInfo {
+            peer_address: mac,
+            lmk: self.lmk,
+            channel: None,
+            encrypt: self.lmk.is_some(),
+        };
+        let result = if self.manager.peer_exists(&mac) {
+            self.manager.modify_peer(info)
+        } else {
+            self.manager.add_peer(info)
+        };
+        match result {
+            Ok(()) => {
+                log::debug!("EspNow: registered peer {address} at {mac:02x?}");
+                peers.insert(
+                    address,
+                    UnicastPeer {
+                        mac,
+                        last_used: Instant::now(),
+                        unanswered: 0,
+                    },
+                );
+            }
+            Err(e) => log::error!("EspNow: could not register peer {address}: {e:?}"),
+        }
     }
 
     fn update_last_rx(&self) {
@@ -230,67 +299,50 @@ impl<'o> EspNowNetworkEngine<'o> {
     }
 
     /// Read data from the transceiver until we find a packet.
-    async fn recv_packet(&self) -> Result<EspNowPacket, EspNowError> {
+    async fn recv_packet(&self) -> Result<ReceivedPacket, EspNowError> {
         loop {
             let received = self.receiver.lock().await.receive_async().await;
             if let Some(rx_led) = &self.rx_led {
                 rx_led.lock().await.set_high();
             }
-            let receive_data = received.data();
             log::debug!("EspNow: reseave info {:?}", received.info);
-
-            if receive_data[0..3] != ESP_NOW_MAGIC {
-                log::debug!("EspNow: magic did not match {:?}", &receive_data[0..3]);
-                continue;
-            }
-
-            let input_buf = &receive_data[3..];
-            let mut crc = CRC.digest();
             self.update_last_rx();
-            crc.update(&input_buf[0..ESP_NOW_HEADER_SIZE]);
-            let (sender, chunk_seq, chunk_len, total_len) = {
-                let mut sc = SliceCursor::new(&input_buf[0..ESP_NOW_HEADER_SIZE]);
-                let recipient = sc.next_u16_be();
-                if recipient != self.my_address && recipient != EspNowNetworkInterface::BROADCAST {
-                    log::debug!(
-                        "recv_packet: packet not for me (address: {}); for {} ",
-                        self.my_address,
-                        recipient
-                    );
+
+            let packet = match Packet::decode::<ESP_NOW_CHUNK_SIZE>(received.data()) {
+                Ok(packet) => packet,
+                Err(FrameError::BadMagic) => {
+                    log::debug!("EspNow: magic did not match");
                     continue;
                 }
-                let sender = sc.next_u16_be();
-                let chunk_seq = sc.next_u8();
-                let chunk_len = sc.next_u16_be() as usize;
-                if chunk_len > ESP_NOW_CHUNK_SIZE + RAPTORQ_OVERHEAD {
-                    log::info!("recv_packet: malformed chunk of size {chunk_len}");
+                Err(FrameError::BadCrc) => {
+                    log::error!("bad checksum");
+                    continue;
+                }
+                Err(e) => {
+                    log::info!("recv_packet: {e}");
                     continue;
                 }
-                let total_len = sc.next_u16_be();
-                // log::info!("recv_packet: chunk len {chunk_len} seq {chunk_seq}");
-
-                assert_eq!(sc.remaining(), 0);
-
-                (sender, chunk_seq, chunk_len, total_len)
             };
-
-            let input_buf = &input_buf[ESP_NOW_HEADER_SIZE..];
-            self.update_last_rx();
-            let checksum =
-                u16::from_be_bytes(input_buf[chunk_len..chunk_len + 2].try_into().unwrap());
-            crc.update(&input_buf[..chunk_len]);
-            if crc.finalize() != checksum {
-                log::error!("bad checksum");
+            let recipient = packet.header.recipient;
+            let my_address = address::get();
+            if recipient != my_address && recipient != EspNowNetworkInterface::BROADCAST {
+                log::debug!(
+                    "recv_packet: packet not for me (address: {}); for {} ",
+                    my_address,
+                    recipient
+                );
                 continue;
             }
-            let contents = input_buf[..chunk_len].try_into().expect("packet too large");
-            return Ok(EspNowPacket {
-                recipient: self.my_address,
-                sender,
-                message_seq: chunk_seq,
-                chunk_len: chunk_len as u16,
-                total_len,
-                contents,
+            if let Some(request) = RepairRequest::from_packet(&packet) {
+                if self.repair_channel.try_send(request).is_err() {
+                    log::debug!("recv_packet: too busy to repair for {}", request.requester);
+                }
+                continue;
+            }
+            return Ok(ReceivedPacket {
+                packet,
+                rssi: received.info.rx_control.rssi as i8,
+                mac: received.info.src_address,
             });
         }
     }
@@ -299,36 +351,92 @@ impl<'o> EspNowNetworkEngine<'o> {
         EspNowNetworkInterface {
             send_tx: self.send_channel.sender(),
             receive_rx: self.receive_channel.receiver(),
-            my_address: self.my_address,
+            learn_tx: self.learn_channel.sender(),
+            last_message: None,
+            peers: &self.peers,
+            encoders: &self.encoders,
+            unacked: &self.unacked,
             message_seq: 0,
-            reconstructors: BTreeMap::new(),
+            decoder: Decoder::with_max_len(MAX_MESSAGE_LEN),
+            reported_loss: LossReports::new(),
+            next_repair_poll: Instant::now() + REPAIR_POLL_INTERVAL,
+        }
+    }
+
+    /// Send a packet and wait until the next one may be sent.
+    async fn send_and_wait(&self, packet: Packet) {
+        match self.send_packet(packet).await {
+            Ok(delay) => {
+                if let Some(tx_led) = &self.tx_led {
+                    tx_led.lock().await.set_low();
+                }
+                Timer::after_millis(delay as u64).await;
+            }
+            Err(e) => {
+                if let Some(tx_led) = &self.tx_led {
+                    tx_led.lock().await.set_low();
+                }
+                log::error!("EspNow: send error: {e}");
+                Timer::after_millis(SEND_RETRY_DELAY_MS).await;
+            }
         }
     }
 
     async fn run_sender(&self) -> ! {
         loop {
             log::debug!("EspNow: Waiting for Packet");
-            let packet = self.send_channel.receive().await;
-            log::debug!("EspNow: Got Packet");
-
-            match self.send_packet(packet).await {
-                Ok(crc) => {
-                    if let Some(tx_led) = &self.tx_led {
-                        tx_led.lock().await.set_low();
-                    }
-                    Timer::after_millis(Self::random_delay(crc) as u64).await;
+            match select(self.send_channel.receive(), self.repair_channel.receive()).await {
+                Either::First(packet) => {
+                    log::debug!("EspNow: Got Packet");
+                    self.send_and_wait(packet).await;
                 }
-                Err(e) => {
-                    if let Some(tx_led) = &self.tx_led {
-                        tx_led.lock().await.set_low();
+                Either::Second(request) => {
+                    let packets = self.encoders.lock().await.answer(&request);
+                    log::debug!(
+                        "EspNow: sending {} repair packets to {}",
+                        packets.len(),
+                        request.requester
+                    );
+                    for packet in packets {
+                        self.send_and_wait(packet).await;
                     }
-                    log::error!("EspNow: send error: {e}");
-                    Timer::after_millis(SEND_RETRY_DELAY_MS).await;
                 }
             }
         }
     }
 
+    /// Register the peers that authenticated messages came from, and answer their repair
+    /// requests.
+    async fn run_learner(&self) -> ! {
+        loop {
+            let (address, mac) = self.learn_channel.receive().await;
+            self.encoders.lock().await.add_peer(address);
+            self.learn_peer(address, mac).await;
+        }
+    }
+
+    /// Carry out requests to change the radio settings.
+    async fn run_radio(&self) -> ! {
+        loop {
+            let request = radio::REQUESTS.wait().await;
+            // Hold the sender so nothing goes out while the radio changes
+            let _sender = self.sender.lock().await;
+            let settings = request.settings;
+            let changed = settings != radio::get();
+            let survey = if request.survey {
+                Some(radio::survey(&self.manager).await)
+            } else {
+                None
+            };
+            // Also puts the radio back on its channel after a survey
+            radio::apply(&self.manager, &settings);
+            if changed {
+                radio::RADIO_CHANGED.signal(settings);
+            }
+            radio::REPORTS.signal(RadioReport { settings, survey });
+        }
+    }
+
     async fn run_receiver(&self) -> ! {
         loop {
             match self.recv_packet().await {
@@ -346,7 +454,13 @@ impl<'o> EspNowNetworkEngine<'o> {
 
 #[embassy_executor::task]
 async fn run_esp_now_engine(engine: &'static EspNowNetworkEngine<'static>) -> ! {
-    embassy_futures::join::join(engine.run_receiver(), engine.run_sender()).await;
+    embassy_futures::join::join4(
+        engine.run_receiver(),
+        engine.run_sender(),
+        engine.run_learner(),
+        engine.run_radio(),
+    )
+    .await;
     // This tells the compiler to not worry about the return type
     unreachable!();
 }
@@ -361,57 +475,117 @@ impl NetworkEngine for EspNowNetworkEngine<'_> {
 }
 
 pub struct EspNowNetworkInterface<'a> {
-    send_tx: Sender<'a, EspNowPacket>,
-    receive_rx: Receiver<'a, EspNowPacket>,
-    my_address: u16,
-    message_seq: u8,
-    reconstructors: BTreeMap<u16, EspNowMessageReconstructor>,
+    send_tx: Sender<'a, Packet>,
+    receive_rx: Receiver<'a, ReceivedPacket>,
+    learn_tx: Sender<'a, (u16, [u8; 6])>,
+    /// The sender of the last message we returned, and the MAC address it came from
+    last_message: Option<(u16, [u8; 6])>,
+    peers: &'a Mutex<BTreeMap<u16, UnicastPeer>>,
+    encoders: &'a Mutex<EncoderCache<ESP_NOW_CHUNK_SIZE>>,
+    unacked: &'a Mutex<BTreeMap<u16, u8>>,
+    message_seq: u16,
+    decoder: Decoder<ESP_NOW_CHUNK_SIZE>,
+    /// The loss each peer reports for our packets.
+    reported_loss: LossReports,
+    /// When to next ask for repairs. Kept here rather than in [`recv`](Self::recv), which may be
+    /// cancelled and restarted at any time.
+    next_repair_poll: Instant,
 }
 
 impl EspNowNetworkInterface<'_> {
-    /// Send a message to a recipient
+    /// Send a message to a recipient. This fails if the radio gave up on the last few frames to
+    /// the recipient, and the message is not sent. The count starts over afterward, so the
+    /// message after it is sent.
     async fn send(&mut self, msg: Message<u16>) -> Result<(), EspNowError> {
-        let total_len = msg.contents.len();
+        if msg.contents.len() > MAX_MESSAGE_LEN {
+            return Err(FrameError::MessageTooLong(msg.contents.len()).into());
+        }
+        {
+            let mut unacked = self.unacked.lock().await;
+            if unacked
+                .get(&msg.recipient)
+                .is_some_and(|n| *n >= MAX_UNACKED_FRAMES)
+            {
+                unacked.remove(&msg.recipient);
+                return Err(EspNowError::Unreachable(msg.recipient));
+            }
+        }
+        if let Some(peer) = self.peers.lock().await.get_mut(&msg.recipient) {
+            peer.unanswered = peer.unanswered.saturating_add(1);
+        }
         let (message_seq, _) = self.message_seq.overflowing_add(1);
         self.message_seq = message_seq;
-        let encoder = raptorq::Encoder::with_defaults(&msg.contents, ESP_NOW_CHUNK_SIZE as u16);
-        let repair_packets = (total_len / ESP_NOW_CHUNK_SIZE) * 12 / 10; // 20% extra packets
-        for packet in encoder.get_encoded_packets(repair_packets as u32) {
-            let enc_packet = packet.serialize();
-            let packet = EspNowPacket {
-                recipient: msg.recipient,
-                sender: self.my_address,
-                message_seq,
-                chunk_len: enc_packet.len() as u16,
-                total_len: total_len as u16,
-                contents: enc_packet.into_iter().collect(),
-            };
+        let mut encoder = Encoder::<ESP_NOW_CHUNK_SIZE>::with_fec(
+            msg.recipient,
+            address::get(),
+            message_seq,
+            &msg.contents,
+            &ESP_NOW_FEC,
+            self.planned_loss(msg.recipient),
+        )?;
+        for packet in encoder.by_ref() {
             log::debug!("EspNow: Sending Packet");
             self.send_tx.send(packet).await;
             log::debug!("EspNow: Sent Packet");
         }
+        self.encoders.lock().await.insert(encoder);
         Ok(())
     }
 
+    /// The loss to plan for when sending to `recipient`. Broadcasts plan for the worst
+    /// neighbor.
+    fn planned_loss(&self, recipient: u16) -> Option<u8> {
+        if recipient == EspNowNetworkInterface::BROADCAST {
+            self.reported_loss.worst(Instant::now().as_millis())
+        } else {
+            self.reported_loss
+                .get(recipient, Instant::now().as_millis())
+        }
+    }
+
     /// Read packets until we assemble a message, then return it
     async fn recv(&mut self) -> Result<Message<u16>, EspNowError> {
         loop {
             log::debug!("EspNow: Waiting for Packet");
-            let packet = self.receive_rx.receive().await;
+            let ReceivedPacket { packet, rssi, mac } =
+                match select(self.receive_rx.receive(), Timer::at(self.next_repair_poll)).await {
+                    Either::First(received) => received,
+                    Either::Second(()) => {
+                        self.request_repairs();
+                        continue;
+                    }
+                };
             log::debug!("EspNow: Received Packet");
 
-            let sender = packet.sender;
-            let recipient = packet.recipient;
-            let reconstructor = self
-                .reconstructors
-                .entry(sender)
-                .or_insert(EspNowMessageReconstructor::new(&packet));
-            if let Some(p) = reconstructor.add_packet(packet) {
-                return Ok(Message {
-                    recipient,
-                    sender,
-                    contents: p.into(),
-                });
+            self.decoder.set_max_len(memory::max_message_len());
+            match self.decoder.add_packet(&packet) {
+                Ok(Some(p)) => {
+                    self.last_message = Some((packet.header.sender, mac));
+                    return Ok(Message {
+                        recipient: packet.header.recipient,
+                        sender: packet.header.sender,
+                        contents: p.into(),
+                        rssi: Some(rssi),
+                    });
+                }
+                Ok(None) => (),
+                Err(e) => log::info!("EspNow: dropped packet from {}: {e}", packet.header.sender),
+            }
+        }
+    }
+
+    /// Ask for repairs to messages that have stopped arriving. Requests are dropped if the send
+    /// queue is full; they'll be asked again next time.
+    fn request_repairs(&mut self) {
+        self.next_repair_poll = Instant::now() + REPAIR_POLL_INTERVAL;
+        for request in self.decoder.repair_requests(address::get()) {
+            log::debug!(
+                "EspNow: asking {} for {} repair packets",
+                request.sender,
+                request.count
+            );
+            if self.send_tx.try_send(request.to_packet()).is_err() {
+                log::debug!("EspNow: send queue full; dropped repair request");
             }
         }
     }
@@ -420,13 +594,12 @@ impl EspNowNetworkInterface<'_> {
 impl NetworkInterface for EspNowNetworkInterface<'_> {
     type Addr = u16;
     const BROADCAST: Self::Addr = 0;
+    const NAME: &'static str = "esp-now";
 
     async fn send_message(&mut self, msg: Message<u16>) -> Result<(), NetworkError> {
-        match self.send(msg).await {
-            Ok(_) => (),
-            Err(e) => log::error!("esp now send: {e}"),
-        }
-        Ok(())
+        self.send(msg)
+            .await
+            .map_err(|e| NetworkError::Send(alloc::format!("esp now send: {e}")))
     }
 
     async fn recv_message(&mut self) -> Result<Message<u16>, NetworkError> {
@@ -438,20 +611,70 @@ impl NetworkInterface for EspNowNetworkInterface<'_> {
     }
 
     fn my_address(&self) -> Self::Addr {
-        self.my_address
+        address::get()
+    }
+
+    fn reassign_address(&mut self) -> Self::Addr {
+        address::reassign()
+    }
+
+    fn rx_loss(&self, peer: &Self::Addr) -> Option<u8> {
+        self.decoder.loss(*peer)
+    }
+
+    fn set_tx_loss(&mut self, peer: Self::Addr, loss: u8) {
+        self.reported_loss
+            .insert(peer, loss, Instant::now().as_millis());
+    }
+
+    fn mtu(&self, _peer: &Self::Addr) -> usize {
+        ESP_NOW_MTU
+    }
+
+    fn authenticated(&mut self, peer: Self::Addr) {
+        if let Some((sender, mac)) = self.last_message.take() {
+            if sender == peer && self.learn_tx.try_send((sender, mac)).is_err() {
+                log::debug!("EspNow: too busy to learn where {sender} is");
+            }
+        }
     }
 }
 
-/// Starts the Esp Now networking engine and returns and interface to it.
+/// Derive the ESP-NOW primary master key and local master key from the team secret.
+fn derive_keys(team_secret: &[u8; 32]) -> ([u8; 16], [u8; 16]) {
+    let mut okm = [0u8; 32];
+    Hkdf::<Sha256>::new(None, team_secret)
+        .expand(KDF_INFO, &mut okm)
+        .expect("32 bytes is a valid HKDF output length");
+    let (pmk, lmk) = okm.split_at(16);
+    (
+        pmk.try_into().expect("16 bytes"),
+        lmk.try_into().expect("16 bytes"),
+    )
+}
+
+/// Starts the Esp Now networking engine and returns and interface to it. The radio is set up as
+/// `radio_settings` say. If `team_secret` is given, unicast frames are encrypted with keys
+/// derived from it.
 pub(crate) async fn start(
+    manager: EspNowManager<'static>,
     sender: Mutex<EspNowSender<'static>>,
     receiver: Mutex<EspNowReceiver<'static>>,
-    my_address: u16,
+    radio_settings: &RadioSettings,
+    team_secret: Option<&[u8; 32]>,
     tx_led: Option<Output<'static>>,
     rx_led: Option<Output<'static>>,
 ) -> &'static EspNowNetworkEngine<'static> {
     mk_static!(
         EspNowNetworkEngine,
-        EspNowNetworkEngine::new(sender, receiver, my_address, tx_led, rx_led)
+        EspNowNetworkEngine::new(
+            manager,
+            sender,
+            receiver,
+            radio_settings,
+            team_secret,
+            tx_led,
+            rx_led
+        )
     )
 }
@@ -0,0 +1,44 @@
+//! Runtime network impairments, for demonstrating partitions and lossy links without blocking
+//! the radio by hand.
+//!
+//! The [`Impairment`] applies to every message going through the
+//! [`MultiInterface`](super::multi::MultiInterface), so it covers every link the same way.
+//! Messages are whole sync messages rather than radio packets, so a dropped message is as if
+//! every packet of it were lost. Impairments live only in memory and are cleared by a reboot.
+//!
+//! They are set with the `impair` serial command, whose data is a list of settings in the form
+//! described in the [`impairment`] crate, and which replies with the current settings in the
+//! same form.
+//!
+//! Holding the button for a few seconds toggles radio silence.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex as BlockingMutex};
+use embassy_time::Duration;
+pub use impairment::Impairment;
+
+static IMPAIRMENT: BlockingMutex<CriticalSectionRawMutex, RefCell<Impairment>> =
+    BlockingMutex::new(RefCell::new(Impairment::new()));
+
+/// The current impairment.
+pub fn get() -> Impairment {
+    IMPAIRMENT.lock(|i| i.borrow().clone())
+}
+
+/// Change the current impairment.
+pub fn update(f: impl FnOnce(&mut Impairment)) {
+    IMPAIRMENT.lock(|i| f(&mut i.borrow_mut()))
+}
+
+/// Whether a message to or from `peer` should get through.
+pub fn passes(peer: u16) -> bool {
+    let mut roll = [0u8];
+    getrandom::getrandom(&mut roll).ok();
+    IMPAIRMENT.lock(|i| i.borrow().passes(peer, roll[0]))
+}
+
+/// How long to hold each message before sending it.
+pub fn latency() -> Duration {
+    IMPAIRMENT.lock(|i| Duration::from_millis(i.borrow().latency_ms))
+}
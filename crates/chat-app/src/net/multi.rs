@@ -0,0 +1,469 @@
+//! A [`NetworkInterface`] that combines every link the device has, so that a single
+//! [`SyncEngine`](crate::aranya::syncer::SyncEngine) can sync over all of them.
+//!
+//! ## Theory of Operation
+//!
+//! Devices have the same address on every link, so a peer is the same peer whichever link we
+//! hear it on. For each peer, [`MultiInterface`] remembers when it last heard from it on each
+//! link. Only messages the syncer [authenticated](NetworkInterface::authenticated) count, so a
+//! forged sender address can't pull a peer's traffic onto another link or revive a link that
+//! has failed. A link is live for a peer if we've heard from the peer on it within
+//! [`ROUTE_TIMEOUT`], sending on it hasn't failed repeatedly since, and the peer hasn't gone
+//! quiet on it: we haven't gone [`FAILOVER_TIMEOUT`] since we sent to it there without hearing
+//! anything back.
+//!
+//! Links are ranked in the order they were added. Messages to a peer go over its
+//! highest-ranked live link. If sending fails we fall back to the next live link, and if the
+//! peer has no live links at all, the message is sent on every link we haven't already tried.
+//! Broadcasts always go out on every link. Received messages from all links are merged into one
+//! stream.
+//!
+//! Any [impairment](super::impair) is applied here, so it affects every link alike.
+//!
+//! The firmware's links are different types, so they are gathered into the [`Link`] enum, which
+//! is itself a [`NetworkInterface`]. A [`MultiInterface`] can be built from any
+//! [`NetworkInterface`] with `u16` addresses, though, including the link crates' own interfaces.
+
+use alloc::collections::btree_map::BTreeMap;
+use core::{
+    future::{poll_fn, Future},
+    pin::Pin,
+    task::Poll,
+};
+
+use embassy_time::{Duration, Instant, Timer};
+
+#[cfg(feature = "net-esp-now")]
+use super::espnow::EspNowNetworkInterface;
+#[cfg(feature = "net-irda")]
+use super::irda::IrNetworkInterface;
+#[cfg(feature = "net-uart")]
+use super::uart::UartNetworkInterface;
+#[cfg(feature = "net-usb")]
+use super::usb::UsbNetworkInterface;
+#[cfg(feature = "net-wifi")]
+use super::wifi::WifiNetworkInterface;
+use super::{impair, Message, NetworkError, NetworkInterface};
+use crate::aranya::neighbors::MAX_NEIGHBORS;
+
+/// The most links a device can have.
+pub const MAX_LINKS: usize = 4;
+/// How long a link stays live for a peer after we last heard from the peer on it. Settled
+/// neighbors may only hello every 16 seconds, so this spans several hellos.
+const ROUTE_TIMEOUT: Duration = Duration::from_secs(60);
+/// A link is not used for a peer after this many sends to it fail in a row.
+const MAX_LINK_FAILURES: u8 = 3;
+/// A link is not used for a peer if it has been this long since we sent to the peer on it
+/// without hearing back. A little shorter than the syncer's stall timeout, so that the request
+/// that resumes a stalled sync already goes over another link.
+const FAILOVER_TIMEOUT: Duration = Duration::from_secs(6);
+
+/// One of the device's network links.
+pub enum Link<'a> {
+    #[cfg(feature = "net-esp-now")]
+    EspNow(EspNowNetworkInterface<'a>),
+    #[cfg(feature = "net-irda")]
+    Ir(IrNetworkInterface<'a>),
+    #[cfg(feature = "net-uart")]
+    Uart(UartNetworkInterface<'a>),
+    #[cfg(feature = "net-usb")]
+    Usb(UsbNetworkInterface<'a>),
+    #[cfg(feature = "net-wifi")]
+    Wifi(WifiNetworkInterface<'a>),
+}
+
+/// Each link's own name and properties show through, except
+/// [`BROADCASTS`](NetworkInterface::BROADCASTS), which can't differ between variants.
+impl NetworkInterface for Link<'_> {
+    type Addr = u16;
+    const BROADCAST: Self::Addr = 0;
+    const NAME: &'static str = "link";
+
+    fn link_name(&self, _peer: &Self::Addr) -> &'static str {
+        match self {
+            #[cfg(feature = "net-esp-now")]
+            Link::EspNow(_) => EspNowNetworkInterface::NAME,
+            #[cfg(feature = "net-irda")]
+            Link::Ir(_) => IrNetworkInterface::NAME,
+            #[cfg(feature = "net-uart")]
+            Link::Uart(_) => UartNetworkInterface::NAME,
+            #[cfg(feature = "net-usb")]
+            Link::Usb(_) => UsbNetworkInterface::NAME,
+            #[cfg(feature = "net-wifi")]
+            Link::Wifi(_) => WifiNetworkInterface::NAME,
+        }
+    }
+
+    fn my_address(&self) -> Self::Addr {
+        match self {
+            #[cfg(feature = "net-esp-now")]
+            Link::EspNow(l) => l.my_address(),
+            #[cfg(feature = "net-irda")]
+            Link::Ir(l) => l.my_address(),
+            #[cfg(feature = "net-uart")]
+            Link::Uart(l) => l.my_address(),
+            #[cfg(feature = "net-usb")]
+            Link::Usb(l) => l.my_address(),
+            #[cfg(feature = "net-wifi")]
+            Link::Wifi(l) => l.my_address(),
+        }
+    }
+
+    fn reassign_address(&mut self) -> Self::Addr {
+        match self {
+            #[cfg(feature = "net-esp-now")]
+            Link::EspNow(l) => l.reassign_address(),
+            #[cfg(feature = "net-irda")]
+            Link::Ir(l) => l.reassign_address(),
+            #[cfg(feature = "net-uart")]
+            Link::Uart(l) => l.reassign_address(),
+            #[cfg(feature = "net-usb")]
+            Link::Usb(l) => l.reassign_address(),
+            #[cfg(feature = "net-wifi")]
+            Link::Wifi(l) => l.reassign_address(),
+        }
+    }
+
+    fn rx_loss(&self, peer: &Self::Addr) -> Option<u8> {
+        match self {
+            #[cfg(feature = "net-esp-now")]
+            Link::EspNow(l) => l.rx_loss(peer),
+            #[cfg(feature = "net-irda")]
+            Link::Ir(l) => l.rx_loss(peer),
+            #[cfg(feature = "net-uart")]
+            Link::Uart(l) => l.rx_loss(peer),
+            #[cfg(feature = "net-usb")]
+            Link::Usb(l) => l.rx_loss(peer),
+            #[cfg(feature = "net-wifi")]
+            Link::Wifi(l) => l.rx_loss(peer),
+        }
+    }
+
+    fn set_tx_loss(&mut self, peer: Self::Addr, loss: u8) {
+        match self {
+            #[cfg(feature = "net-esp-now")]
+            Link::EspNow(l) => l.set_tx_loss(peer, loss),
+            #[cfg(feature = "net-irda")]
+            Link::Ir(l) => l.set_tx_loss(peer, loss),
+            #[cfg(feature = "net-uart")]
+            Link::Uart(l) => l.set_tx_loss(peer, loss),
+            #[cfg(feature = "net-usb")]
+            Link::Usb(l) => l.set_tx_loss(peer, loss),
+            #[cfg(feature = "net-wifi")]
+            Link::Wifi(l) => l.set_tx_loss(peer, loss),
+        }
+    }
+
+    fn authenticated(&mut self, peer: Self::Addr) {
+        match self {
+            #[cfg(feature = "net-esp-now")]
+            Link::EspNow(l) => l.authenticated(peer),
+            #[cfg(feature = "net-irda")]
+            Link::Ir(l) => l.authenticated(peer),
+            #[cfg(feature = "net-uart")]
+            Link::Uart(l) => l.authenticated(peer),
+            #[cfg(feature = "net-usb")]
+            Link::Usb(l) => l.authenticated(peer),
+            #[cfg(feature = "net-wifi")]
+            Link::Wifi(l) => l.authenticated(peer),
+        }
+    }
+
+    fn mtu(&self, peer: &Self::Addr) -> usize {
+        match self {
+            #[cfg(feature = "net-esp-now")]
+            Link::EspNow(l) => l.mtu(peer),
+            #[cfg(feature = "net-irda")]
+            Link::Ir(l) => l.mtu(peer),
+            #[cfg(feature = "net-uart")]
+            Link::Uart(l) => l.mtu(peer),
+            #[cfg(feature = "net-usb")]
+            Link::Usb(l) => l.mtu(peer),
+            #[cfg(feature = "net-wifi")]
+            Link::Wifi(l) => l.mtu(peer),
+        }
+    }
+
+    async fn send_message(&mut self, msg: Message<u16>) -> Result<(), NetworkError> {
+        match self {
+            #[cfg(feature = "net-esp-now")]
+            Link::EspNow(l) => l.send_message(msg).await,
+            #[cfg(feature = "net-irda")]
+            Link::Ir(l) => l.send_message(msg).await,
+            #[cfg(feature = "net-uart")]
+            Link::Uart(l) => l.send_message(msg).await,
+            #[cfg(feature = "net-usb")]
+            Link::Usb(l) => l.send_message(msg).await,
+            #[cfg(feature = "net-wifi")]
+            Link::Wifi(l) => l.send_message(msg).await,
+        }
+    }
+
+    async fn recv_message(&mut self) -> Result<Message<u16>, NetworkError> {
+        match self {
+            #[cfg(feature = "net-esp-now")]
+            Link::EspNow(l) => l.recv_message().await,
+            #[cfg(feature = "net-irda")]
+            Link::Ir(l) => l.recv_message().await,
+            #[cfg(feature = "net-uart")]
+            Link::Uart(l) => l.recv_message().await,
+            #[cfg(feature = "net-usb")]
+            Link::Usb(l) => l.recv_message().await,
+            #[cfg(feature = "net-wifi")]
+            Link::Wifi(l) => l.recv_message().await,
+        }
+    }
+}
+
+/// What we know about reaching one peer over each link.
+#[derive(Default)]
+struct Route {
+    last_heard: [Option<Instant>; MAX_LINKS],
+    failures: [u8; MAX_LINKS],
+    /// When we first sent to the peer on each link without hearing back since
+    unanswered: [Option<Instant>; MAX_LINKS],
+}
+
+impl Route {
+    fn is_live(&self, link: usize, now: Instant) -> bool {
+        self.last_heard[link].is_some_and(|t| now - t < ROUTE_TIMEOUT)
+            && self.failures[link] < MAX_LINK_FAILURES
+            && !self.unanswered[link].is_some_and(|t| now - t >= FAILOVER_TIMEOUT)
+    }
+
+    /// The last time we heard from this peer on any link.
+    fn newest(&self) -> Option<Instant> {
+        self.last_heard.iter().flatten().max().copied()
+    }
+}
+
+/// All of the device's links, behind one [`NetworkInterface`]. `L` is usually a [`Link`].
+pub struct MultiInterface<L> {
+    /// Links in order of preference
+    links: heapless::Vec<L, MAX_LINKS>,
+    routes: BTreeMap<u16, Route>,
+    /// The link to poll first, so a busy link can't starve the others
+    next_poll: usize,
+    /// The sender of the last message we returned, and the link it came in on
+    last_message: Option<(u16, usize)>,
+}
+
+impl<L: NetworkInterface<Addr = u16>> MultiInterface<L> {
+    pub const fn new() -> MultiInterface<L> {
+        MultiInterface {
+            links: heapless::Vec::new(),
+            routes: BTreeMap::new(),
+            next_poll: 0,
+            last_message: None,
+        }
+    }
+
+    /// Add a link, ranked below any links already added.
+    pub fn add_link(&mut self, link: L) {
+        log::info!("adding {} link", link.link_name(&L::BROADCAST));
+        if self.links.push(link).is_err() {
+            log::error!("too many links; ignoring");
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.links.is_empty()
+    }
+
+    /// The links to `peer` that are live, best first.
+    fn live_links(&self, peer: u16) -> impl Iterator<Item = usize> + '_ {
+        let now = Instant::now();
+        let route = self.routes.get(&peer);
+        (0..self.links.len()).filter(move |i| route.is_some_and(|r| r.is_live(*i, now)))
+    }
+
+    /// Note that we heard an authenticated message from `peer` on `link`.
+    fn heard(&mut self, peer: u16, link: usize) {
+        if !self.routes.contains_key(&peer) && self.routes.len() >= MAX_NEIGHBORS {
+            let stalest = self
+                .routes
+                .iter()
+                .min_by_key(|(_, r)| r.newest())
+                .map(|(a, _)| *a);
+            if let Some(stalest) = stalest {
+                self.routes.remove(&stalest);
+            }
+        }
+        let route = self.routes.entry(peer).or_default();
+        route.last_heard[link] = Some(Instant::now());
+        route.failures[link] = 0;
+        route.unanswered[link] = None;
+    }
+
+    /// Send a copy of `msg` on `link`, and keep track of whether it worked.
+    async fn send_on(&mut self, link: usize, msg: &Message<u16>) -> Result<(), NetworkError> {
+        let copy = Message::new(msg.sender, msg.recipient, msg.contents.clone());
+        let result = self.links[link].send_message(copy).await;
+        if let Some(route) = self.routes.get_mut(&msg.recipient) {
+            match result {
+                Ok(()) => {
+                    route.failures[link] = 0;
+                    route.unanswered[link].get_or_insert_with(Instant::now);
+                }
+                Err(_) => route.failures[link] = route.failures[link].saturating_add(1),
+            }
+        }
+        result
+    }
+
+    /// Send `msg` on every link except those in `failed`, succeeding if any link succeeds.
+    async fn send_all(&mut self, msg: &Message<u16>, failed: &[usize]) -> Result<(), NetworkError> {
+        let mut result = Err(NetworkError::Send("no links".into()));
+        for link in (0..self.links.len()).filter(|l| !failed.contains(l)) {
+            match self.send_on(link, msg).await {
+                Ok(()) => result = Ok(()),
+                Err(e) => {
+                    log::warn!(
+                        "send on {} failed: {e}",
+                        self.links[link].link_name(&msg.recipient)
+                    );
+                    if result.is_err() {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+impl<L: NetworkInterface<Addr = u16>> NetworkInterface for MultiInterface<L> {
+    type Addr = u16;
+    const BROADCAST: Self::Addr = L::BROADCAST;
+    const NAME: &'static str = "mesh";
+
+    async fn send_message(&mut self, msg: Message<u16>) -> Result<(), NetworkError> {
+        if !impair::passes(msg.recipient) {
+            // As far as the sender can tell, it was sent and lost
+            return Ok(());
+        }
+        let latency = impair::latency();
+        if latency > Duration::from_ticks(0) {
+            Timer::after(latency).await;
+        }
+        if msg.recipient == Self::BROADCAST {
+            return self.send_all(&msg, &[]).await;
+        }
+        let live: heapless::Vec<usize, MAX_LINKS> = self.live_links(msg.recipient).collect();
+        let mut failed: heapless::Vec<usize, MAX_LINKS> = heapless::Vec::new();
+        for link in live {
+            match self.send_on(link, &msg).await {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    log::warn!(
+                        "send to {} on {} failed, failing over: {e}",
+                        msg.recipient,
+                        self.links[link].link_name(&msg.recipient)
+                    );
+                    // SAFETY: there are at most MAX_LINKS links
+                    failed.push(link).unwrap();
+                }
+            }
+        }
+        // We don't know how to reach this peer, so try everything else
+        self.send_all(&msg, &failed).await
+    }
+
+    async fn recv_message(&mut self) -> Result<Message<u16>, NetworkError> {
+        loop {
+            let count = self.links.len();
+            let first = self.next_poll;
+            // Every link's receive future is kept until one of them has a message, so that each
+            // link can wake us. The rest are then dropped, which is safe because links only give
+            // up a packet once a message is complete. They are kept in place rather than boxed,
+            // since the syncer calls this on every iteration.
+            let (n, result) = {
+                let (before, after) = self.links.split_at_mut(first);
+                let mut receiving: heapless::Vec<_, MAX_LINKS> = after
+                    .iter_mut()
+                    .chain(before)
+                    .map(|l| l.recv_message())
+                    .collect();
+                poll_fn(|cx| {
+                    for (n, future) in receiving.iter_mut().enumerate() {
+                        // SAFETY: `receiving` is never moved, and is dropped in place at the end
+                        // of this block
+                        let future = unsafe { Pin::new_unchecked(future) };
+                        if let Poll::Ready(result) = future.poll(cx) {
+                            return Poll::Ready((n, result));
+                        }
+                    }
+                    Poll::Pending
+                })
+                .await
+            };
+            let link = (first + n) % count;
+            self.next_poll = (link + 1) % count;
+            let msg = result?;
+            if !impair::passes(msg.sender) {
+                continue;
+            }
+            self.last_message = Some((msg.sender, link));
+            return Ok(msg);
+        }
+    }
+
+    fn my_address(&self) -> Self::Addr {
+        // Every link has the same address
+        self.links.first().map(L::my_address).unwrap_or_default()
+    }
+
+    fn reassign_address(&mut self) -> Self::Addr {
+        // Every link shares the address, so reassigning it on one moves all of them
+        self.links
+            .first_mut()
+            .map(L::reassign_address)
+            .unwrap_or_default()
+    }
+
+    /// The MTU of the link we'd use to reach `peer`. If we don't know how to reach it, the
+    /// smallest MTU of any link.
+    fn mtu(&self, peer: &Self::Addr) -> usize {
+        match self.live_links(*peer).next() {
+            Some(link) => self.links[link].mtu(peer),
+            None => self
+                .links
+                .iter()
+                .map(|l| l.mtu(peer))
+                .min()
+                .unwrap_or_default(),
+        }
+    }
+
+    fn link_name(&self, peer: &Self::Addr) -> &'static str {
+        match self.live_links(*peer).next() {
+            Some(link) => self.links[link].link_name(peer),
+            None => "-",
+        }
+    }
+
+    /// The loss on the link we'd use to reach `peer`. Both ends rank links the same way, so
+    /// that is also the link the peer reaches us over.
+    fn rx_loss(&self, peer: &Self::Addr) -> Option<u8> {
+        let link = self.live_links(*peer).next()?;
+        self.links[link].rx_loss(peer)
+    }
+
+    fn set_tx_loss(&mut self, peer: Self::Addr, loss: u8) {
+        let link = self.live_links(peer).next();
+        if let Some(link) = link {
+            self.links[link].set_tx_loss(peer, loss);
+        }
+    }
+
+    /// Learns that the link the message came in on reaches `peer`, and passes it on to that
+    /// link.
+    fn authenticated(&mut self, peer: Self::Addr) {
+        if let Some((sender, link)) = self.last_message.take() {
+            if sender == peer {
+                self.heard(peer, link);
+                self.links[link].authenticated(peer);
+            }
+        }
+    }
+}
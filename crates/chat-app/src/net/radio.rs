@@ -0,0 +1,257 @@
+#![cfg(feature = "net-esp-now")]
+//! The ESP-NOW radio's channel, PHY rate and transmit power.
+//!
+//! They start out as the [`RadioSettings`] in the parameters. Devices only hear each other on
+//! the same channel, so these are team configuration, provisioned alike on every member.
+//!
+//! They can be changed at runtime with the `radio` serial command, whose data is a list of
+//! settings separated by spaces like the [`impair`](super::impair) command's, and which replies
+//! with the current settings in the same form:
+//!
+//! - `channel=N`: move to Wi-Fi channel N, from 1 to 13
+//! - `rate=R`: send at PHY rate R in Mbps, e.g. `1m`, `5.5m` or `54m`
+//! - `power=DBM`: transmit at no more than DBM dBm, from 2 to 20
+//! - `survey`: listen on every channel in turn, and report how busy each one was
+//!
+//! After a survey, the reply also lists how busy each channel was and suggests the quietest.
+//! The survey itself changes nothing, since a device that changes channel can't hear the rest
+//! of its team until they move too. Moving to the suggested channel is left to provisioning
+//! the whole team. Changes are saved to the parameters.
+//!
+//! The [`EspNowNetworkEngine`](super::espnow::EspNowNetworkEngine) owns the radio, so requests
+//! are passed to it to carry out.
+
+use core::{
+    cell::RefCell,
+    ffi::c_void,
+    fmt,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex as BlockingMutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Timer};
+use esp_wifi::esp_now::{EspNowManager, WifiPhyRate};
+use parameter_store::{PhyRate, RadioSettings};
+
+/// How many channels a survey covers.
+pub const CHANNEL_COUNT: usize = 13;
+/// How long a survey listens on each channel.
+const SURVEY_DWELL: Duration = Duration::from_millis(250);
+
+/// The settings in effect, once the engine has applied them.
+static SETTINGS: BlockingMutex<CriticalSectionRawMutex, RefCell<Option<RadioSettings>>> =
+    BlockingMutex::new(RefCell::new(None));
+/// Requests for the engine to carry out.
+pub(crate) static REQUESTS: Signal<CriticalSectionRawMutex, RadioRequest> = Signal::new();
+/// The engine's answers to [`REQUESTS`].
+pub(crate) static REPORTS: Signal<CriticalSectionRawMutex, RadioReport> = Signal::new();
+/// Signaled with the new settings whenever they change at runtime, to be persisted.
+pub static RADIO_CHANGED: Signal<CriticalSectionRawMutex, RadioSettings> = Signal::new();
+
+/// Energy heard during a survey, from the promiscuous receive callback.
+static ENERGY: AtomicU32 = AtomicU32::new(0);
+
+// esp-wifi doesn't wrap these, but they're in the Wi-Fi libraries it links.
+extern "C" {
+    fn esp_wifi_set_max_tx_power(power: i8) -> i32;
+    fn esp_wifi_set_promiscuous(enable: bool) -> i32;
+    fn esp_wifi_set_promiscuous_rx_cb(
+        callback: Option<unsafe extern "C" fn(buf: *mut c_void, kind: u32)>,
+    ) -> i32;
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RadioError {
+    #[error("unknown setting `{0}`")]
+    UnknownSetting(heapless::String<16>),
+    #[error("bad value for `{0}`")]
+    BadValue(&'static str),
+}
+
+/// Settings for the engine to apply.
+#[derive(Debug, Clone)]
+pub struct RadioRequest {
+    pub settings: RadioSettings,
+    /// Survey the channels before applying `settings`
+    pub survey: bool,
+}
+
+/// The settings in effect after a request.
+#[derive(Debug, Clone)]
+pub struct RadioReport {
+    pub settings: RadioSettings,
+    /// How busy each channel was, if we surveyed them
+    pub survey: Option<[u32; CHANNEL_COUNT]>,
+}
+
+/// Formats as settings that the `radio` command accepts, followed by any survey results.
+impl fmt::Display for RadioReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "channel={} rate={} power={}",
+            self.settings.channel, self.settings.rate, self.settings.tx_power
+        )?;
+        if let Some(survey) = &self.survey {
+            write!(f, " survey=")?;
+            for (i, energy) in survey.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ",")?;
+                }
+                write!(f, "{energy}")?;
+            }
+            write!(f, " suggest={}", quietest(survey))?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse settings in the form described in the [module docs](self), starting from `settings`.
+fn parse(mut settings: RadioSettings, text: &str) -> Result<RadioRequest, RadioError> {
+    let mut survey = false;
+    for setting in text.split_whitespace() {
+        let (name, value) = setting.split_once('=').unwrap_or((setting, ""));
+        match name {
+            "channel" => {
+                settings.channel = value
+                    .parse()
+                    .ok()
+                    .filter(|c| RadioSettings::CHANNELS.contains(c))
+                    .ok_or(RadioError::BadValue("channel"))?
+            }
+            "rate" => settings.rate = value.parse().map_err(|_| RadioError::BadValue("rate"))?,
+            "power" => {
+                settings.tx_power = value
+                    .parse()
+                    .ok()
+                    .filter(|p| RadioSettings::TX_POWERS.contains(p))
+                    .ok_or(RadioError::BadValue("power"))?
+            }
+            "survey" => survey = true,
+            _ => {
+                let mut name = heapless::String::new();
+                for c in setting.chars() {
+                    if name.push(c).is_err() {
+                        break;
+                    }
+                }
+                return Err(RadioError::UnknownSetting(name));
+            }
+        }
+    }
+    Ok(RadioRequest { settings, survey })
+}
+
+/// The settings in effect, or the defaults if the radio hasn't started.
+pub fn get() -> RadioSettings {
+    SETTINGS.lock(|s| s.borrow().unwrap_or_default())
+}
+
+/// Ask the engine to apply `text`, and wait for the outcome. Empty text just reports the
+/// current settings.
+pub async fn request(text: &str) -> Result<RadioReport, RadioError> {
+    let current = get();
+    if text.trim().is_empty() {
+        return Ok(RadioReport {
+            settings: current,
+            survey: None,
+        });
+    }
+    REPORTS.reset();
+    REQUESTS.signal(parse(current, text)?);
+    Ok(REPORTS.wait().await)
+}
+
+fn phy_rate(rate: PhyRate) -> WifiPhyRate {
+    match rate {
+        PhyRate::Rate1M => WifiPhyRate::Rate1mL,
+        PhyRate::Rate2M => WifiPhyRate::Rate2m,
+        PhyRate::Rate5_5M => WifiPhyRate::Rate5mL,
+        PhyRate::Rate11M => WifiPhyRate::Rate11mL,
+        PhyRate::Rate6M => WifiPhyRate::Rate6m,
+        PhyRate::Rate9M => WifiPhyRate::Rate9m,
+        PhyRate::Rate12M => WifiPhyRate::Rate12m,
+        PhyRate::Rate18M => WifiPhyRate::Rate18m,
+        PhyRate::Rate24M => WifiPhyRate::Rate24m,
+        PhyRate::Rate36M => WifiPhyRate::Rate36m,
+        PhyRate::Rate48M => WifiPhyRate::Rate48m,
+        PhyRate::Rate54M => WifiPhyRate::Rate54m,
+    }
+}
+
+/// Apply `settings` to the radio. Settings the radio refuses are logged and skipped.
+pub(crate) fn apply(manager: &EspNowManager<'_>, settings: &RadioSettings) {
+    if let Err(e) = manager.set_channel(settings.channel) {
+        log::error!("radio: could not set channel {}: {e:?}", settings.channel);
+    }
+    if let Err(e) = manager.set_rate(phy_rate(settings.rate)) {
+        log::error!("radio: could not set rate {}: {e:?}", settings.rate);
+    }
+    // The driver counts power in quarter dBm
+    let quarter_dbm = settings.tx_power.clamp(2, 20) as i8 * 4;
+    // SAFETY: The radio has been started, and this only takes a number.
+    let err = unsafe { esp_wifi_set_max_tx_power(quarter_dbm) };
+    if err != 0 {
+        log::error!(
+            "radio: could not set TX power {} dBm: error {err}",
+            settings.tx_power
+        );
+    }
+    SETTINGS.lock(|s| *s.borrow_mut() = Some(*settings));
+    log::info!(
+        "radio: channel {}, rate {}, {} dBm",
+        settings.channel,
+        settings.rate,
+        settings.tx_power
+    );
+}
+
+/// Called by the driver for every frame it hears in promiscuous mode.
+extern "C" fn count_energy(buf: *mut c_void, _kind: u32) {
+    // The packet starts with its receive control fields, whose first byte is the RSSI in dBm.
+    // SAFETY: The driver passes a valid packet.
+    let rssi = unsafe { *(buf as *const i8) };
+    // A rough measure of how much the frame occupied the channel: louder frames crowd it more
+    let energy = (i32::from(rssi) + 100).max(0) as u32;
+    ENERGY.fetch_add(energy, Ordering::Relaxed);
+}
+
+/// Listen on each channel for a while, and return how busy each one was. The caller should
+/// not send anything until this returns, and must set the channel back afterward.
+pub(crate) async fn survey(manager: &EspNowManager<'_>) -> [u32; CHANNEL_COUNT] {
+    let mut energy = [0; CHANNEL_COUNT];
+    // SAFETY: The callback only touches an atomic.
+    unsafe {
+        esp_wifi_set_promiscuous_rx_cb(Some(count_energy));
+        esp_wifi_set_promiscuous(true);
+    }
+    for (channel, energy) in RadioSettings::CHANNELS.zip(energy.iter_mut()) {
+        if let Err(e) = manager.set_channel(channel) {
+            log::error!("radio: could not survey channel {channel}: {e:?}");
+            *energy = u32::MAX;
+            continue;
+        }
+        ENERGY.store(0, Ordering::Relaxed);
+        Timer::after(SURVEY_DWELL).await;
+        *energy = ENERGY.load(Ordering::Relaxed);
+    }
+    // SAFETY: As above.
+    unsafe {
+        esp_wifi_set_promiscuous(false);
+        esp_wifi_set_promiscuous_rx_cb(None);
+    }
+    log::info!("radio: survey {energy:?}");
+    energy
+}
+
+/// The quietest channel in a survey. Ties go to the lowest channel.
+pub fn quietest(survey: &[u32; CHANNEL_COUNT]) -> u8 {
+    RadioSettings::CHANNELS
+        .zip(survey)
+        .min_by_key(|(_, energy)| **energy)
+        .map(|(channel, _)| channel)
+        .unwrap_or(1)
+}
@@ -0,0 +1,107 @@
+#![cfg(feature = "net-uart")]
+//! A wired networking interface over a plain UART, for boards cabled together. Calling
+//! [`start`] will give you a [`UartNetworkEngine`], and [`UartNetworkInterface::new`] an
+//! interface to it that implements [`NetworkInterface`].
+//!
+//! The engine itself lives in [`uart_link`] so that it can be tested on a host. This just wires
+//! it to the ESP32-S3 UART and the rest of the firmware.
+
+use esp_hal::{
+    gpio::interconnect::{PeripheralInput, PeripheralOutput},
+    peripheral::Peripheral,
+    uart::{self, Uart, UartRx, UartTx},
+    Async,
+};
+use uart_link::{UartEngine, UartInterface};
+
+use super::{address, Message, NetworkEngine, NetworkError, NetworkInterface};
+use crate::{aranya::memory, mk_static};
+
+/// The wired link's baud rate. Both ends must agree.
+const UART_BAUD: u32 = 115200;
+
+pub(crate) type UartNetworkEngine =
+    UartEngine<'static, UartRx<'static, Async>, UartTx<'static, Async>>;
+
+/// The wired link's [`NetworkInterface`]. It's [`UartInterface`]'s, plus moving to a new
+/// address on a conflict and shrinking the messages it takes when memory runs low.
+pub struct UartNetworkInterface<'a>(UartInterface<'a>);
+
+impl UartNetworkInterface<'_> {
+    pub(crate) fn new(engine: &UartNetworkEngine) -> UartNetworkInterface<'_> {
+        UartNetworkInterface(engine.interface())
+    }
+}
+
+#[embassy_executor::task]
+async fn run_uart_engine(engine: &'static UartNetworkEngine) -> ! {
+    engine.run().await
+}
+
+impl NetworkEngine for UartNetworkEngine {
+    fn run(&'static self, spawner: embassy_executor::Spawner) -> Result<(), NetworkError> {
+        spawner
+            .spawn(run_uart_engine(self))
+            .expect("could not spawn UART engine");
+        Ok(())
+    }
+}
+
+impl NetworkInterface for UartNetworkInterface<'_> {
+    type Addr = u16;
+    const BROADCAST: Self::Addr = UartInterface::BROADCAST;
+    const NAME: &'static str = <UartInterface as NetworkInterface>::NAME;
+    const BROADCASTS: bool = UartInterface::BROADCASTS;
+
+    async fn send_message(&mut self, msg: Message<u16>) -> Result<(), NetworkError> {
+        self.0.send_message(msg).await
+    }
+
+    async fn recv_message(&mut self) -> Result<Message<u16>, NetworkError> {
+        self.0.set_max_message_len(memory::max_message_len());
+        self.0.recv_message().await
+    }
+
+    fn my_address(&self) -> Self::Addr {
+        self.0.my_address()
+    }
+
+    fn reassign_address(&mut self) -> Self::Addr {
+        address::reassign()
+    }
+
+    fn rx_loss(&self, peer: &Self::Addr) -> Option<u8> {
+        self.0.rx_loss(peer)
+    }
+
+    fn set_tx_loss(&mut self, peer: Self::Addr, loss: u8) {
+        self.0.set_tx_loss(peer, loss)
+    }
+
+    fn authenticated(&mut self, peer: Self::Addr) {
+        self.0.authenticated(peer)
+    }
+
+    fn mtu(&self, peer: &Self::Addr) -> usize {
+        self.0.mtu(peer)
+    }
+}
+
+/// Starts the wired networking engine on `uart` and returns it.
+pub(crate) fn start(
+    uart: impl Peripheral<P = impl uart::Instance> + 'static,
+    tx: impl Peripheral<P = impl PeripheralOutput> + 'static,
+    rx: impl Peripheral<P = impl PeripheralInput> + 'static,
+) -> &'static UartNetworkEngine {
+    let config = uart::Config::default().with_baudrate(UART_BAUD);
+    let (uart_rx, uart_tx) = Uart::new(uart, config)
+        .expect("valid UART config")
+        .with_tx(tx)
+        .with_rx(rx)
+        .into_async()
+        .split();
+    mk_static!(
+        UartNetworkEngine,
+        UartEngine::new(uart_rx, uart_tx, address::shared())
+    )
+}
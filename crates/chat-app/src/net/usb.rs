@@ -0,0 +1,102 @@
+#![cfg(feature = "net-usb")]
+//! A networking interface to a host over USB, so that a laptop can sync with the board
+//! directly. Calling [`start`] will give you a [`UsbNetworkEngine`], and
+//! [`UsbNetworkInterface::new`] an interface to it that implements [`NetworkInterface`].
+//!
+//! The board shows up on the host as a second serial port next to the chat console. That port
+//! carries the same frames as the [wired link](super::uart), so the link is the
+//! [`uart_link`] engine reading and writing a pair of pipes. The USB serial task moves bytes
+//! between the pipes and the port, and throws away what we send while no host has the port
+//! open. The host end is [`uart_link::host`].
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pipe::Pipe};
+use uart_link::{UartEngine, UartInterface};
+
+use super::{address, Message, NetworkEngine, NetworkError, NetworkInterface};
+use crate::{aranya::memory, mk_static};
+
+/// How many bytes can wait in each direction. A couple of frames' worth, since the USB side
+/// moves them much faster than we make them.
+const USB_PIPE_SIZE: usize = 256;
+
+pub(crate) type UsbPipe = Pipe<CriticalSectionRawMutex, USB_PIPE_SIZE>;
+
+/// Bytes from the host, to be read by the engine.
+pub(crate) static FROM_HOST: UsbPipe = Pipe::new();
+/// Bytes from the engine, to be written to the host.
+pub(crate) static TO_HOST: UsbPipe = Pipe::new();
+
+pub(crate) type UsbNetworkEngine = UartEngine<'static, &'static UsbPipe, &'static UsbPipe>;
+
+/// The USB link's [`NetworkInterface`]. It's the [wired link's](super::uart::UartNetworkInterface)
+/// under another name.
+pub struct UsbNetworkInterface<'a>(UartInterface<'a>);
+
+impl UsbNetworkInterface<'_> {
+    pub(crate) fn new(engine: &UsbNetworkEngine) -> UsbNetworkInterface<'_> {
+        UsbNetworkInterface(engine.interface())
+    }
+}
+
+#[embassy_executor::task]
+async fn run_usb_engine(engine: &'static UsbNetworkEngine) -> ! {
+    engine.run().await
+}
+
+impl NetworkEngine for UsbNetworkEngine {
+    fn run(&'static self, spawner: embassy_executor::Spawner) -> Result<(), NetworkError> {
+        spawner
+            .spawn(run_usb_engine(self))
+            .expect("could not spawn USB engine");
+        Ok(())
+    }
+}
+
+impl NetworkInterface for UsbNetworkInterface<'_> {
+    type Addr = u16;
+    const BROADCAST: Self::Addr = UartInterface::BROADCAST;
+    const NAME: &'static str = "usb";
+    const BROADCASTS: bool = UartInterface::BROADCASTS;
+
+    async fn send_message(&mut self, msg: Message<u16>) -> Result<(), NetworkError> {
+        self.0.send_message(msg).await
+    }
+
+    async fn recv_message(&mut self) -> Result<Message<u16>, NetworkError> {
+        self.0.set_max_message_len(memory::max_message_len());
+        self.0.recv_message().await
+    }
+
+    fn my_address(&self) -> Self::Addr {
+        self.0.my_address()
+    }
+
+    fn reassign_address(&mut self) -> Self::Addr {
+        address::reassign()
+    }
+
+    fn rx_loss(&self, peer: &Self::Addr) -> Option<u8> {
+        self.0.rx_loss(peer)
+    }
+
+    fn set_tx_loss(&mut self, peer: Self::Addr, loss: u8) {
+        self.0.set_tx_loss(peer, loss)
+    }
+
+    fn authenticated(&mut self, peer: Self::Addr) {
+        self.0.authenticated(peer)
+    }
+
+    fn mtu(&self, peer: &Self::Addr) -> usize {
+        self.0.mtu(peer)
+    }
+}
+
+/// Starts the USB networking engine and returns it. Nothing reaches the host until the USB
+/// serial task is running.
+pub(crate) fn start() -> &'static UsbNetworkEngine {
+    mk_static!(
+        UsbNetworkEngine,
+        UartEngine::new(&FROM_HOST, &TO_HOST, address::shared())
+    )
+}
@@ -0,0 +1,498 @@
+#![cfg(feature = "net-wifi")]
+//! A networking interface over Wi-Fi, so that boards on the same network can sync however far
+//! apart they are, and with host nodes on that network. Calling [`start`] joins the network
+//! and gives you a [`WifiNetworkEngine`], whose interface implements [`NetworkInterface`].
+//!
+//! ## Theory of Operation
+//!
+//! Broadcasts, which are mostly hellos, go out as UDP datagrams to the local broadcast address.
+//! Every other message, such as a sync request or response, goes over its own TCP connection to
+//! the recipient. Nodes learn each other's IP addresses from the datagrams and connections they
+//! receive, so a peer can be reached once it has been heard from. An address is only learned
+//! from a message that the syncer has [authenticated](NetworkInterface::authenticated), since
+//! anyone on the network can claim to be any sender. Until then, sending to the peer fails, and
+//! the sync engine tries another link. Connections are made in the background, so if one fails,
+//! the next message to that peer fails to send instead.
+//!
+//! Wi-Fi and TCP already retry lost packets, so unlike the radio links there is no fountain
+//! coding.
+//!
+//! The network stack can't be shared between executors, so the Wi-Fi tasks run on the main
+//! executor, spawned by [`start`], rather than on the network engines' executor. The interface
+//! talks to them over channels.
+//!
+//! The network to join comes from the parameters, and is set with `aranya-embedded-config
+//! --wifi-ssid`.
+//!
+//! ## On-wire format
+//!
+//! Datagrams and connections both use port [`WIFI_PORT`]. A datagram is a header followed by
+//! the message:
+//!
+//! ```text
+//! |  0  |  1  |  2  |  3  |  4  |  5  |  6  |  7  |  8 ...
+//! | magic                 | recipient |  sender   | contents
+//! | 41h | 45h | 57h | 31h |    u16    |    u16    |
+//! ```
+//!
+//! A connection carries one message, with the same header followed by the length of the
+//! contents as a u32 and then the contents. The sender closes it after the message. All fields
+//! are big-endian.
+
+use alloc::{
+    collections::{btree_map::BTreeMap, btree_set::BTreeSet},
+    format, vec,
+};
+use core::cell::RefCell;
+
+use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
+use embassy_net::{
+    tcp::{self, ConnectError, TcpSocket},
+    udp::{PacketMetadata, UdpSocket},
+    IpAddress, IpEndpoint, Runner, Stack, StackResources,
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex as BlockingMutex};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::{Read, ReadExactError, Write};
+use esp_hal::{peripheral::Peripheral, peripherals::WIFI};
+use esp_wifi::{
+    wifi::{
+        ClientConfiguration, Configuration, WifiController, WifiDevice, WifiEvent, WifiStaDevice,
+        WifiState,
+    },
+    EspWifiController,
+};
+use parameter_store::WifiCredentials;
+
+use super::{address, Message, NetworkError, NetworkInterface, MAX_MESSAGE_LEN};
+use crate::{aranya::memory, mk_static};
+
+const WIFI_QUEUE_SIZE: usize = 4;
+type Channel<T> = embassy_sync::channel::Channel<CriticalSectionRawMutex, T, WIFI_QUEUE_SIZE>;
+
+/// The UDP and TCP port of the Wi-Fi link.
+pub const WIFI_PORT: u16 = 5080;
+const WIFI_MAGIC: [u8; 4] = *b"AEW1";
+const HEADER_SIZE: usize = WIFI_MAGIC.len() + 4;
+/// The largest datagram we send or accept, which fits in an Ethernet frame.
+const MAX_DATAGRAM: usize = 1472;
+/// See [`NetworkInterface::mtu`].
+const WIFI_MTU: usize = 16 * 1024;
+/// How many incoming connections can be handled at once.
+const LISTENERS: usize = 2;
+/// DHCP, the UDP socket, the listeners, and one outgoing connection.
+const SOCKETS: usize = 3 + LISTENERS;
+/// The most peers whose IP addresses we remember.
+const MAX_WIFI_PEERS: usize = 32;
+const TCP_BUFFER_SIZE: usize = 1024;
+/// How long a connection can go quiet before it is dropped.
+const TCP_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait before trying to join the network again.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+enum WifiError {
+    #[error("could not connect: {0:?}")]
+    Connect(ConnectError),
+    #[error("connection reset")]
+    Reset,
+    #[error("connection closed early")]
+    Closed,
+    #[error("bad header")]
+    BadHeader,
+    #[error("message of {0} bytes is too long")]
+    TooLong(usize),
+}
+
+impl From<tcp::Error> for WifiError {
+    fn from(_: tcp::Error) -> WifiError {
+        WifiError::Reset
+    }
+}
+
+impl From<ReadExactError<tcp::Error>> for WifiError {
+    fn from(e: ReadExactError<tcp::Error>) -> WifiError {
+        match e {
+            ReadExactError::UnexpectedEof => WifiError::Closed,
+            ReadExactError::Other(e) => e.into(),
+        }
+    }
+}
+
+/// Write the header for `msg` from `sender` into `out`.
+fn write_header(out: &mut [u8], sender: u16, msg: &Message<u16>) {
+    out[..4].copy_from_slice(&WIFI_MAGIC);
+    out[4..6].copy_from_slice(&msg.recipient.to_be_bytes());
+    out[6..8].copy_from_slice(&sender.to_be_bytes());
+}
+
+/// Parse a header, returning the recipient and sender.
+fn read_header(header: &[u8]) -> Option<(u16, u16)> {
+    if header.len() < HEADER_SIZE || header[..4] != WIFI_MAGIC {
+        return None;
+    }
+    let recipient = u16::from_be_bytes([header[4], header[5]]);
+    let sender = u16::from_be_bytes([header[6], header[7]]);
+    Some((recipient, sender))
+}
+
+/// The channels between the interface and the Wi-Fi tasks.
+pub(crate) struct WifiNetworkEngine {
+    broadcast_channel: Channel<Message<u16>>,
+    unicast_channel: Channel<Message<u16>>,
+    /// Messages for us, and the IP address each came from
+    receive_channel: Channel<(Message<u16>, IpAddress)>,
+    /// The IP address each peer was last heard from, and when.
+    peers: BlockingMutex<CriticalSectionRawMutex, RefCell<BTreeMap<u16, (IpAddress, Instant)>>>,
+    /// Peers the last connection to failed, to be reported by the next send to them
+    failed: BlockingMutex<CriticalSectionRawMutex, RefCell<BTreeSet<u16>>>,
+}
+
+impl WifiNetworkEngine {
+    fn new() -> WifiNetworkEngine {
+        WifiNetworkEngine {
+            broadcast_channel: Channel::new(),
+            unicast_channel: Channel::new(),
+            receive_channel: Channel::new(),
+            peers: BlockingMutex::new(RefCell::new(BTreeMap::new())),
+            failed: BlockingMutex::new(RefCell::new(BTreeSet::new())),
+        }
+    }
+
+    pub fn interface(&self) -> WifiNetworkInterface<'_> {
+        WifiNetworkInterface {
+            engine: self,
+            last_message: None,
+        }
+    }
+
+    /// Remember that `peer` is at `ip`, forgetting the longest-silent peer if there are too
+    /// many.
+    fn learn(&self, peer: u16, ip: IpAddress) {
+        self.peers.lock(|peers| {
+            let mut peers = peers.borrow_mut();
+            if !peers.contains_key(&peer) && peers.len() >= MAX_WIFI_PEERS {
+                let oldest = peers
+                    .iter()
+                    .min_by_key(|(_, (_, heard))| *heard)
+                    .map(|(peer, _)| *peer);
+                if let Some(oldest) = oldest {
+                    peers.remove(&oldest);
+                }
+            }
+            peers.insert(peer, (ip, Instant::now()));
+        })
+    }
+
+    fn peer_ip(&self, peer: u16) -> Option<IpAddress> {
+        self.peers
+            .lock(|peers| peers.borrow().get(&peer).map(|(ip, _)| *ip))
+    }
+
+    fn forget(&self, peer: u16) {
+        self.peers.lock(|peers| peers.borrow_mut().remove(&peer));
+    }
+
+    /// Record that a message to `peer` could not be sent.
+    fn fail(&self, peer: u16) {
+        self.failed.lock(|failed| failed.borrow_mut().insert(peer));
+    }
+
+    /// Whether a message to `peer` could not be sent since we last asked.
+    fn take_failure(&self, peer: u16) -> bool {
+        self.failed.lock(|failed| failed.borrow_mut().remove(&peer))
+    }
+
+    /// Pass on a message received from `ip`, if it is for us. `ip` is only learned as the
+    /// sender's address once the message has been authenticated.
+    async fn deliver(&self, msg: Message<u16>, ip: IpAddress) {
+        let my_address = address::get();
+        if msg.sender == my_address {
+            // Our own broadcast
+            return;
+        }
+        if msg.recipient != my_address && msg.recipient != WifiNetworkInterface::BROADCAST {
+            log::debug!(
+                "wifi: message not for me (address: {my_address}); for {}",
+                msg.recipient
+            );
+            return;
+        }
+        self.receive_channel.send((msg, ip)).await;
+    }
+}
+
+/// Keep the device joined to the network.
+#[embassy_executor::task]
+async fn connection_task(mut controller: WifiController<'static>, credentials: WifiCredentials) {
+    let config = Configuration::Client(ClientConfiguration {
+        ssid: credentials.ssid.clone(),
+        password: credentials.password.clone(),
+        ..Default::default()
+    });
+    loop {
+        if matches!(esp_wifi::wifi::wifi_state(), WifiState::StaConnected) {
+            controller.wait_for_event(WifiEvent::StaDisconnected).await;
+            log::info!("wifi: disconnected from {}", credentials.ssid);
+            Timer::after(RECONNECT_DELAY).await;
+        }
+        if !matches!(controller.is_started(), Ok(true)) {
+            controller
+                .set_configuration(&config)
+                .expect("valid Wi-Fi configuration");
+            controller
+                .start_async()
+                .await
+                .expect("could not start Wi-Fi");
+        }
+        match controller.connect_async().await {
+            Ok(()) => log::info!("wifi: connected to {}", credentials.ssid),
+            Err(e) => {
+                log::error!("wifi: could not connect to {}: {e:?}", credentials.ssid);
+                Timer::after(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn stack_task(mut runner: Runner<'static, WifiDevice<'static, WifiStaDevice>>) {
+    runner.run().await
+}
+
+/// Send and receive broadcasts.
+#[embassy_executor::task]
+async fn udp_task(stack: Stack<'static>, engine: &'static WifiNetworkEngine) {
+    let mut rx_meta = [PacketMetadata::EMPTY; WIFI_QUEUE_SIZE];
+    let mut rx_buffer = [0u8; 2 * MAX_DATAGRAM];
+    let mut tx_meta = [PacketMetadata::EMPTY; WIFI_QUEUE_SIZE];
+    let mut tx_buffer = [0u8; 2 * MAX_DATAGRAM];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(WIFI_PORT).expect("could not bind Wi-Fi port");
+
+    stack.wait_config_up().await;
+    log::info!("wifi: network up: {:?}", stack.config_v4());
+
+    let broadcast = IpEndpoint::new(IpAddress::v4(255, 255, 255, 255), WIFI_PORT);
+    let mut buf = [0u8; MAX_DATAGRAM];
+    loop {
+        match select(
+            socket.recv_from(&mut buf),
+            engine.broadcast_channel.receive(),
+        )
+        .await
+        {
+            Either::First(Ok((len, meta))) => match read_header(&buf[..len]) {
+                Some((recipient, sender)) => {
+                    let msg = Message::new(sender, recipient, &buf[HEADER_SIZE..len]);
+                    engine.deliver(msg, meta.endpoint.addr).await;
+                }
+                None => log::debug!("wifi: bad datagram from {}", meta.endpoint),
+            },
+            Either::First(Err(e)) => log::error!("wifi: receive error: {e:?}"),
+            Either::Second(msg) => {
+                let mut out = [0u8; MAX_DATAGRAM];
+                let len = HEADER_SIZE + msg.contents.len();
+                write_header(&mut out, address::get(), &msg);
+                out[HEADER_SIZE..len].copy_from_slice(&msg.contents);
+                if let Err(e) = socket.send_to(&out[..len], broadcast).await {
+                    log::error!("wifi: broadcast error: {e:?}");
+                }
+            }
+        }
+    }
+}
+
+/// Accept connections and read a message from each.
+#[embassy_executor::task(pool_size = LISTENERS)]
+async fn listen_task(stack: Stack<'static>, engine: &'static WifiNetworkEngine) {
+    let mut rx_buffer = [0u8; TCP_BUFFER_SIZE];
+    let mut tx_buffer = [0u8; 64];
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(TCP_TIMEOUT));
+        if let Err(e) = socket.accept(WIFI_PORT).await {
+            log::error!("wifi: accept error: {e:?}");
+            Timer::after(RECONNECT_DELAY).await;
+            continue;
+        }
+        let Some(remote) = socket.remote_endpoint() else {
+            continue;
+        };
+        match read_message(&mut socket).await {
+            Ok(msg) => engine.deliver(msg, remote.addr).await,
+            Err(e) => log::info!("wifi: dropped connection from {remote}: {e}"),
+        }
+        socket.close();
+        socket.flush().await.ok();
+    }
+}
+
+async fn read_message(socket: &mut TcpSocket<'_>) -> Result<Message<u16>, WifiError> {
+    let mut header = [0u8; HEADER_SIZE + 4];
+    socket.read_exact(&mut header).await?;
+    let (recipient, sender) = read_header(&header).ok_or(WifiError::BadHeader)?;
+    let len = u32::from_be_bytes(header[HEADER_SIZE..].try_into().expect("4 bytes")) as usize;
+    if len > memory::max_message_len() {
+        return Err(WifiError::TooLong(len));
+    }
+    let mut contents = vec![0u8; len];
+    socket.read_exact(&mut contents).await?;
+    Ok(Message::new(sender, recipient, contents))
+}
+
+/// Send each unicast message over its own connection.
+#[embassy_executor::task]
+async fn tcp_send_task(stack: Stack<'static>, engine: &'static WifiNetworkEngine) {
+    let mut rx_buffer = [0u8; 64];
+    let mut tx_buffer = [0u8; TCP_BUFFER_SIZE];
+    loop {
+        let msg = engine.unicast_channel.receive().await;
+        let Some(ip) = engine.peer_ip(msg.recipient) else {
+            log::info!("wifi: forgot where {} is; dropping message", msg.recipient);
+            engine.fail(msg.recipient);
+            continue;
+        };
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(TCP_TIMEOUT));
+        if let Err(e) = send_message(&mut socket, ip, &msg).await {
+            // It may have moved; we'll hear from it again if it's still around
+            log::info!("wifi: send to {} at {ip} failed: {e}", msg.recipient);
+            engine.forget(msg.recipient);
+            engine.fail(msg.recipient);
+            socket.abort();
+        }
+    }
+}
+
+async fn send_message(
+    socket: &mut TcpSocket<'_>,
+    ip: IpAddress,
+    msg: &Message<u16>,
+) -> Result<(), WifiError> {
+    socket
+        .connect(IpEndpoint::new(ip, WIFI_PORT))
+        .await
+        .map_err(WifiError::Connect)?;
+    let mut header = [0u8; HEADER_SIZE + 4];
+    write_header(&mut header, address::get(), msg);
+    header[HEADER_SIZE..].copy_from_slice(&(msg.contents.len() as u32).to_be_bytes());
+    socket.write_all(&header).await?;
+    socket.write_all(&msg.contents).await?;
+    socket.flush().await?;
+    socket.close();
+    // Wait for the other end to close too, so the message isn't cut off
+    let mut buf = [0u8; 16];
+    while socket.read(&mut buf).await? > 0 {}
+    Ok(())
+}
+
+pub struct WifiNetworkInterface<'a> {
+    engine: &'a WifiNetworkEngine,
+    /// The sender of the last message received, and the IP address it came from
+    last_message: Option<(u16, IpAddress)>,
+}
+
+impl NetworkInterface for WifiNetworkInterface<'_> {
+    type Addr = u16;
+    const BROADCAST: Self::Addr = 0;
+    const NAME: &'static str = "wifi";
+
+    async fn send_message(&mut self, msg: Message<u16>) -> Result<(), NetworkError> {
+        if msg.contents.len() > MAX_MESSAGE_LEN {
+            return Err(NetworkError::Send(format!(
+                "{} bytes is too long to send",
+                msg.contents.len()
+            )));
+        }
+        if msg.recipient == Self::BROADCAST {
+            if HEADER_SIZE + msg.contents.len() > MAX_DATAGRAM {
+                return Err(NetworkError::Send(format!(
+                    "{} bytes is too long to broadcast",
+                    msg.contents.len()
+                )));
+            }
+            self.engine.broadcast_channel.send(msg).await;
+        } else {
+            if self.engine.take_failure(msg.recipient) {
+                // Report it now so the message goes another way
+                return Err(NetworkError::Send(format!(
+                    "last message to {} was not delivered",
+                    msg.recipient
+                )));
+            }
+            if self.engine.peer_ip(msg.recipient).is_none() {
+                return Err(NetworkError::Send(format!(
+                    "no IP address for {}",
+                    msg.recipient
+                )));
+            }
+            self.engine.unicast_channel.send(msg).await;
+        }
+        Ok(())
+    }
+
+    async fn recv_message(&mut self) -> Result<Message<u16>, NetworkError> {
+        let (msg, ip) = self.engine.receive_channel.receive().await;
+        self.last_message = Some((msg.sender, ip));
+        Ok(msg)
+    }
+
+    fn authenticated(&mut self, peer: Self::Addr) {
+        if let Some((sender, ip)) = self.last_message.take() {
+            if sender == peer {
+                self.engine.learn(peer, ip);
+            }
+        }
+    }
+
+    fn my_address(&self) -> Self::Addr {
+        address::get()
+    }
+
+    fn reassign_address(&mut self) -> Self::Addr {
+        address::reassign()
+    }
+
+    fn mtu(&self, _peer: &Self::Addr) -> usize {
+        WIFI_MTU
+    }
+}
+
+/// Joins the network in `credentials` and starts the Wi-Fi tasks on `spawner`, which must be
+/// the main executor's. Returns the engine to get an interface from.
+pub(crate) fn start(
+    spawner: Spawner,
+    init: &'static EspWifiController<'static>,
+    wifi: impl Peripheral<P = WIFI> + 'static,
+    credentials: WifiCredentials,
+) -> &'static WifiNetworkEngine {
+    let (device, controller) = esp_wifi::wifi::new_with_mode(init, wifi, WifiStaDevice)
+        .expect("could not create Wi-Fi device");
+    let mut seed = [0u8; 8];
+    getrandom::getrandom(&mut seed).expect("could not get random seed");
+    let (stack, runner) = embassy_net::new(
+        device,
+        embassy_net::Config::dhcpv4(Default::default()),
+        mk_static!(StackResources<SOCKETS>, StackResources::new()),
+        u64::from_le_bytes(seed),
+    );
+    let engine = mk_static!(WifiNetworkEngine, WifiNetworkEngine::new());
+
+    log::info!("wifi: joining {}", credentials.ssid);
+    spawner.must_spawn(connection_task(controller, credentials));
+    spawner.must_spawn(stack_task(runner));
+    spawner.must_spawn(udp_task(stack, engine));
+    spawner.must_spawn(tcp_send_task(stack, engine));
+    for _ in 0..LISTENERS {
+        spawner.must_spawn(listen_task(stack, engine));
+    }
+    engine
+}
@@ -126,9 +126,15 @@ where
 
 impl<N> SyncEngine<N>
 where
-    N: NetworkInterface,
+    N: NetworkInterface + Copy,
     N::Addr: Default + Ord + serde::Serialize + for<'b> serde::Deserialize<'b>,
 {
+    /// A handle to the network. Sending and receiving happen at once, so each gets its own
+    /// copy.
+    fn network(&self) -> N {
+        self.network
+    }
+
     /// Syncs with the peer.
     /// Aranya client sends a `SyncRequest` to peer. The `SyncResponse` is handled below in
     /// [`handle_message()`](Self::handle_message).
@@ -172,7 +178,7 @@ where
         send_buf.truncate(len);
         let sm = SyncMessage::new(SyncMessageType::Request, send_buf.into());
         let m = sm.into_message(self.network.my_address(), peer_addr)?;
-        self.network.send_message(m).await?;
+        self.network().send_message(m).await?;
         Ok(())
     }
 
@@ -214,7 +220,7 @@ where
         let hello_bytes = postcard::to_allocvec(&hello)?;
         let sm = SyncMessage::new(SyncMessageType::Hello, hello_bytes.into());
         let m = sm.into_message(self.network.my_address(), N::BROADCAST)?;
-        self.network.send_message(m).await?;
+        self.network().send_message(m).await?;
 
         Ok(())
     }
@@ -248,7 +254,7 @@ where
             let response_message =
                 SyncMessage::new(SyncMessageType::Response, msg_buf.into_boxed_slice());
             let msg = response_message.into_message(self.network.my_address(), from)?;
-            self.network.send_message(msg).await?;
+            self.network().send_message(msg).await?;
         }
 
         Ok(())
@@ -292,7 +298,7 @@ where
     }
 
     async fn handle_message(&self, buffers: &mut TraversalBuffers) -> Result<()> {
-        let msg = self.network.recv_message().await?;
+        let msg = self.network().recv_message().await?;
         let (from, sm) = SyncMessage::from_message(msg)?;
         log::info!(
             "received SyncMessage {:?} from {from}, len {}",
@@ -351,10 +357,14 @@ where
 #[cfg(feature = "net-wifi")]
 #[embassy_executor::task]
 pub async fn sync_wifi(
-    client: Arc<Mutex<daemon::Client>>,
-    network: crate::net::wifi::WifiNetwork<'static>,
+    imp: Imp<NeopixelSink>,
+    network: crate::net::wifi::WifiNetworkInterface<'static>,
+    peers: heapless::Vec<u16, MAX_PEERS>,
 ) {
-    log::info!("WiFi syncer does nothing, lol");
+    log::info!("WiFi syncer started");
+    let engine = SyncEngine::new(imp, &network, peers);
+
+    embassy_futures::join::join(engine.initiate(), engine.serve()).await;
 }
 
 #[cfg(feature = "net-irda")]
@@ -365,7 +375,7 @@ pub async fn sync_irda(
     peers: heapless::Vec<u16, MAX_PEERS>,
 ) {
     log::info!("IrDA syncer started");
-    let engine = SyncEngine::new(imp, network, peers);
+    let engine = SyncEngine::new(imp, &network, peers);
 
     embassy_futures::join::join(engine.initiate(), engine.serve()).await;
 }
@@ -378,7 +388,7 @@ pub async fn sync_esp_now(
     peers: heapless::Vec<u16, MAX_PEERS>,
 ) {
     log::info!("ESP Now syncer started");
-    let engine = SyncEngine::new(imp, network, peers);
+    let engine = SyncEngine::new(imp, &network, peers);
 
     embassy_futures::join::join(engine.initiate(), engine.serve()).await;
 }
@@ -1,7 +1,6 @@
 #![no_std]
 #![no_main]
 #![feature(impl_trait_in_assoc_type)]
-#![feature(core_io_borrowed_buf)]
 #![feature(new_zeroed_alloc)]
 
 extern crate alloc;
@@ -163,15 +162,17 @@ async fn main(spawner: Spawner) {
             timer_g1.timer1,
             rng,
             spawner,
+            parameter_values.address,
         )
         .await;
-        spawner
-            .spawn(aranya::syncer::sync_wifi(
-                daemon.get_client(),
-                engine.interface(),
-            ))
-            .expect("could not spawn WiFi syncer task");
-        network_engines.push(engine);
+        spawner.must_spawn(aranya::syncer::sync_wifi(
+            daemon.get_imp(graph_id, NeopixelSink::new()),
+            engine.interface(),
+            parameter_values.peers.clone(),
+        ));
+        if network_engines.push(engine).is_err() {
+            log::info!("could not start WiFi network engine");
+        }
     }
 
     #[cfg(feature = "net-esp-now")]
@@ -1,46 +1,27 @@
 #![cfg(feature = "net-esp-now")]
 //! This implements a networking interface over EspNow hardware.
-//! Calling [`start`] will give you a [`EspNowNetworkInterface`] instance that implements [`Network`].
+//! Calling [`start`] will give you a [`EspNowNetworkInterface`] instance that implements [`NetworkInterface`].
 //!
 //! ## Theory of Operation
 //!
-//! A [`Message`]'s payload is split up into chunks with [`raptorq`], which adds redundancy to
-//! allow reconstruction from damaged packets. Then each chunk is packaged in an [`EspNowPacket`] and
-//! sent over the EspNow Interface.
-//!
-//! On the receiving end, it reads the Esp Now interface until a valid packet header is found,
-//! then the packet is read and given to an [`EspNowMessageReconstructor`], which collects packets
-//! sent to this address until it can reconstruct the original [`Message`]. Once a packet is
-//! successfully reconstructed, it is returned to the caller.
-//!
-//! ## [`EspNowPacket`] on-wire format
-//!
-//! The packet is a header followed by the payload bytes and finally by a 16-bit CRC. The header
-//! is 12 bytes and looks like this:
-//!
-//! ```
-//! |  0  |  1  |  2  |  3  |  4  |  5  |  6  |  7          |  8  |  9  | 10  | 11  |
-//! | magic           | recipient |  sender   | message_seq | chunk_len | total_len |
-//! | F0h | 0Fh | F0h |    u16    |    u16    |      u8     |    u16    |    u16    |
-//! ```
+//! Messages are framed with [`fountain_framing`], which splits them into redundant chunks so they
+//! can be reconstructed from damaged or missing packets. Each frame is broadcast as one ESP-NOW
+//! datagram.
 //!
+//! On the receiving end, each datagram is parsed as a frame, and frames sent to this address are
+//! given to a [`Decoder`], which collects packets until it can reconstruct the original
+//! [`Message`]. Once a message is successfully reconstructed, it is returned to the caller.
 
-use alloc::{collections::btree_map::BTreeMap, vec::Vec};
-use core::{
-    io::BorrowedBuf,
-    mem::MaybeUninit,
-    sync::atomic::{AtomicU32, AtomicU8, Ordering},
-};
+use core::sync::atomic::{AtomicU16, AtomicU32, Ordering};
 
-use crc::{self, Crc};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_time::{Duration, Instant, Timer};
 use esp_hal::gpio::Input;
 use esp_wifi::esp_now::{EspNowReceiver, EspNowSender, BROADCAST_ADDRESS};
-use raptorq::{EncodingPacket, ObjectTransmissionInformation};
+use fountain_framing::{max_frame_size, Decoder, Encoder, FrameError, Packet};
 
-use super::{Message, NetworkEngine, NetworkError, NetworkInterface};
-use crate::{mk_static, util::SliceCursor};
+use super::{Message, NetworkEngine, NetworkError, NetworkInterface, MAX_MESSAGE_LEN};
+use crate::mk_static;
 
 const ESP_NOW_PACKET_QUEUE_SIZE: usize = 2;
 type Mutex<T> = embassy_sync::mutex::Mutex<CriticalSectionRawMutex, T>;
@@ -51,23 +32,11 @@ type Sender<'a, T> =
 type Receiver<'a, T> =
     embassy_sync::channel::Receiver<'a, CriticalSectionRawMutex, T, ESP_NOW_PACKET_QUEUE_SIZE>;
 
-const CRC: Crc<u16> = Crc::<u16>::new(&crc::CRC_16_XMODEM); // XMODEM seems appropriate. :D
-const ESP_NOW_MAGIC: [u8; 3] = [0xF0, 0x0F, 0xF0];
-const ESP_NOW_CHUNK_SIZE: usize = 64; // needs to be less than 65536 because this will become the raptorq MTU which is u16
-const ESP_NOW_HEADER_SIZE: usize = 9; // recipient, sender, chunk_seq, chunk_len, total_len
-const ESP_NOW_CRC_SIZE: usize = (CRC.algorithm.width / 8) as usize;
-const RAPTORQ_OVERHEAD: usize = 4; // determined empirically - I don't know if there's a way to ask raptorq for this
-const ESP_NOW_PACKET_SIZE: usize = ESP_NOW_MAGIC.len()
-    + ESP_NOW_HEADER_SIZE
-    + ESP_NOW_CHUNK_SIZE
-    + ESP_NOW_CRC_SIZE
-    + RAPTORQ_OVERHEAD;
+const ESP_NOW_CHUNK_SIZE: usize = 64;
+/// See [`NetworkInterface::mtu`].
+const ESP_NOW_MTU: usize = 8 * 1024;
+const ESP_NOW_FRAME_SIZE: usize = max_frame_size(ESP_NOW_CHUNK_SIZE);
 
-/// The minimum time to wait between packets.
-const RANDOM_MIN: u32 = 25;
-/// The distance between the minimum and maximum times to wait. Time between packets is then
-/// unformly distributed between `RANDOM_MIN` and `RANDOM_MIN + RANDOM_SPREAD`.
-const RANDOM_SPREAD: u32 = 100;
 /// How long to wait to retry after a failed send.
 const SEND_RETRY_DELAY_MS: u64 = 50;
 
@@ -75,76 +44,8 @@ const SEND_RETRY_DELAY_MS: u64 = 50;
 pub enum EspNowError {
     #[error("EspNow Error")]
     EspNow,
-}
-
-/// `EspNowPacket` is one link-layer packet on the ESP Now interface
-pub struct EspNowPacket {
-    /// Recipient address.
-    pub recipient: u16,
-    /// Sender address.
-    pub sender: u16,
-    /// Identifier for this sequence of packets. All packets in the same message have the
-    /// same `message_seq`.
-    pub message_seq: u8,
-    // `chunk_len` is never read but the field exists for documentary purposes and because it
-    // will be used in the likely event we refactor to bincode 2.0.
-    #[allow(dead_code)]
-    /// Length of the `contents`` in this packet.
-    pub chunk_len: u16,
-    /// Total length of the message encoded by these packets.
-    pub total_len: u16,
-    /// The encoded payload of this packet.
-    pub contents: heapless::Vec<u8, { ESP_NOW_CHUNK_SIZE + RAPTORQ_OVERHEAD }>,
-}
-
-/// An EspNowMessageReconstructor consumes a series of packets to reconstruct the message
-/// encoded within.
-pub struct EspNowMessageReconstructor {
-    decoder: raptorq::Decoder,
-    message_seq: u8,
-    total_len: u16,
-    packets_recvd: usize,
-    finished: bool,
-}
-
-impl EspNowMessageReconstructor {
-    /// Create a new message reconstructor. The `initial_packet` argument is just used to
-    /// set up some decoder parameters. You should still call [`add_packet`](Self::add_packet)
-    /// after creating the reconstructor.
-    pub fn new(initial_packet: &EspNowPacket) -> EspNowMessageReconstructor {
-        EspNowMessageReconstructor {
-            decoder: raptorq::Decoder::new(ObjectTransmissionInformation::with_defaults(
-                initial_packet.total_len as u64,
-                ESP_NOW_CHUNK_SIZE as u16,
-            )),
-            message_seq: initial_packet.message_seq,
-            total_len: initial_packet.total_len,
-            packets_recvd: 0,
-            finished: false,
-        }
-    }
-
-    /// Add a packet to the reconstructor. When enough packets are added to reproduce the
-    /// original message, this will return `Some(data)`. Until then it will return `None`.
-    pub fn add_packet(&mut self, packet: EspNowPacket) -> Option<Vec<u8>> {
-        if packet.message_seq != self.message_seq || packet.total_len != self.total_len {
-            // sequence or length id different; this is a new packet sequence.
-            // Reset our state.
-            log::info!(
-                "reconstructor reset with {}/{} est. packets",
-                self.packets_recvd,
-                (self.total_len - 1) / ESP_NOW_CHUNK_SIZE as u16 + 1
-            );
-            *self = EspNowMessageReconstructor::new(&packet);
-        } else if self.finished {
-            // We are done but this is part of a message we've already completed
-            return None;
-        }
-        self.packets_recvd += 1;
-        self.decoder
-            .decode(EncodingPacket::deserialize(&packet.contents))
-            .inspect(|_| self.finished = true)
-    }
+    #[error("framing error: {0}")]
+    Frame(#[from] FrameError),
 }
 
 /// `EspNowNetworkEngine` manages turning a message into a series of packets and back again.
@@ -152,9 +53,8 @@ pub(crate) struct EspNowNetworkEngine<'a> {
     sender: Mutex<EspNowSender<'a>>,
     receiver: Mutex<EspNowReceiver<'a>>,
     my_address: u16,
-    input_buf: Mutex<[u8; ESP_NOW_PACKET_SIZE]>,
-    send_channel: Channel<EspNowPacket>,
-    receive_channel: Channel<EspNowPacket>,
+    send_channel: Channel<Packet>,
+    receive_channel: Channel<Packet>,
     last_rx: AtomicU32,
 }
 
@@ -169,49 +69,26 @@ impl<'o> EspNowNetworkEngine<'o> {
             sender,
             receiver,
             my_address,
-            input_buf: Mutex::new([0u8; ESP_NOW_PACKET_SIZE]),
             send_channel: Channel::new(),
             receive_channel: Channel::new(),
             last_rx: AtomicU32::new(0),
         }
     }
 
-    fn random_delay(crc: u16) -> u32 {
-        let crc_extended: u32 = crc.into();
-        RANDOM_MIN + crc_extended % RANDOM_SPREAD
-    }
-
-    /// Send a message to a recipient
-    async fn send_packet(&self, packet: EspNowPacket) -> Result<u16, EspNowError> {
-        let mut output_buf: [MaybeUninit<u8>; ESP_NOW_PACKET_SIZE] =
-            [MaybeUninit::uninit(); ESP_NOW_PACKET_SIZE];
-        let mut bb = BorrowedBuf::from(&mut output_buf[..]);
-        {
-            let mut bc = bb.unfilled();
-            // SAFETY: This shouldn't overflow as we should be writing at most `ESP_NOW_PACKET_SIZE`
-            // bytes.
-            bc.append(&ESP_NOW_MAGIC);
-            bc.append(&u16::to_be_bytes(packet.recipient));
-            bc.append(&u16::to_be_bytes(self.my_address));
-            bc.append(&u8::to_be_bytes(packet.message_seq));
-            bc.append(&u16::to_be_bytes(packet.chunk_len));
-            bc.append(&u16::to_be_bytes(packet.total_len));
-            bc.append(&packet.contents);
-        }
-        let crc = CRC.checksum(&bb.filled()[3..]); // do not CRC magic bytes
-        {
-            let mut bc = bb.unfilled();
-            bc.append(&u16::to_be_bytes(crc));
-        }
+    /// Send a packet, returning how long to wait before sending the next one.
+    async fn send_packet(&self, packet: Packet) -> Result<u32, EspNowError> {
+        let mut frame = [0u8; ESP_NOW_FRAME_SIZE];
+        let len = packet.encode(&mut frame)?;
+        let frame = &frame[..len];
 
         self.sender
             .lock()
             .await
-            .send_async(&BROADCAST_ADDRESS, bb.filled())
+            .send_async(&BROADCAST_ADDRESS, frame)
             .await
             .map_err(|_| EspNowError::EspNow)?;
 
-        Ok(crc)
+        Ok(fountain_framing::send_delay_ms(frame))
     }
 
     fn update_last_rx(&self) {
@@ -220,68 +97,37 @@ impl<'o> EspNowNetworkEngine<'o> {
     }
 
     /// Read data from the transceiver until we find a packet.
-    async fn recv_packet(&self) -> Result<EspNowPacket, EspNowError> {
-        let mut input_buf_guard = self.input_buf.lock().await;
-        let input_buf = input_buf_guard.as_mut();
-
+    async fn recv_packet(&self) -> Result<Packet, EspNowError> {
         loop {
             let received = self.receiver.lock().await.receive_async().await;
-            let receive_data = received.data();
             log::debug!("EspNow: reseave info {:?}", received.info);
-
-            if receive_data[0..3] != ESP_NOW_MAGIC {
-                log::debug!("EspNow: magic did not match {:?}", &receive_data[0..3]);
-                continue;
-            }
-
-            let input_buf = &receive_data[3..];
-            let mut crc = CRC.digest();
             self.update_last_rx();
-            crc.update(&input_buf[0..ESP_NOW_HEADER_SIZE]);
-            let (sender, chunk_seq, chunk_len, total_len) = {
-                let mut sc = SliceCursor::new(&input_buf[0..ESP_NOW_HEADER_SIZE]);
-                let recipient = sc.next_u16_be();
-                if recipient != self.my_address && recipient != EspNowNetworkInterface::BROADCAST {
-                    log::info!(
-                        "recv_packet: packet not for me (address: {}); for {} ",
-                        self.my_address,
-                        recipient
-                    );
+
+            let packet = match Packet::decode::<ESP_NOW_CHUNK_SIZE>(received.data()) {
+                Ok(packet) => packet,
+                Err(FrameError::BadMagic) => {
+                    log::debug!("EspNow: magic did not match");
                     continue;
                 }
-                let sender = sc.next_u16_be();
-                let chunk_seq = sc.next_u8();
-                let chunk_len = sc.next_u16_be() as usize;
-                if chunk_len > ESP_NOW_CHUNK_SIZE + RAPTORQ_OVERHEAD {
-                    log::info!("recv_packet: malformed chunk of size {chunk_len}");
+                Err(FrameError::BadCrc) => {
+                    log::error!("bad checksum");
+                    continue;
+                }
+                Err(e) => {
+                    log::info!("recv_packet: {e}");
                     continue;
                 }
-                let total_len = sc.next_u16_be();
-                // log::info!("recv_packet: chunk len {chunk_len} seq {chunk_seq}");
-
-                assert_eq!(sc.remaining(), 0);
-
-                (sender, chunk_seq, chunk_len, total_len)
             };
-
-            let input_buf = &input_buf[ESP_NOW_HEADER_SIZE..];
-            self.update_last_rx();
-            let checksum =
-                u16::from_be_bytes(input_buf[chunk_len..chunk_len + 2].try_into().unwrap());
-            crc.update(&input_buf[..chunk_len]);
-            if crc.finalize() != checksum {
-                log::error!("bad checksum");
+            let recipient = packet.header.recipient;
+            if recipient != self.my_address && recipient != EspNowNetworkInterface::BROADCAST {
+                log::info!(
+                    "recv_packet: packet not for me (address: {}); for {} ",
+                    self.my_address,
+                    recipient
+                );
                 continue;
             }
-            let contents = input_buf[..chunk_len].try_into().expect("packet too large");
-            return Ok(EspNowPacket {
-                recipient: self.my_address,
-                sender,
-                message_seq: chunk_seq,
-                chunk_len: chunk_len as u16,
-                total_len,
-                contents,
-            });
+            return Ok(packet);
         }
     }
 
@@ -290,8 +136,8 @@ impl<'o> EspNowNetworkEngine<'o> {
             send_tx: self.send_channel.sender(),
             receive_rx: self.receive_channel.receiver(),
             my_address: self.my_address,
-            message_seq: AtomicU8::new(0),
-            reconstructors: Mutex::new(BTreeMap::new()),
+            message_seq: AtomicU16::new(0),
+            decoder: Mutex::new(Decoder::with_max_len(MAX_MESSAGE_LEN)),
         }
     }
 
@@ -302,8 +148,8 @@ impl<'o> EspNowNetworkEngine<'o> {
             log::debug!("EspNow: Got Packet");
 
             match self.send_packet(packet).await {
-                Ok(crc) => {
-                    Timer::after_millis(Self::random_delay(crc) as u64).await;
+                Ok(delay) => {
+                    Timer::after_millis(delay as u64).await;
                 }
                 Err(e) => {
                     log::error!("EspNow: send error: {e}");
@@ -342,30 +188,27 @@ impl NetworkEngine for EspNowNetworkEngine<'_> {
 }
 
 pub struct EspNowNetworkInterface<'a> {
-    send_tx: Sender<'a, EspNowPacket>,
-    receive_rx: Receiver<'a, EspNowPacket>,
+    send_tx: Sender<'a, Packet>,
+    receive_rx: Receiver<'a, Packet>,
     my_address: u16,
-    message_seq: AtomicU8,
-    reconstructors: Mutex<BTreeMap<u16, EspNowMessageReconstructor>>,
+    message_seq: AtomicU16,
+    decoder: Mutex<Decoder<ESP_NOW_CHUNK_SIZE>>,
 }
 
 impl EspNowNetworkInterface<'_> {
     /// Send a message to a recipient
     async fn send(&self, msg: Message<u16>) -> Result<(), EspNowError> {
-        let total_len = msg.contents.len();
+        if msg.contents.len() > MAX_MESSAGE_LEN {
+            return Err(FrameError::MessageTooLong(msg.contents.len()).into());
+        }
         let message_seq = self.message_seq.fetch_add(1, Ordering::Relaxed);
-        let encoder = raptorq::Encoder::with_defaults(&msg.contents, ESP_NOW_CHUNK_SIZE as u16);
-        let repair_packets = (total_len / ESP_NOW_CHUNK_SIZE) * 12 / 10; // 20% extra packets
-        for packet in encoder.get_encoded_packets(repair_packets as u32) {
-            let enc_packet = packet.serialize();
-            let packet = EspNowPacket {
-                recipient: msg.recipient,
-                sender: self.my_address,
-                message_seq,
-                chunk_len: enc_packet.len() as u16,
-                total_len: total_len as u16,
-                contents: enc_packet.into_iter().collect(),
-            };
+        let encoder = Encoder::<ESP_NOW_CHUNK_SIZE>::new(
+            msg.recipient,
+            self.my_address,
+            message_seq,
+            &msg.contents,
+        )?;
+        for packet in encoder {
             log::info!("EspNow: Sending Packet");
             self.send_tx.send(packet).await;
             log::info!("EspNow: Sent Packet");
@@ -380,36 +223,35 @@ impl EspNowNetworkInterface<'_> {
             let packet = self.receive_rx.receive().await;
             log::debug!("EspNow: Received Packet");
 
-            let sender = packet.sender;
-            let recipient = packet.recipient;
-            let mut reconstructors = self.reconstructors.lock().await;
-            let reconstructor = reconstructors
-                .entry(sender)
-                .or_insert(EspNowMessageReconstructor::new(&packet));
-            if let Some(p) = reconstructor.add_packet(packet) {
-                return Ok(Message {
-                    recipient,
-                    sender,
-                    contents: p.into(),
-                });
+            match self.decoder.lock().await.add_packet(&packet) {
+                Ok(Some(p)) => {
+                    return Ok(Message {
+                        recipient: packet.header.recipient,
+                        sender: packet.header.sender,
+                        contents: p.into(),
+                        rssi: None,
+                    });
+                }
+                Ok(None) => (),
+                Err(e) => log::info!("EspNow: dropped packet from {}: {e}", packet.header.sender),
             }
         }
     }
 }
 
-impl NetworkInterface for EspNowNetworkInterface<'_> {
+/// Implemented for a shared reference, since the syncer sends and receives at the same time.
+impl NetworkInterface for &EspNowNetworkInterface<'_> {
     type Addr = u16;
     const BROADCAST: Self::Addr = 0;
+    const NAME: &'static str = "esp-now";
 
-    async fn send_message(&self, msg: Message<u16>) -> Result<(), NetworkError> {
-        match self.send(msg).await {
-            Ok(_) => (),
-            Err(e) => log::error!("esp now send: {e}"),
-        }
-        Ok(())
+    async fn send_message(&mut self, msg: Message<u16>) -> Result<(), NetworkError> {
+        self.send(msg)
+            .await
+            .map_err(|e| NetworkError::Send(alloc::format!("esp now send: {e}")))
     }
 
-    async fn recv_message(&self) -> Result<Message<u16>, NetworkError> {
+    async fn recv_message(&mut self) -> Result<Message<u16>, NetworkError> {
         let msg = self
             .recv()
             .await
@@ -420,6 +262,10 @@ impl NetworkInterface for EspNowNetworkInterface<'_> {
     fn my_address(&self) -> Self::Addr {
         self.my_address
     }
+
+    fn mtu(&self, _peer: &Self::Addr) -> usize {
+        ESP_NOW_MTU
+    }
 }
 
 /// Starts the Esp Now networking engine and returns and interface to it.
@@ -1,60 +1,252 @@
 #![cfg(feature = "net-wifi")]
+//! This implements a networking interface over Wi-Fi.
+//! Calling [`start`] will give you a [`WifiNetworkEngine`], whose
+//! [`interface`](WifiNetworkEngine::interface) implements [`NetworkInterface`].
+//!
+//! ## Theory of Operation
+//!
+//! This works like the [ESP-NOW link](super::espnow), with the access point in place of the
+//! radio. Messages are framed with [`fountain_framing`], and each frame is broadcast on the
+//! local network as one UDP datagram. On the receiving end, frames sent to this address are
+//! given to a [`Decoder`], which collects packets until it can reconstruct the original
+//! [`Message`].
+//!
+//! The socket belongs to the network stack, which runs on the main executor, so the socket task
+//! is started along with the stack rather than by [`NetworkEngine::run`].
 
 //mod addr;
 mod tasks;
 
-use alloc::{collections::btree_map::BTreeMap, format, string::String, vec::Vec};
-use core::sync::atomic::{AtomicU32, Ordering};
+use core::sync::atomic::{AtomicU16, Ordering};
 
 use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
 use embassy_net::{
-    tcp::{ConnectError, TcpSocket},
-    IpAddress, IpEndpoint, IpListenEndpoint, Stack, StackResources,
+    udp::{PacketMetadata, UdpSocket},
+    IpAddress, IpEndpoint, Stack, StackResources,
 };
-use embedded_io_async::Write;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::Timer;
 use esp_hal::peripheral::Peripheral;
 use esp_wifi::{wifi::WifiStaDevice, EspWifiController, EspWifiRngSource, EspWifiTimerSource};
+use fountain_framing::{max_frame_size, Decoder, Encoder, FrameError, Packet};
 
 use self::tasks::{connection, net_task};
-use super::{Network, NetworkError};
+use super::{Message, NetworkEngine, NetworkError, NetworkInterface, MAX_MESSAGE_LEN};
+use crate::mk_static;
+
+const WIFI_PACKET_QUEUE_SIZE: usize = 4;
+type Mutex<T> = embassy_sync::mutex::Mutex<CriticalSectionRawMutex, T>;
+type Channel<T> =
+    embassy_sync::channel::Channel<CriticalSectionRawMutex, T, WIFI_PACKET_QUEUE_SIZE>;
+type Sender<'a, T> =
+    embassy_sync::channel::Sender<'a, CriticalSectionRawMutex, T, WIFI_PACKET_QUEUE_SIZE>;
+type Receiver<'a, T> =
+    embassy_sync::channel::Receiver<'a, CriticalSectionRawMutex, T, WIFI_PACKET_QUEUE_SIZE>;
+
+const WIFI_PORT: u16 = 5080;
+/// Fits a frame in one Ethernet-sized datagram.
+const WIFI_CHUNK_SIZE: usize = 1024;
+const WIFI_FRAME_SIZE: usize = max_frame_size(WIFI_CHUNK_SIZE);
+/// See [`NetworkInterface::mtu`].
+const WIFI_MTU: usize = 64 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum WifiError {
+    #[error("framing error: {0}")]
+    Frame(#[from] FrameError),
+}
 
-type Mutex<T> = embassy_sync::mutex::Mutex<embassy_sync::blocking_mutex::raw::NoopRawMutex, T>;
+/// `WifiNetworkEngine` passes packets between the interface and the socket task.
+pub(crate) struct WifiNetworkEngine {
+    my_address: u16,
+    send_channel: Channel<Packet>,
+    receive_channel: Channel<Packet>,
+}
 
-const TCP_PORT: u16 = 5080;
+impl WifiNetworkEngine {
+    fn new(my_address: u16) -> WifiNetworkEngine {
+        WifiNetworkEngine {
+            my_address,
+            send_channel: Channel::new(),
+            receive_channel: Channel::new(),
+        }
+    }
 
-impl From<ConnectError> for NetworkError {
-    fn from(value: ConnectError) -> Self {
-        NetworkError::Connect(format!("{:?}", value))
+    pub fn interface(&self) -> WifiNetworkInterface<'_> {
+        WifiNetworkInterface {
+            send_tx: self.send_channel.sender(),
+            receive_rx: self.receive_channel.receiver(),
+            my_address: self.my_address,
+            message_seq: AtomicU16::new(0),
+            decoder: Mutex::new(Decoder::with_max_len(MAX_MESSAGE_LEN)),
+        }
+    }
+
+    /// Pass on a frame received from the network, if it is for us.
+    async fn deliver(&self, frame: &[u8]) {
+        let packet = match Packet::decode::<WIFI_CHUNK_SIZE>(frame) {
+            Ok(packet) => packet,
+            Err(e) => {
+                log::debug!("wifi: dropped datagram: {e}");
+                return;
+            }
+        };
+        let recipient = packet.header.recipient;
+        if packet.header.sender == self.my_address {
+            // Our own broadcast
+            return;
+        }
+        if recipient != self.my_address && recipient != WifiNetworkInterface::BROADCAST {
+            log::debug!(
+                "wifi: packet not for me (address: {}); for {}",
+                self.my_address,
+                recipient
+            );
+            return;
+        }
+        self.receive_channel.send(packet).await;
     }
 }
 
-impl From<embassy_net::tcp::Error> for NetworkError {
-    fn from(value: embassy_net::tcp::Error) -> Self {
-        match value {
-            embassy_net::tcp::Error::ConnectionReset => {
-                NetworkError::Stream(String::from("connection reset"))
+/// Broadcast packets from the interface, and pass on the ones we receive.
+#[embassy_executor::task]
+async fn run_wifi_socket(stack: Stack<'static>, engine: &'static WifiNetworkEngine) {
+    let mut rx_meta = [PacketMetadata::EMPTY; WIFI_PACKET_QUEUE_SIZE];
+    let mut rx_buffer = [0u8; WIFI_PACKET_QUEUE_SIZE * WIFI_FRAME_SIZE];
+    let mut tx_meta = [PacketMetadata::EMPTY; WIFI_PACKET_QUEUE_SIZE];
+    let mut tx_buffer = [0u8; WIFI_PACKET_QUEUE_SIZE * WIFI_FRAME_SIZE];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(WIFI_PORT).expect("could not bind Wi-Fi port");
+
+    // Wait for network stack to come up
+    log::info!("Waiting for network to come up...");
+    stack.wait_config_up().await;
+    log::info!("Network up: {:?}", stack.config_v4());
+
+    let broadcast = IpEndpoint::new(IpAddress::v4(255, 255, 255, 255), WIFI_PORT);
+    let mut buf = [0u8; WIFI_FRAME_SIZE];
+    loop {
+        match select(socket.recv_from(&mut buf), engine.send_channel.receive()).await {
+            Either::First(Ok((len, _))) => engine.deliver(&buf[..len]).await,
+            Either::First(Err(e)) => log::error!("wifi: recv error: {e:?}"),
+            Either::Second(packet) => {
+                let mut frame = [0u8; WIFI_FRAME_SIZE];
+                let len = match packet.encode(&mut frame) {
+                    Ok(len) => len,
+                    Err(e) => {
+                        log::error!("wifi: could not encode packet: {e}");
+                        continue;
+                    }
+                };
+                if let Err(e) = socket.send_to(&frame[..len], broadcast).await {
+                    log::error!("wifi: send error: {e:?}");
+                }
+                Timer::after_millis(fountain_framing::send_delay_ms(&frame[..len]) as u64).await;
             }
         }
     }
 }
 
-impl From<embassy_net::tcp::AcceptError> for NetworkError {
-    fn from(value: embassy_net::tcp::AcceptError) -> Self {
-        NetworkError::Accept(format!("{value:?}"))
+impl NetworkEngine for WifiNetworkEngine {
+    /// Nothing to do, since the socket task was started with the network stack.
+    fn run(&'static self, _spawner: embassy_executor::Spawner) -> Result<(), NetworkError> {
+        Ok(())
     }
 }
 
-pub async fn start<'a, TIM>(
+pub struct WifiNetworkInterface<'a> {
+    send_tx: Sender<'a, Packet>,
+    receive_rx: Receiver<'a, Packet>,
+    my_address: u16,
+    message_seq: AtomicU16,
+    decoder: Mutex<Decoder<WIFI_CHUNK_SIZE>>,
+}
+
+impl WifiNetworkInterface<'_> {
+    /// Send a message to a recipient
+    async fn send(&self, msg: Message<u16>) -> Result<(), WifiError> {
+        if msg.contents.len() > MAX_MESSAGE_LEN {
+            return Err(FrameError::MessageTooLong(msg.contents.len()).into());
+        }
+        let message_seq = self.message_seq.fetch_add(1, Ordering::Relaxed);
+        let encoder = Encoder::<WIFI_CHUNK_SIZE>::new(
+            msg.recipient,
+            self.my_address,
+            message_seq,
+            &msg.contents,
+        )?;
+        for packet in encoder {
+            self.send_tx.send(packet).await;
+        }
+        Ok(())
+    }
+
+    /// Read packets until we assemble a message, then return it
+    async fn recv(&self) -> Message<u16> {
+        loop {
+            let packet = self.receive_rx.receive().await;
+            match self.decoder.lock().await.add_packet(&packet) {
+                Ok(Some(p)) => {
+                    return Message {
+                        recipient: packet.header.recipient,
+                        sender: packet.header.sender,
+                        contents: p.into(),
+                        rssi: None,
+                    };
+                }
+                Ok(None) => (),
+                Err(e) => log::info!("wifi: dropped packet from {}: {e}", packet.header.sender),
+            }
+        }
+    }
+}
+
+/// Implemented for a shared reference, since the syncer sends and receives at the same time.
+impl NetworkInterface for &WifiNetworkInterface<'_> {
+    type Addr = u16;
+    const BROADCAST: Self::Addr = 0;
+    const NAME: &'static str = "wifi";
+
+    async fn send_message(&mut self, msg: Message<u16>) -> Result<(), NetworkError> {
+        self.send(msg)
+            .await
+            .map_err(|e| NetworkError::Send(alloc::format!("wifi send: {e}")))
+    }
+
+    async fn recv_message(&mut self) -> Result<Message<u16>, NetworkError> {
+        Ok(self.recv().await)
+    }
+
+    fn my_address(&self) -> Self::Addr {
+        self.my_address
+    }
+
+    fn mtu(&self, _peer: &Self::Addr) -> usize {
+        WIFI_MTU
+    }
+}
+
+/// Joins the network and starts the Wi-Fi tasks on `spawner`, which must be the main
+/// executor's. Returns the engine to get an interface from.
+pub(crate) async fn start<TIM>(
     wifi: impl Peripheral<P = esp_hal::peripherals::WIFI> + 'static,
     radio_clock: impl Peripheral<P = esp_hal::peripherals::RADIO_CLK> + 'static,
     timer: impl Peripheral<P = TIM> + 'static,
     rng: impl EspWifiRngSource,
     spawner: Spawner,
-) -> WifiNetwork<'a>
+    my_address: u16,
+) -> &'static WifiNetworkEngine
 where
     TIM: EspWifiTimerSource,
 {
-    let wifi_cont = crate::mk_static!(
+    let wifi_cont = mk_static!(
         EspWifiController<'static>,
         esp_wifi::init(timer, rng, radio_clock).expect("Failed to initialize wifi controller")
     );
@@ -72,165 +264,16 @@ where
     let (stack, runner) = embassy_net::new(
         device,
         config,
-        crate::mk_static!(StackResources<3>, StackResources::<3>::new()),
+        mk_static!(StackResources<3>, StackResources::<3>::new()),
         seed,
     );
+    let engine = mk_static!(WifiNetworkEngine, WifiNetworkEngine::new(my_address));
 
     // Spawn collection of tasks that passively maintain the necessary aspects of the server which are:
     // Starting device as an access point
     spawner.spawn(connection(controller)).ok();
     spawner.spawn(net_task(runner)).ok();
+    spawner.must_spawn(run_wifi_socket(stack, engine));
 
-    // Wait for network stack to come up
-    log::info!("Waiting for network to come up...");
-    stack.wait_config_up().await;
-    log::info!("Network up: {:?}", stack.config_v4().unwrap());
-
-    WifiNetwork::new(stack)
-}
-
-pub struct WifiNetwork<'a> {
-    stack: Stack<'a>,
-    tx_id: AtomicU32,
-    pending_responses: Mutex<BTreeMap<u32, Vec<u8>>>,
-}
-
-impl<'a> WifiNetwork<'a> {
-    pub fn new(stack: Stack<'a>) -> WifiNetwork<'a> {
-        WifiNetwork {
-            stack,
-            tx_id: AtomicU32::new(0),
-            pending_responses: Mutex::new(BTreeMap::new()),
-        }
-    }
-}
-
-impl Network for WifiNetwork<'_> {
-    type Addr = IpAddress;
-    type TxId = u32;
-
-    async fn send_request(&self, to: IpAddress, req: Vec<u8>) -> Result<u32, NetworkError> {
-        let mut rx_buffer = [0u8; 512];
-        let mut tx_buffer = [0u8; 512];
-        let mut socket = TcpSocket::new(self.stack, &mut rx_buffer, &mut tx_buffer);
-        let endpoint = IpEndpoint::new(to, TCP_PORT);
-        socket.connect(endpoint).await?;
-
-        socket.write_all(&req).await?;
-        socket.close();
-        log::debug!("sent sync request to {to}");
-
-        // get the sync response.
-        let mut recv = heapless::Vec::new();
-        let len = read_to_end(&mut socket, &mut recv).await?;
-        log::debug!("received sync response: {len} bytes from {to}");
-        // this is a janky solution but it doesn't require storing a TcpSocket in an object.
-        let tx_id = self.tx_id.fetch_add(1, Ordering::Relaxed);
-        self.pending_responses
-            .lock()
-            .await
-            .insert(tx_id, recv.to_vec());
-
-        Ok(tx_id)
-    }
-
-    async fn recv_response(&self, tx_id: u32) -> Result<Vec<u8>, NetworkError> {
-        loop {
-            let v = self.pending_responses.lock().await.remove(&tx_id);
-            match v {
-                Some(resp) => return Ok(resp),
-                None => embassy_time::Timer::after_millis(100).await,
-            }
-        }
-    }
-
-    async fn accept(&self) -> Result<(Self::TxId, Vec<u8>), NetworkError> {
-        let mut rx_buffer = [0u8; 512];
-        let mut tx_buffer = [0u8; 512];
-        let mut socket = TcpSocket::new(self.stack, &mut rx_buffer, &mut tx_buffer);
-        let endpoint = IpListenEndpoint {
-            addr: None,
-            port: TCP_PORT,
-        };
-        socket.accept(endpoint).await?;
-        let mut recv = heapless::Vec::new();
-        read_to_end(&mut socket, &mut recv).await?;
-        // TODO(chip): use a real tx_id
-        let tx_id = 0;
-
-        // TODO(chip): we need to respond to this socket somehow but we can't move the buffers
-        Ok((tx_id, recv.to_vec()))
-    }
-
-    async fn send_response(&self, tx_id: Self::TxId, resp: Vec<u8>) -> Result<(), NetworkError> {
-        todo!()
-    }
-}
-
-async fn read_to_end(
-    socket: &mut TcpSocket<'_>,
-    out: &mut heapless::Vec<u8, 1024>,
-) -> Result<usize, NetworkError> {
-    let mut buf = [0u8; 64];
-
-    loop {
-        match socket.read(&mut buf).await {
-            Ok(n) => out
-                .extend_from_slice(&buf[..n])
-                .map_err(|_| NetworkError::MessageTooLarge)?,
-            Err(e) => match e {
-                embassy_net::tcp::Error::ConnectionReset => break,
-            },
-        }
-    }
-
-    Ok(out.len())
-}
-
-/* #[embassy_executor::task]
-pub async fn wifi_sync_task(stack: Stack<'static>, graph_id: GraphId, daemon: Arc<Daemon>) {
-    log::info!("Waiting for connection...");
-
-    let _buf = [0u8; 1024];
-    let mut rx_buffer = [0; 1024];
-    let mut tx_buffer = [0; 1024];
-    loop {
-        if let Some(config) = stack.config_v4() {
-            log::info!("IP address: {:?}", config);
-        } else {
-            log::info!("No configuration detected, returning control back to scheduler to manage wifi connection");
-            // Wait a bit for the networking tasks to initialize
-            embassy_time::Timer::after(Duration::from_millis(1000)).await;
-            continue;
-        }
-        // TCP receive and transmit buffer sizes. 1KB for each
-        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
-
-        match socket
-            .accept(IpListenEndpoint {
-                addr: None,
-                port: 8080,
-            })
-            .await
-        {
-            Ok(_) => {
-                log::info!("Client connected");
-
-                let mut sync_handler = TcpSyncHandler::new(socket, graph_id, None);
-                sync_handler
-                    .handle_connection()
-                    .await
-                    .expect("Failed to handle connection");
-            }
-            Err(e) => {
-                // todo properly encase in a loop so that repeated attempts can be made to set up and use TCP sockets
-                log::error!("Accept error: {:?}", e);
-                // Close the current socket connection
-                socket.abort();
-                drop(socket);
-                embassy_time::Timer::after(Duration::from_millis(100)).await;
-            }
-        }
-    }
+    engine
 }
- */
@@ -1,13 +1,35 @@
 #![no_std]
 #![feature(iter_array_chunks)]
+//! IrDA send and receive over an ESP32-S3 UART.
+//!
+//! ## Collision detection
+//!
+//! The UART runs in IrDA duplex mode, so everything we transmit is echoed back to the receiver.
+//! Frames are sent in [`ECHO_CHUNK_SIZE`] byte chunks, and the receiver checks the echo of each
+//! chunk against what was sent before the next one goes out. If another device transmits at the
+//! same time, the pulses mix and the echo doesn't match (or doesn't arrive at all), and the send
+//! is aborted. The echoed bytes are never returned by the receiver.
+//!
+//! Checking the echo relies on the receiver being read while sending, so when the transceiver
+//! is [split](IrdaTransceiver::split), something must be reading from the [`IrdaReceiver`] for
+//! sends through the [`IrdaTransmitter`] to succeed.
 
 extern crate alloc;
 
 mod ir;
 
 use alloc::sync::Arc;
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicU32, Ordering},
+};
 
-use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, CriticalSectionMutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Timer};
 pub use esp_hal::uart::Error as UartError;
 use esp_hal::{
     gpio::{
@@ -21,6 +43,82 @@ use esp_hal::{
 
 pub use self::ir::IrUart;
 
+/// How many bytes are sent between echo checks.
+pub const ECHO_CHUNK_SIZE: usize = 16;
+/// How long to wait for the echo of a chunk after it has been sent. A chunk takes about 1.4ms
+/// to send at 115200 baud, and the echo arrives as it goes, so this only has to cover the
+/// receiver getting around to reading it.
+const ECHO_TIMEOUT: Duration = Duration::from_millis(5);
+
+/// The echo we're waiting for.
+#[derive(Default)]
+struct Echo {
+    expected: [u8; ECHO_CHUNK_SIZE],
+    len: usize,
+    matched: usize,
+    /// How many more bytes of a chunk that collided are still to be echoed. They are garbage,
+    /// so they are thrown away rather than received.
+    discard: usize,
+}
+
+/// State shared between the transmitter and receiver.
+struct Shared {
+    peripheral: CriticalSectionMutex<PeripheralRef<'static, AnyUart>>,
+    echo: CriticalSectionMutex<RefCell<Echo>>,
+    /// Signaled when the echo of a chunk is checked: `true` if it matched.
+    echo_checked: Signal<CriticalSectionRawMutex, bool>,
+    collisions: AtomicU32,
+}
+
+impl Shared {
+    /// Start waiting for the echo of `chunk`.
+    fn expect_echo(&self, chunk: &[u8]) {
+        self.echo_checked.reset();
+        self.echo.lock(|e| {
+            let mut e = e.borrow_mut();
+            e.expected[..chunk.len()].copy_from_slice(chunk);
+            e.len = chunk.len();
+            e.matched = 0;
+            e.discard = 0;
+        });
+    }
+
+    /// Stop waiting for an echo.
+    fn clear_echo(&self) {
+        self.echo.lock(|e| e.borrow_mut().len = 0);
+    }
+
+    /// Check received bytes against the echo we're waiting for, and remove the echoed bytes
+    /// from `buf`. Returns how many bytes are left.
+    fn filter_echo(&self, buf: &mut [u8]) -> usize {
+        self.echo.lock(|e| {
+            let mut e = e.borrow_mut();
+            let mut kept = 0;
+            for i in 0..buf.len() {
+                if e.discard > 0 {
+                    e.discard -= 1;
+                } else if e.len == 0 {
+                    buf[kept] = buf[i];
+                    kept += 1;
+                } else if buf[i] == e.expected[e.matched] {
+                    e.matched += 1;
+                    if e.matched == e.len {
+                        e.len = 0;
+                        self.echo_checked.signal(true);
+                    }
+                } else {
+                    // Someone else was transmitting. The byte is garbage either way, as is the
+                    // rest of the chunk's echo.
+                    e.discard = e.len - e.matched - 1;
+                    e.len = 0;
+                    self.echo_checked.signal(false);
+                }
+            }
+            kept
+        })
+    }
+}
+
 /// An `IrdaTransceiver` wraps the UART connected to an IrDA transceiver and manages the
 /// physical layer of sending and receiving bytes.
 pub struct IrdaTransceiver<'d> {
@@ -53,23 +151,23 @@ impl<'a> IrdaTransceiver<'a> {
             .split();
 
         peripheral_for_irda.set_irda_mode(true);
-        let peripheral = Arc::new(CriticalSectionMutex::new(peripheral_for_irda));
+        // Echo what we send back to the receiver, so collisions can be detected
+        peripheral_for_irda.set_irda_duplex(true);
+        let shared = Arc::new(Shared {
+            peripheral: CriticalSectionMutex::new(peripheral_for_irda),
+            echo: CriticalSectionMutex::new(RefCell::new(Echo::default())),
+            echo_checked: Signal::new(),
+            collisions: AtomicU32::new(0),
+        });
 
-        let obj = IrdaTransceiver {
+        IrdaTransceiver {
             ir_tx: IrdaTransmitter {
-                peripheral: Arc::clone(&peripheral),
+                shared: Arc::clone(&shared),
                 uart_tx,
                 en_driver,
             },
-            ir_rx: IrdaReceiver {
-                peripheral: peripheral,
-                uart_rx,
-            },
-        };
-        // TODO(chip): enable collision detection
-        //obj.set_irda_duplex(true);
-
-        obj
+            ir_rx: IrdaReceiver { shared, uart_rx },
+        }
     }
 
     /// Switch the transceiver's enable pin.
@@ -96,9 +194,25 @@ impl<'a> IrdaTransceiver<'a> {
     /// Send a sequence of bytes with collision detection. Bytes are
     /// sent in 16 byte chunks and if a data error is detected, this
     /// aborts early and returns `false`. Returns `true` if all bytes
-    /// were sent.
+    /// were sent. Anything else received while sending is discarded.
     pub async fn send(&mut self, buf: &[u8]) -> Result<bool, uart::Error> {
-        self.ir_tx.send(buf).await
+        let mut scratch = [0u8; ECHO_CHUNK_SIZE];
+        let drain = async {
+            loop {
+                if let Err(e) = self.ir_rx.read(&mut scratch).await {
+                    return Err::<bool, _>(e);
+                }
+            }
+        };
+        match select(self.ir_tx.send(buf), drain).await {
+            Either::First(result) => result,
+            Either::Second(result) => result,
+        }
+    }
+
+    /// How many sends have been aborted because of a collision.
+    pub fn collisions(&self) -> u32 {
+        self.ir_tx.collisions()
     }
 
     pub fn split(self) -> (IrdaTransmitter<'a>, IrdaReceiver<'a>) {
@@ -107,7 +221,7 @@ impl<'a> IrdaTransceiver<'a> {
 }
 
 pub struct IrdaTransmitter<'d> {
-    peripheral: Arc<CriticalSectionMutex<PeripheralRef<'static, AnyUart>>>,
+    shared: Arc<Shared>,
     uart_tx: UartTx<'d, Async>,
     en_driver: Output<'d>,
 }
@@ -125,29 +239,51 @@ impl IrdaTransmitter<'_> {
     /// Send a sequence of bytes. Bytes are
     /// sent in 16 byte chunks and if a data error is detected, this
     /// aborts early and returns `false`. Returns `true` if all bytes
-    /// were sent.
+    /// were sent. The [`IrdaReceiver`] must be read while sending to
+    /// check for errors; see [Collision detection](crate#collision-detection).
     pub async fn send(&mut self, buf: &[u8]) -> Result<bool, uart::Error> {
-        self.peripheral.lock(|p| p.set_tx_en(true));
-        self.uart_tx.write_async(buf).await?;
-        // We need to wait for everything to be sent here.
-        // `write_async()` only waits when the TX FIFO is full.
-        // Otherwise TX_EN gets turned off below before the message gets
-        // fully sent.
-        self.uart_tx.flush_async().await?;
-        self.peripheral.lock(|p| p.set_tx_en(false));
+        self.shared.peripheral.lock(|p| p.set_tx_en(true));
+        let result = self.send_chunks(buf).await;
+        self.shared.peripheral.lock(|p| p.set_tx_en(false));
+        self.shared.clear_echo();
+        if let Ok(false) = result {
+            self.shared.collisions.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    async fn send_chunks(&mut self, buf: &[u8]) -> Result<bool, uart::Error> {
+        for chunk in buf.chunks(ECHO_CHUNK_SIZE) {
+            self.shared.expect_echo(chunk);
+            self.uart_tx.write_async(chunk).await?;
+            // We need to wait for everything to be sent here.
+            // `write_async()` only waits when the TX FIFO is full.
+            // Otherwise TX_EN gets turned off below before the message gets
+            // fully sent.
+            self.uart_tx.flush_async().await?;
+            let checked = select(self.shared.echo_checked.wait(), Timer::after(ECHO_TIMEOUT)).await;
+            if !matches!(checked, Either::First(true)) {
+                return Ok(false);
+            }
+        }
         Ok(true)
     }
+
+    /// How many sends have been aborted because of a collision.
+    pub fn collisions(&self) -> u32 {
+        self.shared.collisions.load(Ordering::Relaxed)
+    }
 }
 
 pub struct IrdaReceiver<'d> {
-    peripheral: Arc<CriticalSectionMutex<PeripheralRef<'static, AnyUart>>>,
+    shared: Arc<Shared>,
     uart_rx: UartRx<'d, Async>,
 }
 
 impl IrdaReceiver<'_> {
     /// How many bytes are in the recv buffer?
     pub fn rx_fifo_count(&self) -> usize {
-        self.peripheral.lock(|p| {
+        self.shared.peripheral.lock(|p| {
             p.info()
                 .register_block()
                 .status()
@@ -161,7 +297,7 @@ impl IrdaReceiver<'_> {
     // TODO(chip): Remove this when https://github.com/esp-rs/esp-hal/pull/3190 gets released
     fn check_fifo(&self, e: &uart::Error) {
         if matches!(e, uart::Error::FifoOverflowed) {
-            self.peripheral.lock(|p| p.rxfifo_reset());
+            self.shared.peripheral.lock(|p| p.rxfifo_reset());
         }
     }
 
@@ -173,30 +309,36 @@ impl IrdaReceiver<'_> {
         self.uart_rx
             .read_bytes(&mut buf[..count])
             .inspect_err(|e| self.check_fifo(e))?;
-        Ok(count)
+        Ok(self.shared.filter_echo(&mut buf[..count]))
     }
 
-    /// Read from the recv buffer. Will fill `buf` with as
-    /// many bytes as are available up to the size of `buf`, but will
-    /// not wait for new bytes to come in.
+    /// Read from the recv buffer. Waits for at least one byte, then
+    /// fills `buf` with as many bytes as are available up to the size
+    /// of `buf`.
     pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, uart::Error> {
-        let count = self
-            .uart_rx
-            .read_async(buf)
-            .await
-            .inspect_err(|e| self.check_fifo(e))?;
-        Ok(count)
+        loop {
+            let count = self
+                .uart_rx
+                .read_async(buf)
+                .await
+                .inspect_err(|e| self.check_fifo(e))?;
+            let count = self.shared.filter_echo(&mut buf[..count]);
+            if count > 0 || buf.is_empty() {
+                return Ok(count);
+            }
+        }
     }
 
     /// Fill a buffer with bytes read from the transceiver.
     pub async fn read_all(&mut self, buf: &mut [u8]) -> Result<(), uart::Error> {
         let mut c = 0;
         while c < buf.len() {
-            c += self
+            let count = self
                 .uart_rx
                 .read_async(&mut buf[c..])
                 .await
                 .inspect_err(|e| self.check_fifo(e))?;
+            c += self.shared.filter_echo(&mut buf[c..c + count]);
         }
         Ok(())
     }
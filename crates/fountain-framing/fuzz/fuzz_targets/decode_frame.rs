@@ -0,0 +1,16 @@
+#![no_main]
+
+use fountain_framing::{max_frame_size, Decoder, Packet};
+use libfuzzer_sys::fuzz_target;
+
+const CHUNK: usize = 64;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(packet) = Packet::decode::<CHUNK>(data) {
+        // Anything we accept must encode back to the same frame
+        let mut buf = [0u8; max_frame_size(CHUNK)];
+        let len = packet.encode(&mut buf).expect("decoded packet fits");
+        assert_eq!(&buf[..len], &data[..len]);
+        let _ = Decoder::<CHUNK>::new().add_packet(&packet);
+    }
+});
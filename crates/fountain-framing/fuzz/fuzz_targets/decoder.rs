@@ -0,0 +1,29 @@
+#![no_main]
+
+use fountain_framing::{max_payload, Decoder, Header, Packet};
+use libfuzzer_sys::fuzz_target;
+
+const CHUNK: usize = 64;
+
+// Packets that got past the frame checks but are otherwise arbitrary. Each is a sender byte, a
+// sequence byte, a 24-bit big-endian total length, a payload length byte, and the payload.
+fuzz_target!(|data: &[u8]| {
+    let mut decoder = Decoder::<CHUNK>::new();
+    let mut data = data;
+    while let [sender, message_seq, t0, t1, t2, len, rest @ ..] = data {
+        let len = (*len as usize).min(max_payload(CHUNK)).min(rest.len());
+        let (payload, rest) = rest.split_at(len);
+        let packet = Packet {
+            header: Header {
+                recipient: 0,
+                sender: *sender as u16,
+                message_seq: *message_seq as u16,
+                chunk_len: len as u16,
+                total_len: u32::from_be_bytes([0, *t0, *t1, *t2]),
+            },
+            payload: payload.to_vec(),
+        };
+        let _ = decoder.add_packet(&packet);
+        data = rest;
+    }
+});
@@ -0,0 +1,16 @@
+#![no_main]
+
+use fountain_framing::{Decoder, StreamReader};
+use libfuzzer_sys::fuzz_target;
+
+const CHUNK: usize = 64;
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = StreamReader::<CHUNK>::new();
+    let mut decoder = Decoder::<CHUNK>::new();
+    for byte in data {
+        if let Some(Ok(packet)) = reader.push(*byte) {
+            let _ = decoder.add_packet(&packet);
+        }
+    }
+});
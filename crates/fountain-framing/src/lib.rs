@@ -0,0 +1,1194 @@
+#![no_std]
+//! Fountain-coded framing for packet links.
+//!
+//! ## Theory of Operation
+//!
+//! A message is split up into chunks with [`raptorq`], which adds redundancy to allow
+//! reconstruction when some packets are lost or damaged. An [`Encoder`] turns a message into a
+//! series of [`Packet`]s, each of which is written to a frame with [`Packet::encode`] and handed
+//! to the link.
+//!
+//! On the receiving end, frames are parsed back into packets, either from a whole datagram with
+//! [`Packet::decode`] or a byte at a time with a [`StreamReader`]. Packets are given to a
+//! [`Decoder`], which collects the packets from each sender until it can reconstruct the
+//! original message. A sender may have several messages in flight at once, such as a hello
+//! queued behind a long response, and their packets may be interleaved.
+//!
+//! Everything is generic over the chunk size, which is the RaptorQ symbol size. Both ends of a
+//! link must agree on it.
+//!
+//! Decoding takes time and memory that grow faster than the number of symbols, so long messages
+//! are split into RaptorQ source blocks of at most [`MAX_BLOCK_SYMBOLS`] symbols, which are
+//! decoded separately. A message can have up to 255 blocks, so it can be up to
+//! [`max_message_len`] bytes long.
+//!
+//! ## Repair rate
+//!
+//! How many repair packets go out with a message is up to the sender, and is chosen by a
+//! [`FecConfig`] from how lossy the link is. Receivers measure loss: the symbol IDs in the
+//! packets of a message say how many were sent before the last one received, and the
+//! [`Decoder`] keeps a smoothed [`loss`](Decoder::loss) for each sender. Getting that number
+//! back to the sender is up to the transport.
+//!
+//! ## Repair requests
+//!
+//! When the repair packets sent weren't enough, a receiver that has most of a message can ask
+//! for more instead of waiting for the whole message to be resent. The transport calls
+//! [`Decoder::repair_requests`] now and then, and sends each [`RepairRequest`] it returns to the
+//! message's sender. The sender keeps the encoders of its last few messages in an
+//! [`EncoderCache`] and answers with fresh repair packets. A message is only asked about a few
+//! times, and the sender may have forgotten it, so this is best effort.
+//!
+//! Anyone can send a repair request, and a short request can be answered with many packets.
+//! So requests are only answered for peers the transport vouches for, each peer is answered
+//! only a few times per message, and the repairs sent are limited per message and overall.
+//!
+//! Frames come from radios and can contain anything, so no decode path panics. Input that is
+//! truncated, damaged, or crafted is rejected with a [`FrameError`].
+//!
+//! ## On-wire format
+//!
+//! A frame is a header followed by the payload bytes and finally by a 16-bit CRC of everything
+//! but the magic bytes. The header is 15 bytes and looks like this:
+//!
+//! ```text
+//! |  0  |  1  |  2  |  3  |  4  |  5  |  6  |  7  |  8  |  9  | 10  | 11  | 12  | 13  | 14  |
+//! | magic           | recipient |  sender   |message_seq| chunk_len |       total_len       |
+//! | F0h | 0Fh | F0h |    u16    |    u16    |    u16    |    u16    |          u32          |
+//! ```
+//!
+//! All fields are big-endian. A repair request is a frame from the requester to the message's
+//! sender with the message's `message_seq` and `total_len`, and a 2-byte payload holding the
+//! number of repair packets wanted per source block. Data payloads are always a whole encoded
+//! symbol, so the two can't be confused.
+//!
+//! The magic bytes are chosen to allow some dead time during transmission on links where
+//! simultaneous transmissions corrupt each other.
+
+extern crate alloc;
+
+use alloc::{
+    collections::{btree_map::BTreeMap, VecDeque},
+    vec,
+    vec::Vec,
+};
+use core::fmt;
+
+use crc::Crc;
+use raptorq::{EncodingPacket, ObjectTransmissionInformation};
+
+const CRC: Crc<u16> = Crc::<u16>::new(&crc::CRC_16_XMODEM); // XMODEM seems appropriate. :D
+
+/// The bytes every frame starts with.
+pub const MAGIC: [u8; 3] = [0xF0, 0x0F, 0xF0];
+/// The size of the header after the magic bytes: recipient, sender, message_seq, chunk_len,
+/// total_len.
+pub const HEADER_SIZE: usize = 12;
+/// The size of the CRC at the end of a frame.
+pub const CRC_SIZE: usize = (CRC.algorithm.width / 8) as usize;
+/// How much bigger an encoded RaptorQ packet is than its chunk. Determined empirically - I don't
+/// know if there's a way to ask raptorq for this.
+pub const RAPTORQ_OVERHEAD: usize = 4;
+
+/// The minimum time to wait between packets, in milliseconds.
+const RANDOM_MIN: u32 = 25;
+/// The distance between the minimum and maximum times to wait. Time between packets is then
+/// uniformly distributed between `RANDOM_MIN` and `RANDOM_MIN + RANDOM_SPREAD`.
+const RANDOM_SPREAD: u32 = 100;
+
+/// The most source symbols in one RaptorQ source block. Longer messages are split into more
+/// blocks.
+pub const MAX_BLOCK_SYMBOLS: usize = 256;
+/// The most source blocks a message can have, since the block number is a byte.
+const MAX_BLOCKS: usize = u8::MAX as usize;
+
+/// The longest message that can be sent with chunks of `chunk_size`.
+pub const fn max_message_len(chunk_size: usize) -> usize {
+    MAX_BLOCKS * MAX_BLOCK_SYMBOLS * chunk_size
+}
+
+/// The largest payload a packet can carry with chunks of `chunk_size`.
+pub const fn max_payload(chunk_size: usize) -> usize {
+    chunk_size + RAPTORQ_OVERHEAD
+}
+
+/// The largest frame with chunks of `chunk_size`.
+pub const fn max_frame_size(chunk_size: usize) -> usize {
+    MAGIC.len() + HEADER_SIZE + max_payload(chunk_size) + CRC_SIZE
+}
+
+/// How long to wait after sending `frame` before sending the next one, in milliseconds.
+///
+/// The delay is taken from the frame's CRC, so it is effectively random, but costs nothing to
+/// compute. Devices that start sending at the same time drift apart instead of colliding on
+/// every packet.
+pub fn send_delay_ms(frame: &[u8]) -> u32 {
+    let crc = match frame.len().checked_sub(CRC_SIZE) {
+        Some(start) => u16::from_be_bytes([frame[start], frame[start + 1]]),
+        None => 0,
+    };
+    RANDOM_MIN + u32::from(crc) % RANDOM_SPREAD
+}
+
+/// Errors encoding or decoding frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The frame did not start with [`MAGIC`].
+    BadMagic,
+    /// The frame ended before its header said it would.
+    Truncated,
+    /// The header claims a payload larger than the chunk size allows.
+    Oversized(u16),
+    /// The CRC did not match.
+    BadCrc,
+    /// The output buffer is too small for the frame.
+    BufferTooSmall,
+    /// The message is longer than the framing can carry, or than the link allows.
+    MessageTooLong(usize),
+    /// The message is empty.
+    EmptyMessage,
+    /// The payload is not a packet the decoder can use.
+    BadPayload,
+    /// There is no room to reconstruct a message of this many bytes alongside the messages
+    /// already under way.
+    NoRoom(usize),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::BadMagic => write!(f, "magic did not match"),
+            FrameError::Truncated => write!(f, "frame truncated"),
+            FrameError::Oversized(len) => write!(f, "malformed chunk of size {len}"),
+            FrameError::BadCrc => write!(f, "bad checksum"),
+            FrameError::BufferTooSmall => write!(f, "buffer too small"),
+            FrameError::MessageTooLong(len) => write!(f, "message of {len} bytes is too long"),
+            FrameError::EmptyMessage => write!(f, "empty message"),
+            FrameError::BadPayload => write!(f, "malformed payload"),
+            FrameError::NoRoom(len) => write!(f, "no room for a message of {len} bytes"),
+        }
+    }
+}
+
+impl core::error::Error for FrameError {}
+
+/// The header of a [`Packet`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// Recipient address.
+    pub recipient: u16,
+    /// Sender address.
+    pub sender: u16,
+    /// Identifier for this sequence of packets. All packets in the same message have the
+    /// same `message_seq`.
+    pub message_seq: u16,
+    /// Length of the payload of this packet.
+    pub chunk_len: u16,
+    /// Total length of the message encoded by these packets.
+    pub total_len: u32,
+}
+
+impl Header {
+    /// Write the header to the first [`HEADER_SIZE`] bytes of `out`.
+    fn write(&self, out: &mut [u8]) {
+        out[0..2].copy_from_slice(&self.recipient.to_be_bytes());
+        out[2..4].copy_from_slice(&self.sender.to_be_bytes());
+        out[4..6].copy_from_slice(&self.message_seq.to_be_bytes());
+        out[6..8].copy_from_slice(&self.chunk_len.to_be_bytes());
+        out[8..12].copy_from_slice(&self.total_len.to_be_bytes());
+    }
+
+    /// Read a header from the first [`HEADER_SIZE`] bytes of `buf`.
+    fn read(buf: &[u8]) -> Header {
+        Header {
+            recipient: u16::from_be_bytes([buf[0], buf[1]]),
+            sender: u16::from_be_bytes([buf[2], buf[3]]),
+            message_seq: u16::from_be_bytes([buf[4], buf[5]]),
+            chunk_len: u16::from_be_bytes([buf[6], buf[7]]),
+            total_len: u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]),
+        }
+    }
+}
+
+/// One link-layer packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub header: Header,
+    /// The encoded payload of this packet.
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    /// The size of this packet's frame.
+    pub fn frame_len(&self) -> usize {
+        MAGIC.len() + HEADER_SIZE + self.payload.len() + CRC_SIZE
+    }
+
+    /// Write this packet's frame to `out`, returning the length of the frame.
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, FrameError> {
+        let len = self.frame_len();
+        let chunk_len = u16::try_from(self.payload.len())
+            .map_err(|_| FrameError::MessageTooLong(self.payload.len()))?;
+        let out = out.get_mut(..len).ok_or(FrameError::BufferTooSmall)?;
+        let (magic, rest) = out.split_at_mut(MAGIC.len());
+        magic.copy_from_slice(&MAGIC);
+        let (header, rest) = rest.split_at_mut(HEADER_SIZE);
+        Header {
+            chunk_len,
+            ..self.header
+        }
+        .write(header);
+        rest[..self.payload.len()].copy_from_slice(&self.payload);
+        let (body, crc) = out.split_at_mut(len - CRC_SIZE);
+        let checksum = CRC.checksum(&body[MAGIC.len()..]); // do not CRC magic bytes
+        crc.copy_from_slice(&checksum.to_be_bytes());
+        Ok(len)
+    }
+
+    /// Parse the frame at the start of `frame`. Anything after the end of the frame is
+    /// ignored.
+    pub fn decode<const CHUNK: usize>(frame: &[u8]) -> Result<Packet, FrameError> {
+        if frame.len() < MAGIC.len() {
+            return Err(FrameError::Truncated);
+        }
+        if frame[..MAGIC.len()] != MAGIC {
+            return Err(FrameError::BadMagic);
+        }
+        let rest = &frame[MAGIC.len()..];
+        if rest.len() < HEADER_SIZE {
+            return Err(FrameError::Truncated);
+        }
+        let header = Header::read(rest);
+        let chunk_len = header.chunk_len as usize;
+        if chunk_len > max_payload(CHUNK) {
+            return Err(FrameError::Oversized(header.chunk_len));
+        }
+        let body = rest
+            .get(..HEADER_SIZE + chunk_len + CRC_SIZE)
+            .ok_or(FrameError::Truncated)?;
+        let (body, crc) = body.split_at(HEADER_SIZE + chunk_len);
+        if CRC.checksum(body) != u16::from_be_bytes([crc[0], crc[1]]) {
+            return Err(FrameError::BadCrc);
+        }
+        Ok(Packet {
+            header,
+            payload: body[HEADER_SIZE..].to_vec(),
+        })
+    }
+}
+
+/// Parses frames from a stream of bytes, such as a UART.
+///
+/// Bytes are skipped until the magic bytes are found. A frame that fails to parse is
+/// discarded and the search for the magic bytes starts again after it.
+pub struct StreamReader<const CHUNK: usize> {
+    buf: Vec<u8>,
+    /// The length of the frame in `buf`, once its header has been read.
+    frame_len: Option<usize>,
+}
+
+impl<const CHUNK: usize> StreamReader<CHUNK> {
+    pub fn new() -> StreamReader<CHUNK> {
+        StreamReader {
+            buf: Vec::with_capacity(max_frame_size(CHUNK)),
+            frame_len: None,
+        }
+    }
+
+    /// Forget any partially read frame.
+    pub fn reset(&mut self) {
+        self.buf.clear();
+        self.frame_len = None;
+    }
+
+    /// Add a byte from the stream. Returns the result of parsing a frame when `byte` is its
+    /// last byte.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Packet, FrameError>> {
+        self.buf.push(byte);
+        if self.buf.len() <= MAGIC.len() {
+            // Drop bytes until what we have could be the start of the magic
+            while !MAGIC.starts_with(&self.buf) {
+                self.buf.remove(0);
+            }
+            return None;
+        }
+        let frame_len = match self.frame_len {
+            Some(len) => len,
+            None if self.buf.len() < MAGIC.len() + HEADER_SIZE => return None,
+            None => {
+                let header = Header::read(&self.buf[MAGIC.len()..]);
+                if header.chunk_len as usize > max_payload(CHUNK) {
+                    self.reset();
+                    return Some(Err(FrameError::Oversized(header.chunk_len)));
+                }
+                let len = MAGIC.len() + HEADER_SIZE + header.chunk_len as usize + CRC_SIZE;
+                self.frame_len = Some(len);
+                len
+            }
+        };
+        if self.buf.len() < frame_len {
+            return None;
+        }
+        let result = Packet::decode::<CHUNK>(&self.buf);
+        self.reset();
+        Some(result)
+    }
+}
+
+impl<const CHUNK: usize> Default for StreamReader<CHUNK> {
+    fn default() -> Self {
+        StreamReader::new()
+    }
+}
+
+/// The chunk size as raptorq wants it. This fails to compile if `CHUNK` is not a valid
+/// RaptorQ symbol size.
+struct ChunkSize<const CHUNK: usize>;
+
+impl<const CHUNK: usize> ChunkSize<CHUNK> {
+    const U16: u16 = {
+        assert!(CHUNK > 0 && CHUNK <= u16::MAX as usize);
+        CHUNK as u16
+    };
+}
+
+/// The raptorq parameters for a message of `total_len` bytes: as few source blocks as keep
+/// each one within [`MAX_BLOCK_SYMBOLS`].
+fn object_config<const CHUNK: usize>(
+    total_len: u32,
+) -> Result<ObjectTransmissionInformation, FrameError> {
+    if total_len == 0 {
+        return Err(FrameError::EmptyMessage);
+    }
+    if total_len as usize > max_message_len(CHUNK) {
+        return Err(FrameError::MessageTooLong(total_len as usize));
+    }
+    let symbols = total_len.div_ceil(ChunkSize::<CHUNK>::U16.into());
+    let blocks = symbols.div_ceil(MAX_BLOCK_SYMBOLS as u32);
+    Ok(ObjectTransmissionInformation::new(
+        total_len.into(),
+        ChunkSize::<CHUNK>::U16,
+        blocks as u8,
+        1,
+        1,
+    ))
+}
+
+/// The sizes of a message's source blocks in symbols, as `[(large, count), (small, count)]`.
+/// This is how raptorq splits them up.
+fn block_symbols(config: &ObjectTransmissionInformation) -> [(u32, u32); 2] {
+    let symbols = config
+        .transfer_length()
+        .div_ceil(config.symbol_size().into()) as u32;
+    let (large, small, large_blocks, small_blocks) =
+        raptorq::partition(symbols, config.source_blocks());
+    [(large, large_blocks), (small, small_blocks)]
+}
+
+/// The loss to assume on a link we haven't heard a loss report for, in percent.
+pub const UNKNOWN_LOSS: u8 = 50;
+/// Higher losses are treated as this, since no sensible amount of repair gets through.
+const MAX_LOSS: u8 = 90;
+/// How many more symbols than the source symbols we want to arrive, so that decoding is
+/// very likely to succeed.
+const DECODE_MARGIN: u32 = 2;
+
+/// Chooses how many repair packets to send with a message, depending on how lossy the link is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FecConfig {
+    /// The fewest repair packets to send, as a percentage of the source packets.
+    pub min_overhead: u16,
+    /// The most repair packets to send, as a percentage of the source packets.
+    pub max_overhead: u16,
+    /// The fewest repair packets to send with any message, so that short messages that round
+    /// to no repair at all are still protected.
+    pub min_repair: u32,
+}
+
+impl FecConfig {
+    pub const DEFAULT: FecConfig = FecConfig {
+        min_overhead: 20,
+        max_overhead: 150,
+        min_repair: 2,
+    };
+
+    /// The number of repair packets to send along with `source_packets` source packets over a
+    /// link that loses `loss` percent of packets. `None` means the loss is unknown, and
+    /// [`UNKNOWN_LOSS`] is assumed.
+    pub fn repair_packets(&self, source_packets: u32, loss: Option<u8>) -> u32 {
+        let loss = u64::from(loss.unwrap_or(UNKNOWN_LOSS).min(MAX_LOSS));
+        let source = u64::from(source_packets);
+        // Enough packets that the source packets and a margin should get through
+        let needed = ((source + u64::from(DECODE_MARGIN)) * 100).div_ceil(100 - loss);
+        let min = source * u64::from(self.min_overhead) / 100;
+        let max = source * u64::from(self.max_overhead) / 100;
+        let repair = (needed - source)
+            .clamp(min, max.max(min))
+            .max(u64::from(self.min_repair));
+        u32::try_from(repair).unwrap_or(u32::MAX)
+    }
+}
+
+impl Default for FecConfig {
+    fn default() -> Self {
+        FecConfig::DEFAULT
+    }
+}
+
+/// How long a peer's report of the loss on its link from us is trusted, in milliseconds.
+pub const LOSS_REPORT_LIFETIME_MS: u64 = 60_000;
+
+/// The loss each peer reports for our packets, for choosing how much repair to send them.
+///
+/// Links change, so reports are ignored once they are [`LOSS_REPORT_LIFETIME_MS`] old, and only
+/// the [`MAX_SENDERS`] peers that reported most recently are kept. Times are milliseconds from
+/// an arbitrary epoch, so this never reads a clock itself.
+#[derive(Debug, Clone, Default)]
+pub struct LossReports {
+    /// Each peer's loss, and when it reported it
+    reports: BTreeMap<u16, (u8, u64)>,
+}
+
+impl LossReports {
+    pub const fn new() -> LossReports {
+        LossReports {
+            reports: BTreeMap::new(),
+        }
+    }
+
+    /// Record that `peer` reported losing `loss` percent of our packets at `now_ms`. Old
+    /// reports are dropped, and if there are still too many, the one made longest ago.
+    pub fn insert(&mut self, peer: u16, loss: u8, now_ms: u64) {
+        self.reports
+            .retain(|_, (_, at)| now_ms.saturating_sub(*at) < LOSS_REPORT_LIFETIME_MS);
+        if !self.reports.contains_key(&peer) && self.reports.len() >= MAX_SENDERS {
+            let oldest = self
+                .reports
+                .iter()
+                .min_by_key(|(_, (_, at))| *at)
+                .map(|(peer, _)| *peer);
+            if let Some(oldest) = oldest {
+                self.reports.remove(&oldest);
+            }
+        }
+        self.reports.insert(peer, (loss, now_ms));
+    }
+
+    /// The loss `peer` reported, if it did so recently.
+    pub fn get(&self, peer: u16, now_ms: u64) -> Option<u8> {
+        self.reports
+            .get(&peer)
+            .filter(|(_, at)| now_ms.saturating_sub(*at) < LOSS_REPORT_LIFETIME_MS)
+            .map(|(loss, _)| *loss)
+    }
+
+    /// The worst loss any peer reported recently, to plan broadcasts for.
+    pub fn worst(&self, now_ms: u64) -> Option<u8> {
+        self.reports
+            .values()
+            .filter(|(_, at)| now_ms.saturating_sub(*at) < LOSS_REPORT_LIFETIME_MS)
+            .map(|(loss, _)| *loss)
+            .max()
+    }
+}
+
+/// Splits a message into [`Packet`]s.
+pub struct Encoder<const CHUNK: usize> {
+    header: Header,
+    encoder: raptorq::Encoder,
+    packets: vec::IntoIter<EncodingPacket>,
+    /// How many repair packets have been generated for each source block.
+    repair_packets: u32,
+    /// How many packets the message was first sent as.
+    packet_count: u32,
+}
+
+impl<const CHUNK: usize> Encoder<CHUNK> {
+    /// Encode `message` from `sender` to `recipient`. `message_seq` should be different for
+    /// each message a sender sends, so that receivers can tell the packets of concurrent
+    /// messages apart. Repair packets are chosen by [`FecConfig::DEFAULT`] for a link of
+    /// unknown loss.
+    pub fn new(
+        recipient: u16,
+        sender: u16,
+        message_seq: u16,
+        message: &[u8],
+    ) -> Result<Encoder<CHUNK>, FrameError> {
+        Self::with_fec(
+            recipient,
+            sender,
+            message_seq,
+            message,
+            &FecConfig::DEFAULT,
+            None,
+        )
+    }
+
+    /// Like [`new`](Self::new), with repair packets chosen by `fec` for a link that loses
+    /// `loss` percent of packets.
+    pub fn with_fec(
+        recipient: u16,
+        sender: u16,
+        message_seq: u16,
+        message: &[u8],
+        fec: &FecConfig,
+        loss: Option<u8>,
+    ) -> Result<Encoder<CHUNK>, FrameError> {
+        let total_len =
+            u32::try_from(message.len()).map_err(|_| FrameError::MessageTooLong(message.len()))?;
+        let config = object_config::<CHUNK>(total_len)?;
+        let encoder = raptorq::Encoder::new(message, config);
+        // Repair packets are per block, so protect the largest block
+        let [(largest_block, _), _] = block_symbols(&config);
+        let repair_packets = fec.repair_packets(largest_block, loss);
+        let packets = encoder.get_encoded_packets(repair_packets);
+        Ok(Encoder {
+            header: Header {
+                recipient,
+                sender,
+                message_seq,
+                chunk_len: 0,
+                total_len,
+            },
+            packet_count: u32::try_from(packets.len()).unwrap_or(u32::MAX),
+            packets: packets.into_iter(),
+            encoder,
+            repair_packets,
+        })
+    }
+
+    /// The header every packet of this message has.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Generate `count` more repair packets for each source block, following on from the
+    /// ones already generated. This is how [`RepairRequest`]s are answered.
+    pub fn repair(&mut self, count: u32) -> Vec<Packet> {
+        let start = self.repair_packets;
+        self.repair_packets = start.saturating_add(count);
+        self.encoder
+            .get_block_encoders()
+            .iter()
+            .flat_map(|block| block.repair_packets(start, count))
+            .map(|p| self.packet(p))
+            .collect()
+    }
+
+    fn packet(&self, encoded: EncodingPacket) -> Packet {
+        let payload = encoded.serialize();
+        Packet {
+            header: Header {
+                chunk_len: payload.len() as u16,
+                ..self.header
+            },
+            payload,
+        }
+    }
+}
+
+impl<const CHUNK: usize> Iterator for Encoder<CHUNK> {
+    type Item = Packet;
+
+    fn next(&mut self) -> Option<Packet> {
+        let encoded = self.packets.next()?;
+        Some(self.packet(encoded))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.packets.size_hint()
+    }
+}
+
+impl<const CHUNK: usize> ExactSizeIterator for Encoder<CHUNK> {}
+
+/// The payload length that marks a packet as a [`RepairRequest`]. Data packets always carry a
+/// whole symbol, so they are never this short.
+const REPAIR_REQUEST_LEN: usize = 2;
+/// The most times a [`Decoder`] asks for repairs to one message.
+pub const MAX_REPAIR_REQUESTS: u8 = 3;
+/// The most repair packets per source block one request can ask for.
+pub const MAX_REPAIR_COUNT: u16 = 64;
+/// How much of a block must have arrived before we think it's worth asking for the rest, in
+/// percent.
+const REPAIR_THRESHOLD: u32 = 50;
+
+/// A receiver's request for more repair packets of a message it couldn't quite decode.
+///
+/// On the wire this is a packet from the requester to the message's sender, with the
+/// message's `message_seq` and `total_len`, and a payload of just the big-endian `count`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RepairRequest {
+    /// The sender of the message, who the request is for.
+    pub sender: u16,
+    /// Who is asking.
+    pub requester: u16,
+    pub message_seq: u16,
+    pub total_len: u32,
+    /// How many more repair packets to send for each source block.
+    pub count: u16,
+}
+
+impl RepairRequest {
+    /// The packet that carries this request.
+    pub fn to_packet(&self) -> Packet {
+        Packet {
+            header: Header {
+                recipient: self.sender,
+                sender: self.requester,
+                message_seq: self.message_seq,
+                chunk_len: REPAIR_REQUEST_LEN as u16,
+                total_len: self.total_len,
+            },
+            payload: self.count.to_be_bytes().to_vec(),
+        }
+    }
+
+    /// The request `packet` carries, if it is a repair request rather than data.
+    pub fn from_packet(packet: &Packet) -> Option<RepairRequest> {
+        let count: [u8; REPAIR_REQUEST_LEN] = packet.payload.as_slice().try_into().ok()?;
+        Some(RepairRequest {
+            sender: packet.header.recipient,
+            requester: packet.header.sender,
+            message_seq: packet.header.message_seq,
+            total_len: packet.header.total_len,
+            count: u16::from_be_bytes(count),
+        })
+    }
+}
+
+/// How many recently sent messages an [`EncoderCache`] keeps.
+pub const ENCODER_CACHE_SIZE: usize = 4;
+/// The repair packets sent for one message add up to at most this many times the most any
+/// request for it asked for, however many requests there are.
+const REPAIR_BUDGET_FACTOR: u32 = 2;
+/// Repair packets are paid for out of a budget that grows by this percentage of the packets of
+/// each message sent, so that answering requests never adds more than this to our traffic.
+const REPAIR_BUDGET_SHARE: u32 = 50;
+/// The most repair packets an [`EncoderCache`] saves up to send.
+const MAX_REPAIR_BUDGET: u32 = 1024;
+
+/// A message in an [`EncoderCache`], and the repairs sent for it so far.
+struct CachedEncoder<const CHUNK: usize> {
+    encoder: Encoder<CHUNK>,
+    /// How many times each requester has had repairs answered.
+    requesters: Vec<(u16, u8)>,
+    /// The most repair packets per source block any request asked for.
+    most_asked: u32,
+    /// The repair packets per source block sent in answer to requests.
+    answered: u32,
+}
+
+/// Keeps the encoders of the last few messages sent, so that [`RepairRequest`]s for them can be
+/// answered.
+///
+/// Requests are unauthenticated and each can ask for many packets, so they are only answered
+/// for [peers](Self::add_peer), and within limits. Each peer is answered at most
+/// [`MAX_REPAIR_REQUESTS`] times per message, the repairs to a message add up to a small multiple
+/// of the most that was asked for, and all repairs together are a fraction of what was sent.
+pub struct EncoderCache<const CHUNK: usize> {
+    encoders: VecDeque<CachedEncoder<CHUNK>>,
+    /// The addresses whose requests are answered, least recently added first.
+    peers: VecDeque<u16>,
+    /// How many more repair packets may be sent.
+    budget: u32,
+}
+
+impl<const CHUNK: usize> EncoderCache<CHUNK> {
+    pub fn new() -> EncoderCache<CHUNK> {
+        EncoderCache {
+            encoders: VecDeque::with_capacity(ENCODER_CACHE_SIZE),
+            peers: VecDeque::with_capacity(MAX_SENDERS),
+            budget: 0,
+        }
+    }
+
+    /// Keep `encoder` once all its packets have been sent, forgetting the oldest message if
+    /// the cache is full.
+    pub fn insert(&mut self, encoder: Encoder<CHUNK>) {
+        if self.encoders.len() >= ENCODER_CACHE_SIZE {
+            self.encoders.pop_front();
+        }
+        let earned = encoder.packet_count * REPAIR_BUDGET_SHARE / 100;
+        self.budget = self.budget.saturating_add(earned).min(MAX_REPAIR_BUDGET);
+        self.encoders.push_back(CachedEncoder {
+            encoder,
+            requesters: Vec::new(),
+            most_asked: 0,
+            answered: 0,
+        });
+    }
+
+    /// Answer repair requests from `peer`, which should be someone we know is on the team, such
+    /// as the sender of an authenticated message. Only the last [`MAX_SENDERS`] peers are
+    /// remembered.
+    pub fn add_peer(&mut self, peer: u16) {
+        self.peers.retain(|p| *p != peer);
+        if self.peers.len() >= MAX_SENDERS {
+            self.peers.pop_front();
+        }
+        self.peers.push_back(peer);
+    }
+
+    /// The repair packets that answer `request`. There are none if we've forgotten the
+    /// message, the requester isn't a peer, or the request is over the limits.
+    pub fn answer(&mut self, request: &RepairRequest) -> Vec<Packet> {
+        if !self.peers.contains(&request.requester) {
+            return Vec::new();
+        }
+        let Some(cached) = self.encoders.iter_mut().find(|c| {
+            let header = &c.encoder.header;
+            header.sender == request.sender
+                && header.message_seq == request.message_seq
+                && header.total_len == request.total_len
+        }) else {
+            return Vec::new();
+        };
+
+        let requesters = &mut cached.requesters;
+        let asked = match requesters.iter().position(|(r, _)| *r == request.requester) {
+            Some(i) => &mut requesters[i].1,
+            None if requesters.len() < MAX_SENDERS => {
+                requesters.push((request.requester, 0));
+                &mut requesters.last_mut().unwrap().1
+            }
+            None => return Vec::new(),
+        };
+        if *asked >= MAX_REPAIR_REQUESTS {
+            return Vec::new();
+        }
+        *asked += 1;
+
+        let count = u32::from(request.count.min(MAX_REPAIR_COUNT));
+        cached.most_asked = cached.most_asked.max(count);
+        let allowed = (cached.most_asked * REPAIR_BUDGET_FACTOR).saturating_sub(cached.answered);
+        let blocks = cached.encoder.encoder.get_block_encoders().len().max(1) as u32;
+        let count = count.min(allowed).min(self.budget / blocks);
+        if count == 0 {
+            return Vec::new();
+        }
+        cached.answered += count;
+        self.budget -= count * blocks;
+        cached.encoder.repair(count)
+    }
+}
+
+impl<const CHUNK: usize> Default for EncoderCache<CHUNK> {
+    fn default() -> Self {
+        EncoderCache::new()
+    }
+}
+
+/// How much of one source block a [`Reconstructor`] has received.
+struct BlockProgress {
+    /// The number of source symbols in the block.
+    source: u32,
+    /// The ID of the first repair symbol. raptorq pads blocks, so there is a gap after the last
+    /// source symbol.
+    first_repair: u32,
+    /// How many symbols were sent up to and including the last one received.
+    sent: u32,
+    received: u32,
+}
+
+impl BlockProgress {
+    fn new(source: u32) -> BlockProgress {
+        BlockProgress {
+            source,
+            first_repair: raptorq::extended_source_block_symbols(source),
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    /// Count a received symbol.
+    fn receive(&mut self, symbol: u32) {
+        // Senders send the source symbols and then the repair symbols in order
+        let position = if symbol < self.source {
+            symbol
+        } else {
+            self.source + symbol.saturating_sub(self.first_repair)
+        };
+        self.sent = self.sent.max(position.saturating_add(1));
+        self.received += 1;
+    }
+}
+
+/// A `Reconstructor` consumes a series of packets from one sender to reconstruct the message
+/// encoded within.
+///
+/// raptorq panics on packets that don't match the object it is decoding, so every packet is
+/// checked against its own header before it gets there.
+pub struct Reconstructor<const CHUNK: usize> {
+    /// Dropped once the message is finished, to free its memory.
+    decoder: Option<raptorq::Decoder>,
+    message_seq: u16,
+    total_len: u32,
+    packets_recvd: usize,
+    blocks: Vec<BlockProgress>,
+    /// How many times we've asked for repairs.
+    repair_requests: u8,
+}
+
+impl<const CHUNK: usize> Reconstructor<CHUNK> {
+    /// Create a new message reconstructor. The `header` argument is just used to set up some
+    /// decoder parameters. You should still call [`add_packet`](Self::add_packet) with its
+    /// packet after creating the reconstructor.
+    pub fn new(header: &Header) -> Result<Reconstructor<CHUNK>, FrameError> {
+        let config = object_config::<CHUNK>(header.total_len)?;
+        let [(large, large_blocks), (small, small_blocks)] = block_symbols(&config);
+        let blocks = (0..large_blocks)
+            .map(|_| BlockProgress::new(large))
+            .chain((0..small_blocks).map(|_| BlockProgress::new(small)))
+            .collect();
+        Ok(Reconstructor {
+            decoder: Some(raptorq::Decoder::new(config)),
+            message_seq: header.message_seq,
+            total_len: header.total_len,
+            packets_recvd: 0,
+            blocks,
+            repair_requests: 0,
+        })
+    }
+
+    /// Whether the message has been reconstructed.
+    pub fn finished(&self) -> bool {
+        self.decoder.is_none()
+    }
+
+    /// How many bytes of memory reconstructing the message takes, roughly. Nothing once it is
+    /// finished.
+    pub fn reserved(&self) -> usize {
+        if self.finished() {
+            0
+        } else {
+            self.total_len as usize
+        }
+    }
+
+    /// Is `header` from the message this reconstructor is working on?
+    fn is_for(&self, header: &Header) -> bool {
+        header.message_seq == self.message_seq && header.total_len == self.total_len
+    }
+
+    /// The percentage of this message's packets that were lost before the last one we
+    /// received, judging by their symbol IDs. `None` until a packet has been received.
+    pub fn loss(&self) -> Option<u8> {
+        let sent: u64 = self.blocks.iter().map(|b| u64::from(b.sent)).sum();
+        let received: u64 = self.blocks.iter().map(|b| u64::from(b.received)).sum();
+        if sent == 0 {
+            return None;
+        }
+        Some((100 - received.min(sent) * 100 / sent) as u8)
+    }
+
+    /// Ask `sender` for enough repair packets to finish decoding, if we're close enough to be
+    /// worth it and haven't asked too many times already.
+    fn repair_request(&mut self, sender: u16, requester: u16) -> Option<RepairRequest> {
+        if self.finished() || self.repair_requests >= MAX_REPAIR_REQUESTS {
+            return None;
+        }
+        let mut needed = 0;
+        for block in &self.blocks {
+            if block.received * 100 < block.source * REPAIR_THRESHOLD {
+                return None;
+            }
+            needed = needed.max((block.source + DECODE_MARGIN).saturating_sub(block.received));
+        }
+        // Enough should have arrived, but decoding can still fail now and then
+        let needed = needed.max(DECODE_MARGIN);
+        // The repair packets will be lost as often as the rest were
+        let loss = u32::from(self.loss().unwrap_or(0).min(MAX_LOSS));
+        let count = (needed * 100).div_ceil(100 - loss);
+        self.repair_requests += 1;
+        Some(RepairRequest {
+            sender,
+            requester,
+            message_seq: self.message_seq,
+            total_len: self.total_len,
+            count: count.min(MAX_REPAIR_COUNT.into()) as u16,
+        })
+    }
+
+    /// Log that we're giving up on the message, if it wasn't finished.
+    fn abandon(&self) {
+        if !self.finished() {
+            log::info!(
+                "reconstructor for message {} abandoned with {}/{} est. packets",
+                self.message_seq,
+                self.packets_recvd,
+                self.total_len.div_ceil(ChunkSize::<CHUNK>::U16.into())
+            );
+        }
+    }
+
+    /// Check that `packet` can be given to raptorq.
+    fn check(packet: &Packet) -> Result<(), FrameError> {
+        let config = object_config::<CHUNK>(packet.header.total_len)?;
+        // Every packet carries one full symbol, and the first byte of the payload ID is the
+        // source block number.
+        if packet.payload.len() != max_payload(CHUNK) || packet.payload[0] >= config.source_blocks()
+        {
+            return Err(FrameError::BadPayload);
+        }
+        Ok(())
+    }
+
+    /// Add a packet to the reconstructor. When enough packets are added to reproduce the
+    /// original message, this will return `Some(data)`. Until then it will return `None`.
+    /// Malformed packets are rejected without disturbing the message in progress.
+    pub fn add_packet(&mut self, packet: &Packet) -> Result<Option<Vec<u8>>, FrameError> {
+        Self::check(packet)?;
+        let header = &packet.header;
+        if !self.is_for(header) {
+            // sequence or length id different; this is a new packet sequence.
+            // Reset our state.
+            self.abandon();
+            *self = Reconstructor::new(header)?;
+        }
+        let Some(decoder) = &mut self.decoder else {
+            // We are done but this is part of a message we've already completed
+            return Ok(None);
+        };
+        self.packets_recvd += 1;
+        // The payload starts with the source block number and a 24-bit symbol ID
+        let block = usize::from(packet.payload[0]);
+        let symbol =
+            u32::from_be_bytes([0, packet.payload[1], packet.payload[2], packet.payload[3]]);
+        self.blocks[block].receive(symbol);
+        let done = decoder.decode(EncodingPacket::deserialize(&packet.payload));
+        if done.is_some() {
+            self.decoder = None;
+        }
+        Ok(done)
+    }
+}
+
+/// The most senders a [`Decoder`] reconstructs messages from at once. When a packet arrives
+/// from another sender, the sender we heard from least recently is forgotten.
+pub const MAX_SENDERS: usize = 16;
+/// The most messages a [`Decoder`] reconstructs from one sender at once. When a packet of
+/// another message arrives, the message from that sender we heard least recently is forgotten.
+pub const MAX_IN_FLIGHT: usize = 4;
+
+/// A message being reconstructed.
+struct InFlight<const CHUNK: usize> {
+    /// The packet count when this message was last added to.
+    used: u32,
+    /// Whether a packet arrived since the last [`Decoder::repair_requests`].
+    fresh: bool,
+    reconstructor: Reconstructor<CHUNK>,
+}
+
+/// What a [`Decoder`] knows about one sender.
+#[derive(Default)]
+struct SenderState<const CHUNK: usize> {
+    messages: Vec<InFlight<CHUNK>>,
+    /// Smoothed percentage of this sender's packets we lose.
+    loss: Option<u8>,
+    /// Whether we have reconstructed a message from this sender.
+    completed: bool,
+}
+
+impl<const CHUNK: usize> SenderState<CHUNK> {
+    /// Fold the loss measured over one of this sender's messages into our estimate.
+    fn record_loss(&mut self, sample: Option<u8>) {
+        let Some(sample) = sample else {
+            return;
+        };
+        self.loss = Some(match self.loss {
+            None => sample,
+            Some(loss) => ((u16::from(loss) * 3 + u16::from(sample)) / 4) as u8,
+        });
+    }
+}
+
+/// Reconstructs messages from the packets of any number of senders.
+///
+/// Packets are matched to messages by sequence number and length, so a sender can interleave
+/// the packets of up to [`MAX_IN_FLIGHT`] messages. Finished messages are remembered until
+/// they age out, so their leftover repair packets are ignored rather than starting over.
+///
+/// Space for a message is set aside when its first packet arrives, according to the length in
+/// the header, so devices short on memory should limit the length with
+/// [`with_max_len`](Self::with_max_len). That also limits the space set aside for all the
+/// messages under way at once. Anyone can send a first packet, so when a new message doesn't
+/// fit, messages from senders we haven't reconstructed anything from yet are given up first,
+/// and such a sender can't push out the messages of senders we have.
+pub struct Decoder<const CHUNK: usize> {
+    senders: BTreeMap<u16, SenderState<CHUNK>>,
+    /// Packets added so far, which is how we measure age.
+    packets: u32,
+    /// The longest message we'll reconstruct, and the most space we set aside for messages
+    /// under way.
+    max_len: usize,
+}
+
+impl<const CHUNK: usize> Decoder<CHUNK> {
+    /// A decoder for messages of up to [`max_message_len`] bytes.
+    pub const fn new() -> Decoder<CHUNK> {
+        Decoder::with_max_len(max_message_len(CHUNK))
+    }
+
+    /// A decoder that rejects packets of messages longer than `max_len` bytes with
+    /// [`FrameError::MessageTooLong`], and of messages that don't fit alongside the ones under
+    /// way with [`FrameError::NoRoom`].
+    pub const fn with_max_len(max_len: usize) -> Decoder<CHUNK> {
+        Decoder {
+            senders: BTreeMap::new(),
+            packets: 0,
+            max_len,
+        }
+    }
+
+    /// Change the longest message we'll start reconstructing, as when memory runs low. Messages
+    /// already under way are still finished.
+    pub fn set_max_len(&mut self, max_len: usize) {
+        self.max_len = max_len;
+    }
+
+    /// Add a packet. Returns the message it completes, if any.
+    pub fn add_packet(&mut self, packet: &Packet) -> Result<Option<Vec<u8>>, FrameError> {
+        let header = &packet.header;
+        self.packets = self.packets.wrapping_add(1);
+        let now = self.packets;
+        let age = |m: &InFlight<CHUNK>| now.wrapping_sub(m.used);
+
+        if let Some(state) = self.senders.get_mut(&header.sender) {
+            let found = state
+                .messages
+                .iter()
+                .position(|m| m.reconstructor.is_for(header));
+            if let Some(i) = found {
+                let m = &mut state.messages[i];
+                let result = m.reconstructor.add_packet(packet);
+                if let Ok(done) = &result {
+                    m.used = now;
+                    m.fresh = true;
+                    if done.is_some() {
+                        let sample = m.reconstructor.loss();
+                        state.record_loss(sample);
+                        state.completed = true;
+                    }
+                }
+                return result;
+            }
+        }
+
+        let len = header.total_len as usize;
+        if len > self.max_len {
+            return Err(FrameError::MessageTooLong(len));
+        }
+        // Check the packet before anything is given up for it
+        Reconstructor::<CHUNK>::check(packet)?;
+        self.make_room(header.sender, len)?;
+        let mut reconstructor = Reconstructor::new(header)?;
+        let result = reconstructor.add_packet(packet)?;
+        if !self.senders.contains_key(&header.sender) && self.senders.len() >= MAX_SENDERS {
+            // Senders we have reconstructed messages from are forgotten last
+            let stalest = self
+                .senders
+                .iter()
+                .max_by_key(|(_, state)| (!state.completed, state.messages.iter().map(age).min()))
+                .map(|(s, _)| *s);
+            if let Some(stalest) = stalest {
+                self.senders.remove(&stalest);
+            }
+        }
+        let state = self.senders.entry(header.sender).or_default();
+        if state.messages.len() >= MAX_IN_FLIGHT {
+            let stalest = (0..state.messages.len()).max_by_key(|i| age(&state.messages[*i]));
+            if let Some(stalest) = stalest {
+                let old = state.messages.swap_remove(stalest).reconstructor;
+                if !old.finished() {
+                    old.abandon();
+                    state.record_loss(old.loss());
+                }
+            }
+        }
+        if result.is_some() {
+            state.record_loss(reconstructor.loss());
+            state.completed = true;
+        }
+        state.messages.push(InFlight {
+            used: now,
+            fresh: true,
+            reconstructor,
+        });
+        Ok(result)
+    }
+
+    /// Give up unfinished messages, stalest first, until another `len` bytes fit in
+    /// [`max_len`](Self::with_max_len). Messages from senders we haven't reconstructed anything
+    /// from go first, and are all that a new message from such a `sender` may push out.
+    fn make_room(&mut self, sender: u16, len: usize) -> Result<(), FrameError> {
+        let known = self.senders.get(&sender).is_some_and(|s| s.completed);
+        let now = self.packets;
+        loop {
+            let reserved: usize = self
+                .senders
+                .values()
+                .flat_map(|state| &state.messages)
+                .map(|m| m.reconstructor.reserved())
+                .sum();
+            if reserved + len <= self.max_len {
+                return Ok(());
+            }
+            let stalest = self
+                .senders
+                .iter()
+                .filter(|(_, state)| known || !state.completed)
+                .flat_map(|(s, state)| {
+                    state
+                        .messages
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, m)| !m.reconstructor.finished())
+                        .map(move |(i, m)| ((!state.completed, now.wrapping_sub(m.used)), *s, i))
+                })
+                .max_by_key(|(priority, _, _)| *priority);
+            let Some((_, s, i)) = stalest else {
+                return Err(FrameError::NoRoom(len));
+            };
+            let Some(state) = self.senders.get_mut(&s) else {
+                return Err(FrameError::NoRoom(len));
+            };
+            let old = state.messages.swap_remove(i).reconstructor;
+            old.abandon();
+            state.record_loss(old.loss());
+            if state.messages.is_empty() && !state.completed {
+                self.senders.remove(&s);
+            }
+        }
+    }
+
+    /// Ask for the repair packets we need to finish stalled messages, on behalf of
+    /// `requester`. Call this periodically, a bit less often than packets of a message
+    /// usually arrive. A message is stalled if none of its packets arrived since the last
+    /// call. Each message is only asked about [`MAX_REPAIR_REQUESTS`] times, and only once
+    /// most of it has arrived.
+    pub fn repair_requests(&mut self, requester: u16) -> Vec<RepairRequest> {
+        let mut requests = Vec::new();
+        for (sender, state) in &mut self.senders {
+            for m in &mut state.messages {
+                if core::mem::take(&mut m.fresh) {
+                    continue;
+                }
+                requests.extend(m.reconstructor.repair_request(*sender, requester));
+            }
+        }
+        requests
+    }
+
+    /// Our estimate of the percentage of `sender`'s packets we lose, smoothed over its recent
+    /// messages. `None` if we haven't heard enough from it.
+    pub fn loss(&self, sender: u16) -> Option<u8> {
+        self.senders.get(&sender).and_then(|state| state.loss)
+    }
+}
+
+impl<const CHUNK: usize> Default for Decoder<CHUNK> {
+    fn default() -> Self {
+        Decoder::new()
+    }
+}
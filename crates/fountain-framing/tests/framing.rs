@@ -0,0 +1,663 @@
+use fountain_framing::{
+    max_frame_size, max_message_len, send_delay_ms, Decoder, Encoder, EncoderCache, FecConfig,
+    FrameError, LossReports, Packet, RepairRequest, StreamReader, LOSS_REPORT_LIFETIME_MS, MAGIC,
+    MAX_BLOCK_SYMBOLS, MAX_IN_FLIGHT, MAX_REPAIR_COUNT, MAX_REPAIR_REQUESTS, MAX_SENDERS,
+};
+
+const CHUNK: usize = 64;
+
+/// A message that isn't just a repeating pattern.
+fn message(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+        .collect()
+}
+
+/// Encode a message into frames.
+fn frames(sender: u16, seq: u16, msg: &[u8]) -> Vec<Vec<u8>> {
+    Encoder::<CHUNK>::new(0, sender, seq, msg)
+        .unwrap()
+        .map(|packet| {
+            let mut buf = vec![0u8; max_frame_size(CHUNK)];
+            let len = packet.encode(&mut buf).unwrap();
+            buf.truncate(len);
+            buf
+        })
+        .collect()
+}
+
+/// Add frames to `decoder` until a message comes out.
+fn add_frames(decoder: &mut Decoder<CHUNK>, frames: &[Vec<u8>]) -> Option<Vec<u8>> {
+    frames.iter().find_map(|frame| {
+        let packet = Packet::decode::<CHUNK>(frame).unwrap();
+        decoder.add_packet(&packet).unwrap()
+    })
+}
+
+/// Decode frames until a message comes out.
+fn reassemble<'a>(frames: impl IntoIterator<Item = &'a Vec<u8>>) -> Option<Vec<u8>> {
+    let mut decoder = Decoder::<CHUNK>::new();
+    frames.into_iter().find_map(|frame| {
+        let packet = Packet::decode::<CHUNK>(frame).unwrap();
+        decoder.add_packet(&packet).unwrap()
+    })
+}
+
+#[test]
+fn round_trip() {
+    for len in [1, 63, 64, 65, 500, 2048] {
+        let msg = message(len, 7);
+        let frames = frames(1, 1, &msg);
+        assert!(frames.iter().all(|f| f.len() <= max_frame_size(CHUNK)));
+        assert!(frames.iter().all(|f| f[..3] == MAGIC));
+        assert_eq!(reassemble(&frames), Some(msg), "length {len}");
+    }
+}
+
+#[test]
+fn header_survives_encoding() {
+    let packet = Encoder::<CHUNK>::new(0x1234, 0xABCD, 42, &message(300, 1))
+        .unwrap()
+        .next()
+        .unwrap();
+    let mut buf = [0u8; max_frame_size(CHUNK)];
+    let len = packet.encode(&mut buf).unwrap();
+    assert_eq!(len, packet.frame_len());
+    let decoded = Packet::decode::<CHUNK>(&buf[..len]).unwrap();
+    assert_eq!(decoded, packet);
+    assert_eq!(decoded.header.recipient, 0x1234);
+    assert_eq!(decoded.header.sender, 0xABCD);
+    assert_eq!(decoded.header.message_seq, 42);
+    assert_eq!(decoded.header.total_len, 300);
+}
+
+#[test]
+fn survives_lost_packets() {
+    let msg = message(2000, 3);
+    let frames = frames(1, 1, &msg);
+    // Lose every fifth packet
+    let kept = frames
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| i % 5 != 0)
+        .map(|(_, f)| f);
+    assert_eq!(reassemble(kept), Some(msg));
+}
+
+#[test]
+fn damaged_frames_are_rejected() {
+    let frame = frames(1, 1, &message(100, 0)).remove(0);
+    for i in 0..frame.len() {
+        let mut damaged = frame.clone();
+        damaged[i] ^= 0x10;
+        let result = Packet::decode::<CHUNK>(&damaged);
+        assert!(result.is_err(), "flipped bit in byte {i} went unnoticed");
+    }
+    assert_eq!(
+        Packet::decode::<CHUNK>(&frame[..frame.len() - 1]),
+        Err(FrameError::Truncated)
+    );
+    assert_eq!(
+        Packet::decode::<CHUNK>(&[0, 1, 2, 3]),
+        Err(FrameError::BadMagic)
+    );
+}
+
+#[test]
+fn oversized_chunks_are_rejected() {
+    let mut frame = frames(1, 1, &message(100, 0)).remove(0);
+    // chunk_len is bytes 9 and 10
+    frame[9..11].copy_from_slice(&u16::MAX.to_be_bytes());
+    assert_eq!(
+        Packet::decode::<CHUNK>(&frame),
+        Err(FrameError::Oversized(u16::MAX))
+    );
+}
+
+#[test]
+fn interleaved_senders() {
+    let a = message(700, 1);
+    let b = message(900, 2);
+    let fa = frames(1, 1, &a);
+    let fb = frames(2, 1, &b);
+    let mut decoder = Decoder::<CHUNK>::new();
+    let mut done = Vec::new();
+    for i in 0..fa.len().max(fb.len()) {
+        for f in [fa.get(i), fb.get(i)].into_iter().flatten() {
+            let packet = Packet::decode::<CHUNK>(f).unwrap();
+            if let Some(msg) = decoder.add_packet(&packet).unwrap() {
+                done.push((packet.header.sender, msg));
+            }
+        }
+    }
+    done.sort();
+    assert_eq!(done, vec![(1, a), (2, b)]);
+}
+
+#[test]
+fn interleaved_messages_from_one_sender() {
+    // A hello queued up while a long response is going out
+    let response = message(1500, 1);
+    let hello = message(80, 2);
+    let fr = frames(1, 1, &response);
+    let fh = frames(1, 2, &hello);
+    let mut decoder = Decoder::<CHUNK>::new();
+    let mut done = Vec::new();
+    for i in 0..fr.len().max(fh.len()) {
+        for f in [fr.get(i), fh.get(i)].into_iter().flatten() {
+            let packet = Packet::decode::<CHUNK>(f).unwrap();
+            if let Some(msg) = decoder.add_packet(&packet).unwrap() {
+                done.push(msg);
+            }
+        }
+    }
+    assert_eq!(done, vec![hello, response]);
+}
+
+#[test]
+fn stalest_message_is_forgotten() {
+    let msg = message(1000, 1);
+    let first = frames(1, 0, &msg);
+    // Half of the first message arrives, then other messages start
+    let interrupted = |others: u16| {
+        let mut decoder = Decoder::<CHUNK>::new();
+        assert_eq!(add_frames(&mut decoder, &first[..10]), None);
+        for seq in 1..=others {
+            let other = frames(1, seq, &message(100, seq as u8));
+            assert_eq!(add_frames(&mut decoder, &other[..1]), None);
+        }
+        add_frames(&mut decoder, &first[10..20])
+    };
+    assert_eq!(interrupted(MAX_IN_FLIGHT as u16 - 1), Some(msg));
+    // Once it's pushed out, the second half isn't enough on its own
+    assert_eq!(interrupted(MAX_IN_FLIGHT as u16), None);
+}
+
+#[test]
+fn completed_message_is_returned_once() {
+    let msg = message(200, 5);
+    let frames = frames(1, 9, &msg);
+    let mut decoder = Decoder::<CHUNK>::new();
+    let done: Vec<_> = frames
+        .iter()
+        .filter_map(|f| {
+            decoder
+                .add_packet(&Packet::decode::<CHUNK>(f).unwrap())
+                .unwrap()
+        })
+        .collect();
+    assert_eq!(done, vec![msg]);
+}
+
+#[test]
+fn next_message_resets_reconstructor() {
+    let first = message(1000, 1);
+    let second = message(1000, 2);
+    // Only part of the first message arrives
+    let partial = frames(1, 1, &first).into_iter().take(3);
+    let all: Vec<_> = partial.chain(frames(1, 2, &second)).collect();
+    assert_eq!(reassemble(&all), Some(second));
+}
+
+#[test]
+fn stream_reader_finds_frames_in_noise() {
+    let msg = message(500, 4);
+    let frames = frames(1, 1, &msg);
+    let mut stream = Vec::new();
+    for (i, frame) in frames.iter().enumerate() {
+        // Garbage between frames, including partial magic
+        stream.extend_from_slice(&[0x00, 0xF0, 0x55, 0x0F, 0xAA][..i % 6]);
+        stream.extend_from_slice(frame);
+    }
+    let mut reader = StreamReader::<CHUNK>::new();
+    let packets: Vec<_> = stream.iter().filter_map(|b| reader.push(*b)).collect();
+    assert_eq!(packets.len(), frames.len());
+    let mut decoder = Decoder::<CHUNK>::new();
+    let done = packets
+        .into_iter()
+        .find_map(|p| decoder.add_packet(&p.unwrap()).unwrap());
+    assert_eq!(done, Some(msg));
+}
+
+#[test]
+fn stream_reader_recovers_after_bad_frame() {
+    let frames = frames(1, 1, &message(300, 6));
+    let mut damaged = frames[0].clone();
+    damaged[20] ^= 0xFF;
+    let mut reader = StreamReader::<CHUNK>::new();
+    let results: Vec<_> = damaged
+        .iter()
+        .chain(&frames[1])
+        .filter_map(|b| reader.push(*b))
+        .collect();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0], Err(FrameError::BadCrc));
+    assert_eq!(results[1], Ok(Packet::decode::<CHUNK>(&frames[1]).unwrap()));
+}
+
+#[test]
+fn long_messages_are_refused() {
+    let len = max_message_len(CHUNK) + 1;
+    assert!(matches!(
+        Encoder::<CHUNK>::new(0, 1, 1, &vec![0; len]),
+        Err(FrameError::MessageTooLong(l)) if l == len
+    ));
+}
+
+/// The source blocks the packets of a message are spread over.
+fn source_blocks(packets: &[Packet]) -> usize {
+    packets
+        .iter()
+        .map(|p| usize::from(p.payload[0]) + 1)
+        .max()
+        .unwrap_or(0)
+}
+
+/// The ID of the symbol a packet carries within its source block.
+fn symbol_id(packet: &Packet) -> u32 {
+    let p = &packet.payload;
+    u32::from_be_bytes([0, p[1], p[2], p[3]])
+}
+
+#[test]
+fn long_messages_are_split_into_blocks() {
+    for len in [
+        MAX_BLOCK_SYMBOLS * CHUNK,
+        MAX_BLOCK_SYMBOLS * CHUNK + 1,
+        100_000,
+    ] {
+        let packets: Vec<_> = Encoder::<CHUNK>::new(0, 1, 1, &message(len, 3))
+            .unwrap()
+            .collect();
+        let symbols = len.div_ceil(CHUNK);
+        assert_eq!(
+            source_blocks(&packets),
+            symbols.div_ceil(MAX_BLOCK_SYMBOLS),
+            "length {len}"
+        );
+        assert!(packets.iter().all(|p| p.header.total_len == len as u32));
+    }
+}
+
+#[test]
+fn messages_over_64k_round_trip() {
+    let msg = message(100_000, 12);
+    let frames = frames(1, 1, &msg);
+    // Lose one packet in four, across every block
+    let lossy: Vec<_> = frames
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| i % 4 != 0)
+        .map(|(_, f)| f)
+        .collect();
+    assert_eq!(reassemble(lossy), Some(msg));
+}
+
+#[test]
+fn stream_reader_reassembles_multi_block_messages() {
+    let msg = message(70_000, 13);
+    let frames = frames(1, 1, &msg);
+    let mut reader = StreamReader::<CHUNK>::new();
+    let mut decoder = Decoder::<CHUNK>::new();
+    let done = frames
+        .iter()
+        .flatten()
+        .filter_map(|b| reader.push(*b))
+        .find_map(|packet| decoder.add_packet(&packet.unwrap()).unwrap());
+    assert_eq!(done, Some(msg));
+}
+
+#[test]
+fn repair_is_per_block() {
+    // Every block gets enough repair packets for its own size, not the whole message's
+    let encoder = Encoder::<CHUNK>::new(0, 1, 1, &message(100_000, 0)).unwrap();
+    let packets = encoder.len();
+    let symbols = 100_000usize.div_ceil(CHUNK);
+    let repair = packets - symbols;
+    let blocks = symbols.div_ceil(MAX_BLOCK_SYMBOLS);
+    let per_block = FecConfig::DEFAULT.repair_packets(MAX_BLOCK_SYMBOLS as u32, None) as usize;
+    assert!(repair <= blocks * per_block, "{repair} repair packets");
+}
+
+#[test]
+fn repair_requests_finish_multi_block_messages() {
+    let msg = message(100_000, 14);
+    let mut encoder = Encoder::<CHUNK>::new(0, 1, 6, &msg).unwrap();
+    // Every block is short a few source packets, and no repair packets arrive
+    let lossy: Vec<_> = encoder
+        .by_ref()
+        .filter(|p| symbol_id(p) < 200 && symbol_id(p) % 10 != 9)
+        .collect();
+    let blocks = source_blocks(&lossy);
+    assert_eq!(
+        blocks,
+        100_000usize.div_ceil(CHUNK).div_ceil(MAX_BLOCK_SYMBOLS)
+    );
+    let mut cache = EncoderCache::new();
+    cache.insert(encoder);
+    cache.add_peer(2);
+
+    let mut decoder = Decoder::<CHUNK>::new();
+    for packet in &lossy {
+        assert_eq!(decoder.add_packet(packet).unwrap(), None);
+    }
+    decoder.repair_requests(2);
+    let requests = decoder.repair_requests(2);
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].total_len, 100_000);
+
+    // Every block is repaired
+    let answer = cache.answer(&requests[0]);
+    assert_eq!(source_blocks(&answer), blocks);
+    let done = answer.iter().find_map(|p| decoder.add_packet(p).unwrap());
+    assert_eq!(done, Some(msg));
+}
+
+#[test]
+fn decoders_can_limit_message_length() {
+    let packet = Encoder::<CHUNK>::new(0, 1, 1, &message(70_000, 0))
+        .unwrap()
+        .next()
+        .unwrap();
+    let mut decoder = Decoder::<CHUNK>::with_max_len(u16::MAX as usize);
+    assert_eq!(
+        decoder.add_packet(&packet),
+        Err(FrameError::MessageTooLong(70_000))
+    );
+    let packet = Encoder::<CHUNK>::new(0, 1, 2, &message(60_000, 0))
+        .unwrap()
+        .next()
+        .unwrap();
+    assert_eq!(decoder.add_packet(&packet), Ok(None));
+}
+
+/// The first packet of a message.
+fn first_packet(sender: u16, seq: u16, len: usize) -> Packet {
+    Encoder::<CHUNK>::new(0, sender, seq, &message(len, 0))
+        .unwrap()
+        .next()
+        .unwrap()
+}
+
+#[test]
+fn new_messages_push_out_stale_ones_when_full() {
+    let mut decoder = Decoder::<CHUNK>::with_max_len(10_000);
+    let first = frames(1, 1, &message(6_000, 1));
+    assert_eq!(add_frames(&mut decoder, &first[..60]), None);
+    // Both can't fit at once, so the first is given up
+    let msg = message(6_000, 2);
+    assert_eq!(add_frames(&mut decoder, &frames(2, 1, &msg)), Some(msg));
+    // And the rest of it isn't enough on its own
+    assert_eq!(add_frames(&mut decoder, &first[60..100]), None);
+}
+
+#[test]
+fn new_senders_cannot_push_out_known_ones() {
+    let mut decoder = Decoder::<CHUNK>::with_max_len(10_000);
+    let hello = message(100, 1);
+    assert_eq!(add_frames(&mut decoder, &frames(1, 1, &hello)), Some(hello));
+    let msg = message(8_000, 2);
+    let frames = frames(1, 2, &msg);
+    assert_eq!(add_frames(&mut decoder, &frames[..1]), None);
+    // Anyone can claim to be starting a long message
+    assert_eq!(
+        decoder.add_packet(&first_packet(2, 1, 5_000)),
+        Err(FrameError::NoRoom(5_000))
+    );
+    assert_eq!(add_frames(&mut decoder, &frames[1..]), Some(msg));
+}
+
+#[test]
+fn finished_messages_take_no_room() {
+    let mut decoder = Decoder::<CHUNK>::with_max_len(10_000);
+    let msg = message(8_000, 1);
+    assert_eq!(add_frames(&mut decoder, &frames(1, 1, &msg)), Some(msg));
+    assert_eq!(decoder.add_packet(&first_packet(2, 1, 8_000)), Ok(None));
+}
+
+#[test]
+fn known_senders_are_forgotten_last() {
+    let mut decoder = Decoder::<CHUNK>::new();
+    let hello = message(100, 1);
+    assert_eq!(add_frames(&mut decoder, &frames(1, 1, &hello)), Some(hello));
+    assert_eq!(decoder.loss(1), Some(0));
+    // More new senders than we track start messages after it
+    for sender in 2..MAX_SENDERS as u16 * 2 {
+        assert_eq!(decoder.add_packet(&first_packet(sender, 1, 500)), Ok(None));
+    }
+    assert_eq!(decoder.loss(1), Some(0));
+}
+
+#[test]
+fn loss_reports_age_out() {
+    let mut reports = LossReports::new();
+    reports.insert(1, 30, 0);
+    reports.insert(2, 10, 1_000);
+    assert_eq!(reports.get(1, 1_000), Some(30));
+    assert_eq!(reports.worst(1_000), Some(30));
+    // The first report is too old to trust, but the second isn't yet
+    let later = LOSS_REPORT_LIFETIME_MS;
+    assert_eq!(reports.get(1, later), None);
+    assert_eq!(reports.get(2, later), Some(10));
+    assert_eq!(reports.worst(later), Some(10));
+    assert_eq!(reports.worst(later + 1_000), None);
+}
+
+#[test]
+fn oldest_loss_report_is_forgotten() {
+    let mut reports = LossReports::new();
+    // The highest address reports first, so it's the one forgotten
+    for (at, peer) in (1..=MAX_SENDERS as u16 + 1).rev().enumerate() {
+        reports.insert(peer, 20, at as u64);
+    }
+    assert_eq!(reports.get(MAX_SENDERS as u16 + 1, 100), None);
+    assert!((1..=MAX_SENDERS as u16).all(|peer| reports.get(peer, 100) == Some(20)));
+}
+
+#[test]
+fn tiny_messages_get_repair_packets() {
+    let encoder = Encoder::<CHUNK>::new(0, 1, 1, &message(10, 0)).unwrap();
+    assert_eq!(encoder.len(), 1 + FecConfig::DEFAULT.min_repair as usize);
+}
+
+#[test]
+fn repair_scales_with_loss_within_bounds() {
+    let fec = FecConfig::DEFAULT;
+    let repair: Vec<_> = (0..=100)
+        .map(|loss| fec.repair_packets(100, Some(loss)))
+        .collect();
+    assert!(repair.windows(2).all(|w| w[0] <= w[1]));
+    assert_eq!(repair[0], 100 * fec.min_overhead as u32 / 100);
+    assert_eq!(repair[100], 100 * fec.max_overhead as u32 / 100);
+    // 30% loss needs 102 / 0.7 = 146 packets sent
+    assert_eq!(repair[30], 46);
+    // Not knowing is more cautious than knowing the link is good
+    assert!(fec.repair_packets(100, None) > repair[5]);
+}
+
+#[test]
+fn decoder_measures_loss() {
+    let msg = message(3000, 9);
+    let mut decoder = Decoder::<CHUNK>::new();
+    assert_eq!(decoder.loss(1), None);
+    assert_eq!(
+        add_frames(&mut decoder, &frames(1, 1, &msg)),
+        Some(msg.clone())
+    );
+    assert_eq!(decoder.loss(1), Some(0));
+
+    // Lose every fourth packet
+    let lossy: Vec<_> = frames(1, 2, &msg)
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| i % 4 != 3)
+        .map(|(_, f)| f)
+        .collect();
+    let mut decoder = Decoder::<CHUNK>::new();
+    assert_eq!(add_frames(&mut decoder, &lossy), Some(msg));
+    let loss = decoder.loss(1).unwrap();
+    assert!((20..=30).contains(&loss), "measured {loss}% loss");
+}
+
+#[test]
+fn repair_requests_finish_stalled_messages() {
+    let msg = message(2000, 10);
+    let mut encoder = Encoder::<CHUNK>::new(0, 1, 5, &msg).unwrap();
+    // Lose two packets in three, more than the repair packets allow for
+    let lossy: Vec<_> = encoder.by_ref().step_by(3).collect();
+    let mut cache = EncoderCache::new();
+    cache.insert(encoder);
+    cache.add_peer(2);
+
+    let mut decoder = Decoder::<CHUNK>::new();
+    for packet in &lossy {
+        assert_eq!(decoder.add_packet(packet).unwrap(), None);
+    }
+    // Packets just arrived, so the message isn't stalled yet
+    assert_eq!(decoder.repair_requests(2), vec![]);
+    let requests = decoder.repair_requests(2);
+    assert_eq!(requests.len(), 1);
+    let request = requests[0];
+    assert_eq!((request.sender, request.requester), (1, 2));
+    assert_eq!(request.message_seq, 5);
+
+    // The request survives the trip to the sender
+    let packet = request.to_packet();
+    assert_eq!(packet.header.recipient, 1);
+    let mut buf = [0u8; max_frame_size(CHUNK)];
+    let len = packet.encode(&mut buf).unwrap();
+    let packet = Packet::decode::<CHUNK>(&buf[..len]).unwrap();
+    assert_eq!(RepairRequest::from_packet(&packet), Some(request));
+
+    // The answer is just as lossy, and that should have been allowed for
+    let answer = cache.answer(&request);
+    assert!(!answer.is_empty());
+    let done = answer
+        .iter()
+        .step_by(3)
+        .find_map(|p| decoder.add_packet(p).unwrap());
+    assert_eq!(done, Some(msg));
+    assert_eq!(decoder.repair_requests(2), vec![]);
+}
+
+#[test]
+fn data_packets_are_not_repair_requests() {
+    let mut encoder = Encoder::<CHUNK>::new(0, 1, 1, &message(10, 0)).unwrap();
+    assert!(encoder.all(|p| RepairRequest::from_packet(&p).is_none()));
+}
+
+#[test]
+fn repair_requests_are_limited() {
+    let msg = message(2000, 11);
+    let packets: Vec<_> = Encoder::<CHUNK>::new(0, 1, 1, &msg).unwrap().collect();
+    let mut decoder = Decoder::<CHUNK>::new();
+    // Most of the message arrives, but the repairs never do
+    for packet in packets.iter().step_by(3) {
+        decoder.add_packet(packet).unwrap();
+    }
+    decoder.repair_requests(2);
+    let asked = (0..10)
+        .map(|_| decoder.repair_requests(2).len())
+        .sum::<usize>();
+    assert_eq!(asked, MAX_REPAIR_REQUESTS as usize);
+}
+
+#[test]
+fn no_repair_requests_for_barely_started_messages() {
+    let msg = message(2000, 12);
+    let packets: Vec<_> = Encoder::<CHUNK>::new(0, 1, 1, &msg).unwrap().collect();
+    let mut decoder = Decoder::<CHUNK>::new();
+    for packet in &packets[..5] {
+        decoder.add_packet(packet).unwrap();
+    }
+    decoder.repair_requests(2);
+    assert_eq!(decoder.repair_requests(2), vec![]);
+}
+
+#[test]
+fn forgotten_messages_are_not_repaired() {
+    let mut cache = EncoderCache::<CHUNK>::new();
+    cache.insert(Encoder::new(0, 1, 1, &message(500, 0)).unwrap());
+    let request = RepairRequest {
+        sender: 1,
+        requester: 2,
+        message_seq: 2,
+        total_len: 500,
+        count: 4,
+    };
+    cache.add_peer(2);
+    assert_eq!(cache.answer(&request), vec![]);
+}
+
+#[test]
+fn repairs_are_only_sent_to_peers() {
+    let mut cache = EncoderCache::<CHUNK>::new();
+    cache.insert(Encoder::new(0, 1, 1, &message(2000, 0)).unwrap());
+    let request = RepairRequest {
+        sender: 1,
+        requester: 2,
+        message_seq: 1,
+        total_len: 2000,
+        count: 4,
+    };
+    assert_eq!(cache.answer(&request), vec![]);
+    cache.add_peer(3);
+    assert_eq!(cache.answer(&request), vec![]);
+    cache.add_peer(2);
+    assert_eq!(cache.answer(&request).len(), 4);
+}
+
+#[test]
+fn repairs_to_a_message_are_limited() {
+    let mut cache = EncoderCache::<CHUNK>::new();
+    cache.insert(Encoder::new(0, 1, 1, &message(2000, 0)).unwrap());
+    cache.add_peer(2);
+    cache.add_peer(3);
+    let mut request = RepairRequest {
+        sender: 1,
+        requester: 2,
+        message_seq: 1,
+        total_len: 2000,
+        count: 4,
+    };
+    // Twice what was asked for, however often it is asked
+    let answered: Vec<_> = (0..=MAX_REPAIR_REQUESTS)
+        .map(|_| cache.answer(&request).len())
+        .collect();
+    assert_eq!(answered, [4, 4, 0, 0]);
+    // Another peer asking doesn't get any more
+    request.requester = 3;
+    assert_eq!(cache.answer(&request), vec![]);
+}
+
+#[test]
+fn repairs_are_a_fraction_of_what_was_sent() {
+    let mut encoder = Encoder::<CHUNK>::new(0, 1, 1, &message(2000, 0)).unwrap();
+    let sent = encoder.by_ref().count();
+    let mut cache = EncoderCache::new();
+    cache.insert(encoder);
+    let answered: usize = (2..20)
+        .map(|requester| {
+            cache.add_peer(requester);
+            cache
+                .answer(&RepairRequest {
+                    sender: 1,
+                    requester,
+                    message_seq: 1,
+                    total_len: 2000,
+                    count: MAX_REPAIR_COUNT,
+                })
+                .len()
+        })
+        .sum();
+    assert!(answered > 0);
+    assert!(answered <= sent / 2);
+}
+
+#[test]
+fn send_delay_is_bounded_and_varies() {
+    let frames = frames(1, 1, &message(2000, 8));
+    let delays: Vec<_> = frames.iter().map(|f| send_delay_ms(f)).collect();
+    assert!(delays.iter().all(|d| (25..125).contains(d)));
+    assert!(delays.iter().any(|d| *d != delays[0]));
+}
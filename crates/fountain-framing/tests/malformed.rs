@@ -0,0 +1,237 @@
+//! Nothing that arrives over the air should be able to panic the decoder. These feed it
+//! arbitrary and damaged input through every decode path.
+
+use fountain_framing::{
+    max_frame_size, max_message_len, max_payload, Decoder, Encoder, EncoderCache, FrameError,
+    Header, Packet, RepairRequest, StreamReader, MAX_SENDERS,
+};
+use proptest::prelude::*;
+
+const CHUNK: usize = 64;
+
+fn encode(packet: &Packet) -> Vec<u8> {
+    let mut buf = vec![0u8; max_frame_size(CHUNK)];
+    let len = packet.encode(&mut buf).unwrap();
+    buf.truncate(len);
+    buf
+}
+
+fn frames(msg: &[u8]) -> Vec<Vec<u8>> {
+    Encoder::<CHUNK>::new(0, 1, 1, msg)
+        .unwrap()
+        .map(|p| encode(&p))
+        .collect()
+}
+
+prop_compose! {
+    fn arb_header()(
+        recipient in any::<u16>(),
+        sender in 0..4u16,
+        message_seq in 0..3u16,
+        total_len in prop_oneof![
+            Just(0u32),
+            1..300u32,
+            300..max_message_len(CHUNK) as u32 + 2,
+            any::<u32>(),
+        ],
+    ) -> Header {
+        Header { recipient, sender, message_seq, chunk_len: 0, total_len }
+    }
+}
+
+prop_compose! {
+    /// A packet that passes the frame checks, but whose contents are arbitrary.
+    fn arb_packet()(
+        header in arb_header(),
+        payload in prop_oneof![
+            prop::collection::vec(any::<u8>(), 0..=max_payload(CHUNK)),
+            prop::collection::vec(any::<u8>(), max_payload(CHUNK)),
+        ],
+    ) -> Packet {
+        let header = Header { chunk_len: payload.len() as u16, ..header };
+        Packet { header, payload }
+    }
+}
+
+proptest! {
+    #[test]
+    fn decode_arbitrary_bytes(data in prop::collection::vec(any::<u8>(), 0..200)) {
+        let _ = Packet::decode::<CHUNK>(&data);
+    }
+
+    #[test]
+    fn decode_damaged_frames(
+        len in 1..500usize,
+        which in any::<prop::sample::Index>(),
+        damage in prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..4),
+        cut in any::<prop::sample::Index>(),
+    ) {
+        let frames = frames(&vec![0x5A; len]);
+        let mut frame = which.get(&frames).clone();
+        for (at, byte) in damage {
+            let i = at.index(frame.len());
+            frame[i] = byte;
+        }
+        frame.truncate(cut.index(frame.len() + 1));
+        if let Ok(packet) = Packet::decode::<CHUNK>(&frame) {
+            // Anything accepted must be exactly what we parsed
+            prop_assert_eq!(encode(&packet), &frame[..packet.frame_len()]);
+            let _ = Decoder::<CHUNK>::new().add_packet(&packet);
+        }
+    }
+
+    #[test]
+    fn stream_reader_arbitrary_bytes(data in prop::collection::vec(any::<u8>(), 0..1000)) {
+        let mut reader = StreamReader::<CHUNK>::new();
+        let mut decoder = Decoder::<CHUNK>::new();
+        for byte in data {
+            if let Some(Ok(packet)) = reader.push(byte) {
+                let _ = decoder.add_packet(&packet);
+            }
+        }
+    }
+
+    #[test]
+    fn stream_reader_with_noise(
+        len in 1..300usize,
+        noise in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..20), 1..10),
+    ) {
+        let frames = frames(&vec![0xA5; len]);
+        let mut reader = StreamReader::<CHUNK>::new();
+        let mut decoder = Decoder::<CHUNK>::new();
+        for (frame, noise) in frames.iter().zip(noise.iter().cycle()) {
+            for byte in noise.iter().chain(frame) {
+                if let Some(Ok(packet)) = reader.push(*byte) {
+                    let _ = decoder.add_packet(&packet);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decoder_arbitrary_packets(packets in prop::collection::vec(arb_packet(), 1..40)) {
+        let mut decoder = Decoder::<CHUNK>::new();
+        for packet in &packets {
+            let _ = decoder.add_packet(packet);
+        }
+    }
+
+    #[test]
+    fn bad_packets_do_not_disturb_reconstruction(
+        len in 1..1000usize,
+        junk in prop::collection::vec(arb_packet(), 0..10),
+    ) {
+        let msg: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let good: Vec<Packet> = Encoder::<CHUNK>::new(0, 1, 1, &msg).unwrap().collect();
+        let mut decoder = Decoder::<CHUNK>::new();
+        let mut done = None;
+        for (i, packet) in good.iter().enumerate() {
+            // Slip junk claiming to be from the same sender and message in between
+            if let Some(junk) = junk.get(i) {
+                let mut junk = junk.clone();
+                junk.header.sender = 1;
+                junk.header.message_seq = 1;
+                junk.header.total_len = len as u32;
+                junk.payload.truncate(max_payload(CHUNK) - 1);
+                prop_assert_eq!(decoder.add_packet(&junk), Err(FrameError::BadPayload));
+            }
+            if let Some(m) = decoder.add_packet(packet).unwrap() {
+                done = Some(m);
+                break;
+            }
+        }
+        prop_assert_eq!(done, Some(msg));
+    }
+
+    #[test]
+    fn arbitrary_repair_requests(packets in prop::collection::vec(arb_packet(), 1..20)) {
+        let mut cache = EncoderCache::<CHUNK>::new();
+        cache.insert(Encoder::new(0, 1, 1, &[7; 200]).unwrap());
+        for packet in &packets {
+            if let Some(request) = RepairRequest::from_packet(packet) {
+                cache.add_peer(request.requester);
+                let _ = cache.answer(&request);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip(msg in prop::collection::vec(any::<u8>(), 1..3000)) {
+        let mut decoder = Decoder::<CHUNK>::new();
+        let done = frames(&msg).iter().find_map(|f| {
+            decoder.add_packet(&Packet::decode::<CHUNK>(f).unwrap()).unwrap()
+        });
+        prop_assert_eq!(done, Some(msg));
+    }
+}
+
+#[test]
+fn empty_messages_are_rejected() {
+    assert!(matches!(
+        Encoder::<CHUNK>::new(0, 1, 1, &[]),
+        Err(FrameError::EmptyMessage)
+    ));
+    let mut packet = Encoder::<CHUNK>::new(0, 1, 1, &[1, 2, 3])
+        .unwrap()
+        .next()
+        .unwrap();
+    packet.header.total_len = 0;
+    assert_eq!(
+        Decoder::<CHUNK>::new().add_packet(&packet),
+        Err(FrameError::EmptyMessage)
+    );
+}
+
+#[test]
+fn short_payloads_are_rejected() {
+    for len in [0, 1, 3, 4, max_payload(CHUNK) - 1] {
+        let packet = Packet {
+            header: Header {
+                recipient: 0,
+                sender: 1,
+                message_seq: 1,
+                chunk_len: len as u16,
+                total_len: 100,
+            },
+            payload: vec![0; len],
+        };
+        assert_eq!(
+            Decoder::<CHUNK>::new().add_packet(&packet),
+            Err(FrameError::BadPayload)
+        );
+    }
+}
+
+#[test]
+fn unknown_source_blocks_are_rejected() {
+    let mut packet = Encoder::<CHUNK>::new(0, 1, 1, &[7; 100])
+        .unwrap()
+        .next()
+        .unwrap();
+    packet.payload[0] = 5;
+    assert_eq!(
+        Decoder::<CHUNK>::new().add_packet(&packet),
+        Err(FrameError::BadPayload)
+    );
+}
+
+#[test]
+fn senders_are_bounded() {
+    let msg = [9u8; 500];
+    let mut decoder = Decoder::<CHUNK>::new();
+    // Start a message from more senders than we track
+    for sender in 0..MAX_SENDERS as u16 * 2 {
+        let packet = Encoder::<CHUNK>::new(0, sender, 1, &msg)
+            .unwrap()
+            .next()
+            .unwrap();
+        assert_eq!(decoder.add_packet(&packet), Ok(None));
+    }
+    // The most recent senders can still finish
+    let last = MAX_SENDERS as u16 * 2 - 1;
+    let done = Encoder::<CHUNK>::new(0, last, 1, &msg)
+        .unwrap()
+        .skip(1)
+        .find_map(|p| decoder.add_packet(&p).unwrap());
+    assert_eq!(done.as_deref(), Some(&msg[..]));
+}
@@ -0,0 +1,191 @@
+#![no_std]
+//! Network impairments, for demonstrating partitions and lossy links without blocking the radio
+//! by hand.
+//!
+//! An [`Impairment`] is set from a list of settings separated by spaces, and formats as the
+//! same:
+//!
+//! - `drop=N`: drop N percent of messages sent and received
+//! - `latency=MS`: wait MS milliseconds before sending each message
+//! - `allow=A,B`: only talk to the peers at addresses A and B
+//! - `block=A,B`: don't talk to the peers at addresses A and B
+//! - `peers=all`: clear `allow` or `block`
+//! - `silent=on` or `silent=off`: stop sending and receiving anything at all
+//! - `reset`: clear everything
+//!
+//! An `Impairment` never reads a clock or a random number generator itself. Latency is kept in
+//! milliseconds, and the roll deciding whether a message is dropped is passed in.
+
+use core::fmt;
+
+/// The most peers in an allowlist or blocklist.
+pub const MAX_FILTER_PEERS: usize = 8;
+/// The broadcast address, which peer filters don't apply to.
+pub const BROADCAST: u16 = 0;
+
+pub type PeerList = heapless::Vec<u16, MAX_FILTER_PEERS>;
+
+/// Which peers we talk to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerFilter {
+    All,
+    /// Only these peers
+    Allow(PeerList),
+    /// Every peer but these
+    Block(PeerList),
+}
+
+impl PeerFilter {
+    pub fn allows(&self, peer: u16) -> bool {
+        match self {
+            PeerFilter::All => true,
+            PeerFilter::Allow(peers) => peers.contains(&peer),
+            PeerFilter::Block(peers) => !peers.contains(&peer),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Impairment {
+    /// The percentage of messages to drop
+    pub drop_percent: u8,
+    /// Added to every message we send, in milliseconds
+    pub latency_ms: u64,
+    pub peers: PeerFilter,
+    /// Send and receive nothing
+    pub silent: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImpairmentError {
+    #[error("unknown setting `{0}`")]
+    UnknownSetting(heapless::String<16>),
+    #[error("bad value for `{0}`")]
+    BadValue(&'static str),
+    #[error("more than {MAX_FILTER_PEERS} peers")]
+    TooManyPeers,
+}
+
+fn parse_peers(value: &str) -> Result<PeerList, ImpairmentError> {
+    let mut peers = PeerList::new();
+    for peer in value.split(',') {
+        let peer = peer
+            .parse()
+            .map_err(|_| ImpairmentError::BadValue("peers"))?;
+        peers
+            .push(peer)
+            .map_err(|_| ImpairmentError::TooManyPeers)?;
+    }
+    Ok(peers)
+}
+
+impl Impairment {
+    pub const fn new() -> Impairment {
+        Impairment {
+            drop_percent: 0,
+            latency_ms: 0,
+            peers: PeerFilter::All,
+            silent: false,
+        }
+    }
+
+    /// Whether anything is impaired.
+    pub fn is_active(&self) -> bool {
+        *self != Impairment::new()
+    }
+
+    /// Apply settings in the form described in the [crate docs](crate). If any setting is bad,
+    /// none of them are applied.
+    pub fn apply(&mut self, settings: &str) -> Result<(), ImpairmentError> {
+        let mut next = self.clone();
+        for setting in settings.split_whitespace() {
+            next.apply_one(setting)?;
+        }
+        *self = next;
+        Ok(())
+    }
+
+    fn apply_one(&mut self, setting: &str) -> Result<(), ImpairmentError> {
+        let (name, value) = setting.split_once('=').unwrap_or((setting, ""));
+        match name {
+            "reset" => *self = Impairment::new(),
+            "drop" => {
+                self.drop_percent = value
+                    .parse()
+                    .ok()
+                    .filter(|p| *p <= 100)
+                    .ok_or(ImpairmentError::BadValue("drop"))?
+            }
+            "latency" => {
+                self.latency_ms = value
+                    .parse()
+                    .map_err(|_| ImpairmentError::BadValue("latency"))?
+            }
+            "allow" => self.peers = PeerFilter::Allow(parse_peers(value)?),
+            "block" => self.peers = PeerFilter::Block(parse_peers(value)?),
+            "peers" => {
+                if value != "all" {
+                    return Err(ImpairmentError::BadValue("peers"));
+                }
+                self.peers = PeerFilter::All
+            }
+            "silent" => {
+                self.silent = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(ImpairmentError::BadValue("silent")),
+                }
+            }
+            _ => {
+                let mut name = heapless::String::new();
+                for c in setting.chars() {
+                    if name.push(c).is_err() {
+                        break;
+                    }
+                }
+                return Err(ImpairmentError::UnknownSetting(name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a message to or from `peer` should get through, given a random `roll`.
+    /// Broadcasts pass the peer filter, since they aren't to anyone in particular.
+    pub fn passes(&self, peer: u16, roll: u8) -> bool {
+        if self.silent || (peer != BROADCAST && !self.peers.allows(peer)) {
+            return false;
+        }
+        // Close enough to uniform for a demo
+        u16::from(roll) * 100 / 256 >= u16::from(self.drop_percent)
+    }
+}
+
+impl Default for Impairment {
+    fn default() -> Impairment {
+        Impairment::new()
+    }
+}
+
+fn write_peers(f: &mut fmt::Formatter<'_>, name: &str, peers: &PeerList) -> fmt::Result {
+    write!(f, " {name}=")?;
+    for (i, peer) in peers.iter().enumerate() {
+        if i > 0 {
+            write!(f, ",")?;
+        }
+        write!(f, "{peer}")?;
+    }
+    Ok(())
+}
+
+/// Formats as settings that [`Impairment::apply`] accepts.
+impl fmt::Display for Impairment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "drop={} latency={}", self.drop_percent, self.latency_ms)?;
+        match &self.peers {
+            PeerFilter::All => write!(f, " peers=all")?,
+            PeerFilter::Allow(peers) => write_peers(f, "allow", peers)?,
+            PeerFilter::Block(peers) => write_peers(f, "block", peers)?,
+        }
+        write!(f, " silent={}", if self.silent { "on" } else { "off" })
+    }
+}
@@ -0,0 +1,100 @@
+use impairment::{Impairment, ImpairmentError, PeerFilter, PeerList, BROADCAST};
+
+fn peers(list: &[u16]) -> PeerList {
+    PeerList::from_slice(list).unwrap()
+}
+
+#[test]
+fn settings_round_trip_through_display() {
+    for peers in [
+        PeerFilter::All,
+        PeerFilter::Allow(peers(&[3, 400])),
+        PeerFilter::Block(peers(&[7])),
+    ] {
+        let impairment = Impairment {
+            drop_percent: 25,
+            latency_ms: 150,
+            peers,
+            silent: true,
+        };
+        let mut applied = Impairment::new();
+        applied.apply(&impairment.to_string()).unwrap();
+        assert_eq!(applied, impairment);
+    }
+}
+
+#[test]
+fn reset_clears_everything() {
+    let mut impairment = Impairment::new();
+    impairment.apply("drop=50 block=2 silent=on").unwrap();
+    assert!(impairment.is_active());
+    impairment.apply("reset").unwrap();
+    assert!(!impairment.is_active());
+}
+
+#[test]
+fn nothing_is_applied_if_a_setting_is_bad() {
+    let mut impairment = Impairment::new();
+    impairment.apply("latency=20").unwrap();
+    let before = impairment.clone();
+    let result = impairment.apply("drop=10 allow=1,2 latency=soon silent=on");
+    assert!(matches!(result, Err(ImpairmentError::BadValue("latency"))));
+    assert_eq!(impairment, before);
+}
+
+#[test]
+fn bad_settings_are_rejected() {
+    let mut impairment = Impairment::new();
+    assert!(matches!(
+        impairment.apply("drop=101"),
+        Err(ImpairmentError::BadValue("drop"))
+    ));
+    assert!(matches!(
+        impairment.apply("peers=some"),
+        Err(ImpairmentError::BadValue("peers"))
+    ));
+    assert!(matches!(
+        impairment.apply("allow=1,2,3,4,5,6,7,8,9"),
+        Err(ImpairmentError::TooManyPeers)
+    ));
+    match impairment.apply("jitter=5") {
+        Err(ImpairmentError::UnknownSetting(name)) => assert_eq!(name, "jitter=5"),
+        other => panic!("expected an unknown setting, got {other:?}"),
+    }
+    assert!(!impairment.is_active());
+}
+
+#[test]
+fn peer_filters() {
+    assert!(PeerFilter::All.allows(5));
+    let allow = PeerFilter::Allow(peers(&[1, 2]));
+    assert!(allow.allows(2));
+    assert!(!allow.allows(5));
+    let block = PeerFilter::Block(peers(&[1, 2]));
+    assert!(!block.allows(2));
+    assert!(block.allows(5));
+}
+
+#[test]
+fn broadcasts_pass_peer_filters_but_not_silence() {
+    let mut impairment = Impairment::new();
+    impairment.apply("allow=1").unwrap();
+    assert!(impairment.passes(BROADCAST, 0));
+    assert!(!impairment.passes(2, 0));
+    impairment.apply("silent=on").unwrap();
+    assert!(!impairment.passes(BROADCAST, 0));
+    assert!(!impairment.passes(1, 0));
+}
+
+#[test]
+fn drops_follow_the_roll() {
+    let mut impairment = Impairment::new();
+    assert!((0..=u8::MAX).all(|roll| impairment.passes(1, roll)));
+    impairment.apply("drop=100").unwrap();
+    assert!((0..=u8::MAX).all(|roll| !impairment.passes(1, roll)));
+    impairment.apply("drop=50").unwrap();
+    let passed = (0..=u8::MAX)
+        .filter(|roll| impairment.passes(1, *roll))
+        .count();
+    assert_eq!(passed, 128);
+}
@@ -0,0 +1,107 @@
+#![no_std]
+//! The interface between sync code and the network links it syncs over.
+//!
+//! A link implements [`NetworkInterface`] to send and receive [`Message`]s, which are whole
+//! sync messages with a sender and a recipient. How a link gets them across, whether in radio
+//! packets, over a cable or in datagrams, is its own business, so sync code written against the
+//! trait works over any link, and a link works under any app.
+//!
+//! Links that need work done in the background, such as a task feeding a radio, leave running
+//! it to the app, since that depends on the executor.
+
+extern crate alloc;
+
+use alloc::{boxed::Box, string::String};
+use core::{fmt, hash::Hash};
+
+use thiserror::Error;
+
+/// The longest message any link carries. Links refuse to send longer messages, and don't
+/// reconstruct them, so a message that fits gets through on every link. A device that is short
+/// of memory may turn away shorter messages too.
+pub const MAX_MESSAGE_LEN: usize = 512 * 1024;
+
+/// NetworkError is intentionally opaque as it may be produced by any
+/// [`NetworkInterface`] implementation.
+#[derive(Debug, Error)]
+pub enum NetworkError {
+    #[error("Send error: {0}")]
+    Send(String),
+    #[error("Receive error: {0}")]
+    Receive(String),
+}
+
+/// `Message` is a sequence of bytes with addressing information, given
+/// to or produced by a [`NetworkInterface`] implementation.
+#[derive(Debug)]
+pub struct Message<A> {
+    /// Sender address.
+    pub sender: A,
+    /// Recipient address.
+    pub recipient: A,
+    /// The payload.
+    pub contents: Box<[u8]>,
+    /// Received signal strength in dBm, for transports that report it.
+    pub rssi: Option<i8>,
+}
+
+impl<A> Message<A> {
+    pub fn new(sender: A, recipient: A, contents: impl Into<Box<[u8]>>) -> Message<A> {
+        Message {
+            sender,
+            recipient,
+            contents: contents.into(),
+            rssi: None,
+        }
+    }
+}
+
+/// A `NetworkInterface` is the object that a sync implementation uses to access the
+/// network.
+///
+/// Methods take `&mut self` so that an interface can keep per-peer state, like decoders and
+/// loss estimates, without locking. An interface that is shared between tasks can implement
+/// the trait for a reference to itself instead.
+#[allow(async_fn_in_trait)]
+pub trait NetworkInterface {
+    /// The type of a peer address on this network
+    type Addr: Copy + fmt::Display + Hash;
+    /// The address that reaches every peer in range.
+    const BROADCAST: Self::Addr;
+    /// A short name for this network, used in diagnostics.
+    const NAME: &'static str;
+    /// Whether a message to [`BROADCAST`](Self::BROADCAST) reaches every peer at once. If not,
+    /// it reaches at most one peer, like a message on a point-to-point link.
+    const BROADCASTS: bool = true;
+
+    /// Sends a message on the network.
+    async fn send_message(&mut self, msg: Message<Self::Addr>) -> Result<(), NetworkError>;
+    /// Waits until a message is received from the network.
+    async fn recv_message(&mut self) -> Result<Message<Self::Addr>, NetworkError>;
+    /// Gets the address of this node
+    fn my_address(&self) -> Self::Addr;
+    /// Moves this node to a new address because another node is using the current one, and
+    /// returns the new address. Links that can't change address keep the one they have.
+    fn reassign_address(&mut self) -> Self::Addr {
+        self.my_address()
+    }
+    /// The largest message worth sending to `peer` in one piece. Larger messages can still be
+    /// sent, but they take longer and are more likely to be lost.
+    fn mtu(&self, peer: &Self::Addr) -> usize;
+    /// The name of the link we currently reach `peer` over, for interfaces that have more than
+    /// one.
+    fn link_name(&self, _peer: &Self::Addr) -> &'static str {
+        Self::NAME
+    }
+    /// Our estimate of the percentage of `peer`'s packets we lose, for links that measure it.
+    /// Peers are told this so they can send more or less redundancy.
+    fn rx_loss(&self, _peer: &Self::Addr) -> Option<u8> {
+        None
+    }
+    /// `peer` reports losing `loss` percent of our packets.
+    fn set_tx_loss(&mut self, _peer: Self::Addr, _loss: u8) {}
+    /// The message last received from `peer` passed authentication in the layer above. Links
+    /// that learn something about a peer from what they receive, like where to send to reach
+    /// it, should only trust what came with messages that do.
+    fn authenticated(&mut self, _peer: Self::Addr) {}
+}
@@ -46,11 +46,199 @@ impl From<(u8, u8, u8)> for RgbU8 {
     }
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+/// The network to join on devices with the Wi-Fi link.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct WifiCredentials {
+    pub ssid: heapless::String<32>,
+    /// Empty for an open network.
+    pub password: heapless::String<64>,
+}
+
+// Written by hand so the password does not end up in logs.
+impl core::fmt::Debug for WifiCredentials {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("WifiCredentials")
+            .field("ssid", &self.ssid)
+            .field(
+                "password",
+                &if self.password.is_empty() {
+                    "<none>"
+                } else {
+                    "<set>"
+                },
+            )
+            .finish()
+    }
+}
+
+/// A PHY rate for ESP-NOW frames. Slower rates reach further.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum PhyRate {
+    #[default]
+    Rate1M,
+    Rate2M,
+    Rate5_5M,
+    Rate11M,
+    Rate6M,
+    Rate9M,
+    Rate12M,
+    Rate18M,
+    Rate24M,
+    Rate36M,
+    Rate48M,
+    Rate54M,
+}
+
+impl PhyRate {
+    const NAMES: [(PhyRate, &'static str); 12] = [
+        (PhyRate::Rate1M, "1m"),
+        (PhyRate::Rate2M, "2m"),
+        (PhyRate::Rate5_5M, "5.5m"),
+        (PhyRate::Rate11M, "11m"),
+        (PhyRate::Rate6M, "6m"),
+        (PhyRate::Rate9M, "9m"),
+        (PhyRate::Rate12M, "12m"),
+        (PhyRate::Rate18M, "18m"),
+        (PhyRate::Rate24M, "24m"),
+        (PhyRate::Rate36M, "36m"),
+        (PhyRate::Rate48M, "48m"),
+        (PhyRate::Rate54M, "54m"),
+    ];
+
+    /// The rate's name in megabits per second, e.g. `5.5m`.
+    pub fn name(self) -> &'static str {
+        PhyRate::NAMES
+            .iter()
+            .find(|(rate, _)| *rate == self)
+            .map(|(_, name)| *name)
+            .expect("every rate has a name")
+    }
+}
+
+impl core::fmt::Display for PhyRate {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("unknown PHY rate")]
+pub struct UnknownPhyRate;
+
+/// Parses the names [`PhyRate::name`] gives.
+impl core::str::FromStr for PhyRate {
+    type Err = UnknownPhyRate;
+
+    fn from_str(s: &str) -> Result<PhyRate, UnknownPhyRate> {
+        PhyRate::NAMES
+            .iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(s))
+            .map(|(rate, _)| *rate)
+            .ok_or(UnknownPhyRate)
+    }
+}
+
+/// The ESP-NOW radio settings. Devices only hear each other on the same channel, so these are
+/// team configuration, set the same on every member like the team secret.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RadioSettings {
+    /// The Wi-Fi channel.
+    pub channel: u8,
+    pub rate: PhyRate,
+    /// The most transmit power to use, in dBm.
+    pub tx_power: u8,
+}
+
+impl RadioSettings {
+    pub const CHANNELS: core::ops::RangeInclusive<u8> = 1..=13;
+    pub const TX_POWERS: core::ops::RangeInclusive<u8> = 2..=20;
+}
+
+/// The radio's own defaults.
+impl Default for RadioSettings {
+    fn default() -> RadioSettings {
+        RadioSettings {
+            channel: 1,
+            rate: PhyRate::Rate1M,
+            tx_power: 20,
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
 pub struct Parameters {
     pub graph_id: Option<[u8; 32]>,
     pub device_id: Option<[u8; 32]>,
+    /// The device's address on every link. 0 is the broadcast address, and means the device
+    /// should pick its own.
     pub address: u16,
     pub peers: heapless::Vec<u16, MAX_PEERS>,
     pub color: RgbU8,
+    /// Secret shared by all members of the team, provisioned at onboarding. Sync traffic is
+    /// authenticated with a key derived from it.
+    pub team_secret: Option<[u8; 32]>,
+    /// Incremented every time the device powers on.
+    pub boot_count: u32,
+    /// The Wi-Fi network to join, if any.
+    pub wifi: Option<WifiCredentials>,
+    /// How ESP-NOW uses the radio.
+    pub radio: RadioSettings,
+    /// The address the device picked for itself while `address` is 0, or 0 if it hasn't picked
+    /// one yet. Kept apart from `address` so a picked address is never mistaken for a configured
+    /// one.
+    pub auto_address: u16,
+}
+
+/// Older blocks hold a prefix of the current layout, since each firmware added fields to the end.
+/// They are read a group of fields at a time until the data runs out, and the rest are left at
+/// their defaults.
+impl Versioned for Parameters {
+    const VERSION: u16 = 2;
+
+    fn upgrade(version: Option<u16>, bytes: &[u8]) -> Result<Parameters, ParameterStoreError> {
+        if let Some(version @ 2..) = version {
+            return Err(ParameterStoreError::UnknownVersion(version));
+        }
+        let mut p = Parameters::default();
+        let mut rest = bytes;
+        (p.graph_id, p.device_id, p.address, p.peers, p.color) = take(&mut rest)?;
+        if !rest.is_empty() {
+            (p.team_secret, p.boot_count) = take(&mut rest)?;
+        }
+        if !rest.is_empty() {
+            p.wifi = take(&mut rest)?;
+        }
+        if !rest.is_empty() {
+            p.radio = take(&mut rest)?;
+        }
+        if !rest.is_empty() {
+            p.auto_address = take(&mut rest)?;
+        }
+        Ok(p)
+    }
+}
+
+/// Decode a value from the front of `bytes`, and move past it.
+fn take<'a, T: Deserialize<'a>>(bytes: &mut &'a [u8]) -> Result<T, postcard::Error> {
+    let (v, rest) = postcard::take_from_bytes(bytes)?;
+    *bytes = rest;
+    Ok(v)
+}
+
+// Written by hand so the team secret does not end up in logs.
+impl core::fmt::Debug for Parameters {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Parameters")
+            .field("graph_id", &self.graph_id)
+            .field("device_id", &self.device_id)
+            .field("address", &self.address)
+            .field("peers", &self.peers)
+            .field("color", &self.color)
+            .field("team_secret", &self.team_secret.map(|_| "<set>"))
+            .field("boot_count", &self.boot_count)
+            .field("wifi", &self.wifi)
+            .field("radio", &self.radio)
+            .field("auto_address", &self.auto_address)
+            .finish()
+    }
 }
@@ -5,6 +5,9 @@ use crc::Crc;
 use crate::abstract_io::AbstractIO;
 
 const PARAMETER_BLOCK_SIZE: usize = 1024;
+/// Starts the data of a block written with a version. Blocks written before versions were
+/// recorded start with the parameters themselves, whose first byte is never this.
+const VERSION_TAG: u8 = 0xa5;
 
 #[derive(Debug, thiserror::Error)]
 pub enum ParameterStoreError {
@@ -16,6 +19,20 @@ pub enum ParameterStoreError {
     Size,
     #[error("invalid checksum")]
     Corrupt,
+    #[error("unknown parameter layout version {0}")]
+    UnknownVersion(u16),
+}
+
+/// Parameters that can be stored. Blocks record which version of the layout they were written
+/// with, so that blocks written by older firmware can still be read after the layout changes.
+pub trait Versioned: Sized {
+    /// The version of the current layout. Bump it whenever the layout changes, and teach
+    /// [`upgrade`](Self::upgrade) to read the layout it replaces.
+    const VERSION: u16;
+
+    /// Decode `bytes` written with an older `version` of the layout. `version` is `None` for
+    /// blocks written before versions were recorded.
+    fn upgrade(version: Option<u16>, bytes: &[u8]) -> Result<Self, ParameterStoreError>;
 }
 
 pub struct ParameterStore<T, IO> {
@@ -34,24 +51,32 @@ impl<T, IO> ParameterStore<T, IO> {
 
 impl<T, IO> ParameterStore<T, IO>
 where
-    T: Sized + serde::Serialize + for<'a> serde::Deserialize<'a>,
+    T: Versioned + serde::Serialize + for<'a> serde::Deserialize<'a>,
     IO: AbstractIO,
 {
     pub fn store(&mut self, v: &T) -> Result<T, ParameterStoreError> {
         let mut buffer: heapless::Vec<u8, PARAMETER_BLOCK_SIZE> = heapless::Vec::new();
         let serialized_bytes = postcard::to_allocvec(v)?;
-        if serialized_bytes.len() + 8 > PARAMETER_BLOCK_SIZE {
+        // The data is the version followed by the parameters
+        let data_size = serialized_bytes.len() + 3;
+        if data_size + 8 > PARAMETER_BLOCK_SIZE {
             return Err(ParameterStoreError::Size);
         }
 
         buffer
-            .extend_from_slice(&(serialized_bytes.len() as u32).to_be_bytes())
+            .extend_from_slice(&(data_size as u32).to_be_bytes())
+            .map_err(|_| ParameterStoreError::Size)?;
+        buffer
+            .push(VERSION_TAG)
+            .map_err(|_| ParameterStoreError::Size)?;
+        buffer
+            .extend_from_slice(&T::VERSION.to_be_bytes())
             .map_err(|_| ParameterStoreError::Size)?;
         buffer
             .extend_from_slice(&serialized_bytes)
             .map_err(|_| ParameterStoreError::Size)?;
         let checksum = Crc::<u32>::new(&crc::CRC_32_CKSUM)
-            .checksum(&buffer[..serialized_bytes.len() + 4])
+            .checksum(&buffer[..data_size + 4])
             .to_be_bytes();
         buffer
             .extend_from_slice(&checksum)
@@ -79,8 +104,15 @@ where
         if checksum != buffer[data_size + 4..data_size + 8] {
             return Err(ParameterStoreError::Corrupt);
         }
-        let v = postcard::from_bytes(&buffer[4..data_size + 4])?;
-        Ok(v)
+        let data = &buffer[4..data_size + 4];
+        match *data {
+            [VERSION_TAG, hi, lo, ref bytes @ ..] => match u16::from_be_bytes([hi, lo]) {
+                version if version == T::VERSION => Ok(postcard::from_bytes(bytes)?),
+                version if version < T::VERSION => T::upgrade(Some(version), bytes),
+                version => Err(ParameterStoreError::UnknownVersion(version)),
+            },
+            _ => T::upgrade(None, data),
+        }
     }
 
     pub fn update<F>(&mut self, f: F) -> Result<T, ParameterStoreError>
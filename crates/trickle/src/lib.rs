@@ -0,0 +1,186 @@
+#![no_std]
+//! An implementation of the Trickle algorithm from [RFC 6206].
+//!
+//! ## Theory of Operation
+//!
+//! Trickle divides time into intervals of length `I`. At the start of each interval a
+//! transmission time `t` is picked uniformly from the second half of the interval and the
+//! consistency counter `c` is reset to zero. Every consistent transmission heard during the
+//! interval increments `c`. At time `t`, we transmit only if `c < k`, so in a dense, settled
+//! neighborhood only about `k` nodes transmit per interval. When an interval expires `I` is
+//! doubled, up to `I_max`. When something inconsistent is heard, `I` is reset to `I_min` so
+//! that news travels quickly.
+//!
+//! All times are milliseconds from an arbitrary epoch. [`TrickleTimer`] never reads a clock
+//! itself, which lets it be driven by a virtual clock in tests.
+//!
+//! [RFC 6206]: https://www.rfc-editor.org/rfc/rfc6206
+
+/// Parameters for a [`TrickleTimer`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrickleConfig {
+    /// The minimum interval size in milliseconds (`I_min`).
+    pub i_min: u64,
+    /// The maximum number of times the interval is doubled (`I_max` = `I_min` * 2^doublings).
+    pub i_max_doublings: u8,
+    /// The redundancy constant (`k`). A transmission is suppressed if at least this many
+    /// consistent transmissions were heard in the current interval. Zero disables suppression.
+    pub k: u8,
+}
+
+impl TrickleConfig {
+    /// The maximum interval size in milliseconds (`I_max`).
+    pub fn i_max(&self) -> u64 {
+        self.i_min << self.i_max_doublings
+    }
+}
+
+/// A Trickle timer.
+#[derive(Debug)]
+pub struct TrickleTimer {
+    config: TrickleConfig,
+    /// The current interval size (`I`).
+    interval: u64,
+    /// When the current interval began.
+    interval_start: u64,
+    /// When to transmit in the current interval (`t`).
+    transmit_at: u64,
+    /// Consistent transmissions heard in the current interval (`c`).
+    counter: u8,
+    /// Whether `transmit_at` has passed in the current interval.
+    fired: bool,
+    /// Transmissions suppressed since creation, for diagnostics.
+    suppressed: u32,
+    rng: XorShift32,
+}
+
+impl TrickleTimer {
+    /// Create a new timer starting at `now`. `seed` seeds the generator used to pick
+    /// transmission times, and should differ between nodes so they do not stay in lockstep.
+    ///
+    /// Per the RFC, the first interval is a random size between `I_min` and `I_max`.
+    pub fn new(config: TrickleConfig, now: u64, seed: u32) -> TrickleTimer {
+        let mut rng = XorShift32::new(seed);
+        let doublings = rng.next() % (config.i_max_doublings as u32 + 1);
+        let mut timer = TrickleTimer {
+            config,
+            interval: config.i_min << doublings,
+            interval_start: now,
+            transmit_at: now,
+            counter: 0,
+            fired: false,
+            suppressed: 0,
+            rng,
+        };
+        timer.start_interval(now);
+        timer
+    }
+
+    /// The configuration this timer was created with.
+    pub fn config(&self) -> &TrickleConfig {
+        &self.config
+    }
+
+    /// The current interval size in milliseconds.
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+
+    /// The number of consistent transmissions heard in the current interval.
+    pub fn counter(&self) -> u8 {
+        self.counter
+    }
+
+    /// The number of transmissions that have been suppressed so far.
+    pub fn suppressed(&self) -> u32 {
+        self.suppressed
+    }
+
+    /// The next time [`poll`](Self::poll) has something to do.
+    pub fn next_deadline(&self) -> u64 {
+        if self.fired {
+            self.interval_start + self.interval
+        } else {
+            self.transmit_at
+        }
+    }
+
+    /// Record that a consistent transmission was heard.
+    pub fn consistent(&mut self) {
+        self.counter = self.counter.saturating_add(1);
+    }
+
+    /// Record that an inconsistent transmission was heard, or that our own state changed.
+    /// If the interval is larger than `I_min` it is reset to `I_min` and a new interval
+    /// starts at `now`. Otherwise nothing happens.
+    pub fn inconsistent(&mut self, now: u64) {
+        if self.interval > self.config.i_min {
+            self.reset(now);
+        }
+    }
+
+    /// Unconditionally start a new `I_min` interval at `now`.
+    pub fn reset(&mut self, now: u64) {
+        self.interval = self.config.i_min;
+        self.start_interval(now);
+    }
+
+    /// Advance the timer to `now`. Returns `true` if we should transmit.
+    pub fn poll(&mut self, now: u64) -> bool {
+        let mut transmit = false;
+        if !self.fired && now >= self.transmit_at {
+            self.fired = true;
+            if self.config.k == 0 || self.counter < self.config.k {
+                transmit = true;
+            } else {
+                self.suppressed = self.suppressed.wrapping_add(1);
+            }
+        }
+        let interval_end = self.interval_start + self.interval;
+        if now >= interval_end {
+            self.interval = u64::min(self.interval * 2, self.config.i_max());
+            // If we've been away for more than an interval, start counting from now
+            // rather than replaying the intervals we missed.
+            let next_start = if now - interval_end < self.interval {
+                interval_end
+            } else {
+                now
+            };
+            self.start_interval(next_start);
+        }
+        transmit
+    }
+
+    fn start_interval(&mut self, start: u64) {
+        let half = self.interval / 2;
+        let offset = match self.interval - half {
+            0 => 0,
+            spread => self.rng.next() as u64 % spread,
+        };
+        self.interval_start = start;
+        self.transmit_at = start + half + offset;
+        self.counter = 0;
+        self.fired = false;
+    }
+}
+
+/// A tiny PRNG. Transmission times only need to be decorrelated between nodes, not
+/// unpredictable, so this avoids pulling in a real RNG.
+#[derive(Debug)]
+struct XorShift32(u32);
+
+impl XorShift32 {
+    fn new(seed: u32) -> XorShift32 {
+        // Zero is a fixed point for xorshift
+        XorShift32(if seed == 0 { 0x9E37_79B9 } else { seed })
+    }
+
+    fn next(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+}
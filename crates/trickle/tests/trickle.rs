@@ -0,0 +1,128 @@
+use trickle::{TrickleConfig, TrickleTimer};
+
+const CONFIG: TrickleConfig = TrickleConfig {
+    i_min: 100,
+    i_max_doublings: 4,
+    k: 2,
+};
+
+/// Step a timer along a virtual clock in 1ms increments, returning the times at which it
+/// asked to transmit.
+fn run(timer: &mut TrickleTimer, from: u64, to: u64) -> Vec<u64> {
+    (from..to).filter(|now| timer.poll(*now)).collect()
+}
+
+#[test]
+fn first_interval_is_within_bounds() {
+    for seed in 0..64 {
+        let timer = TrickleTimer::new(CONFIG, 0, seed);
+        assert!(timer.interval() >= CONFIG.i_min);
+        assert!(timer.interval() <= CONFIG.i_max());
+    }
+}
+
+#[test]
+fn transmits_once_per_interval_in_second_half() {
+    let mut timer = TrickleTimer::new(CONFIG, 0, 1);
+    timer.reset(0);
+    let mut start = 0;
+    let mut interval = CONFIG.i_min;
+    for _ in 0..6 {
+        let sent = run(&mut timer, start, start + interval);
+        assert_eq!(sent.len(), 1);
+        assert!(
+            sent[0] >= start + interval / 2,
+            "{sent:?} in {start}+{interval}"
+        );
+        start += interval;
+        interval = u64::min(interval * 2, CONFIG.i_max());
+    }
+}
+
+#[test]
+fn interval_doubles_up_to_max() {
+    let mut timer = TrickleTimer::new(CONFIG, 0, 7);
+    timer.reset(0);
+    let mut now = 0;
+    let mut seen = Vec::new();
+    while now < 20 * CONFIG.i_max() {
+        timer.poll(now);
+        if seen.last() != Some(&timer.interval()) {
+            seen.push(timer.interval());
+        }
+        now += 1;
+    }
+    assert_eq!(seen, [100, 200, 400, 800, 1600]);
+}
+
+#[test]
+fn consistent_transmissions_suppress() {
+    let mut timer = TrickleTimer::new(CONFIG, 0, 3);
+    timer.reset(0);
+    timer.consistent();
+    timer.consistent();
+    assert!(run(&mut timer, 0, CONFIG.i_min).is_empty());
+    assert_eq!(timer.suppressed(), 1);
+
+    // The counter resets with the next interval, so one consistent transmission is not enough
+    timer.consistent();
+    assert_eq!(run(&mut timer, CONFIG.i_min, CONFIG.i_min * 3).len(), 1);
+}
+
+#[test]
+fn zero_k_never_suppresses() {
+    let mut timer = TrickleTimer::new(TrickleConfig { k: 0, ..CONFIG }, 0, 3);
+    timer.reset(0);
+    for _ in 0..10 {
+        timer.consistent();
+    }
+    assert_eq!(run(&mut timer, 0, CONFIG.i_min).len(), 1);
+}
+
+#[test]
+fn inconsistency_resets_to_min() {
+    let mut timer = TrickleTimer::new(CONFIG, 0, 11);
+    timer.reset(0);
+    run(&mut timer, 0, 10 * CONFIG.i_max());
+    assert_eq!(timer.interval(), CONFIG.i_max());
+
+    let now = 10 * CONFIG.i_max();
+    timer.inconsistent(now);
+    assert_eq!(timer.interval(), CONFIG.i_min);
+    let sent = run(&mut timer, now, now + CONFIG.i_min);
+    assert_eq!(sent.len(), 1);
+    assert!(sent[0] >= now + CONFIG.i_min / 2);
+}
+
+#[test]
+fn inconsistency_at_min_does_not_restart_interval() {
+    let mut timer = TrickleTimer::new(CONFIG, 0, 5);
+    timer.reset(0);
+    let deadline = timer.next_deadline();
+    timer.inconsistent(deadline - 1);
+    assert_eq!(timer.next_deadline(), deadline);
+}
+
+#[test]
+fn long_gap_does_not_replay_missed_intervals() {
+    let mut timer = TrickleTimer::new(CONFIG, 0, 9);
+    timer.reset(0);
+    let late = 1_000_000;
+    // One transmission is owed from the interval we slept through, and no more
+    assert!(timer.poll(late));
+    assert!(!timer.poll(late));
+    assert!(timer.next_deadline() > late);
+    assert!(timer.next_deadline() <= late + CONFIG.i_max());
+}
+
+#[test]
+fn different_seeds_pick_different_times() {
+    let times: Vec<u64> = (1..9)
+        .map(|seed| {
+            let mut timer = TrickleTimer::new(CONFIG, 0, seed);
+            timer.reset(0);
+            timer.next_deadline()
+        })
+        .collect();
+    assert!(times.iter().any(|t| *t != times[0]));
+}
@@ -0,0 +1,51 @@
+//! Print every message heard on a tty, such as the USB link port of a board built with the
+//! `net-usb` feature. Lines typed in are broadcast.
+//!
+//! ```text
+//! cargo run --example monitor --features host -- /dev/ttyACM1 65000
+//! ```
+
+use std::sync::atomic::AtomicU16;
+
+use tokio::io::{AsyncBufReadExt, BufReader};
+use uart_link::BROADCAST;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> std::io::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let (Some(path), Some(address)) = (args.next(), args.next()) else {
+        eprintln!("usage: monitor <tty> <address>");
+        std::process::exit(2);
+    };
+    let address: u16 = address.parse().expect("address is a number");
+    let address = Box::leak(Box::new(AtomicU16::new(address)));
+    let engine = uart_link::host::open(&path, address)?;
+    let mut interface = engine.interface();
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let talk = async {
+        loop {
+            tokio::select! {
+                msg = interface.recv() => println!(
+                    "{} -> {}: {} bytes: {:02x?}",
+                    msg.sender,
+                    msg.recipient,
+                    msg.contents.len(),
+                    msg.contents
+                ),
+                line = lines.next_line() => match line? {
+                    Some(line) if !line.is_empty() => {
+                        if let Err(e) = interface.send(BROADCAST, line.as_bytes()).await {
+                            eprintln!("send: {e}");
+                        }
+                    }
+                    Some(_) => (),
+                    None => return Ok(()),
+                },
+            }
+        }
+    };
+    tokio::select! {
+        _ = engine.run() => unreachable!(),
+        result = talk => result,
+    }
+}
@@ -0,0 +1,98 @@
+//! The host end of a link, over a tty such as a board's USB serial port, a USB-to-UART cable,
+//! or a pty.
+//!
+//! A [`Tty`] implements the [`embedded_io_async`] traits on top of Tokio, so a [`UartEngine`]
+//! runs on it as it would on a board's UART. Run the engine in a Tokio runtime. This feature
+//! also brings in `std` implementations of the embassy time driver and critical sections, so
+//! nothing else is needed.
+
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    path::Path,
+    sync::atomic::AtomicU16,
+};
+
+use embedded_io_async::{ErrorType, Read, Write};
+use tokio::io::unix::AsyncFd;
+
+use crate::UartEngine;
+
+/// A tty, as an async byte stream.
+pub struct Tty(AsyncFd<File>);
+
+impl Tty {
+    /// Open the tty at `path` in raw mode, so bytes go through untouched. This must be called
+    /// from within a Tokio runtime.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Tty> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+            .open(path)?;
+        make_raw(&file)?;
+        Tty::from_file(file)
+    }
+
+    /// Use a tty that is already open. It must be non-blocking, and should be in raw mode.
+    pub fn from_file(file: File) -> io::Result<Tty> {
+        Ok(Tty(AsyncFd::new(file)?))
+    }
+
+    /// Another handle to the same tty, so that one can be read while the other is written.
+    pub fn try_clone(&self) -> io::Result<Tty> {
+        Tty::from_file(self.0.get_ref().try_clone()?)
+    }
+}
+
+/// Put a tty in raw mode: no echo, no line editing, and no translation of any bytes.
+pub fn make_raw(file: &File) -> io::Result<()> {
+    // SAFETY: termios is plain old data, and is only used after tcgetattr fills it in
+    unsafe {
+        let mut termios = std::mem::zeroed();
+        if libc::tcgetattr(file.as_raw_fd(), &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        if libc::tcsetattr(file.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+impl ErrorType for Tty {
+    type Error = io::Error;
+}
+
+impl Read for Tty {
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.0.readable().await?;
+            if let Ok(result) = guard.try_io(|f| io::Read::read(&mut f.get_ref(), buf)) {
+                return result;
+            }
+        }
+    }
+}
+
+impl Write for Tty {
+    async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.0.writable().await?;
+            if let Ok(result) = guard.try_io(|f| io::Write::write(&mut f.get_ref(), buf)) {
+                return result;
+            }
+        }
+    }
+}
+
+/// An engine on a tty.
+pub type TtyEngine<'a> = UartEngine<'a, Tty, Tty>;
+
+/// Open the tty at `path` and make an engine on it, with our address in `address`.
+pub fn open(path: impl AsRef<Path>, address: &AtomicU16) -> io::Result<TtyEngine<'_>> {
+    let tty = Tty::open(path)?;
+    Ok(UartEngine::new(tty.try_clone()?, tty, address))
+}
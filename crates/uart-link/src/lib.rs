@@ -0,0 +1,389 @@
+#![no_std]
+//! A network link over a plain UART.
+//!
+//! ## Theory of Operation
+//!
+//! Messages are framed with [`fountain_framing`] exactly as on the IR link, and the frames are
+//! written to the UART back to back. A wire doesn't lose packets the way the air does, so little
+//! redundancy is sent, and there is no need to wait between packets or listen for collisions:
+//! each end of the cable has its own TX line.
+//!
+//! On the receiving end, bytes read from the UART are fed to a [`StreamReader`] until a valid
+//! frame is found, so noise on the line or a cable plugged in halfway through a frame costs at
+//! most that frame. Frames sent to this address are given to a [`Decoder`], which collects
+//! packets until it can reconstruct the original message.
+//!
+//! Messages that stall are finished with [`RepairRequest`]s, answered from an [`EncoderCache`]
+//! of the last few messages sent, as on the radio links. Only requests from
+//! [peers](UartInterface::add_peer) are answered.
+//!
+//! The [`UartEngine`] does the reading and writing, and is generic over the
+//! [`embedded_io_async`] traits so that it runs on the ESP32-S3 UART and over a pty on a host
+//! alike. Run it with [`UartEngine::run`], and send and receive messages through a
+//! [`UartInterface`], which also implements [`NetworkInterface`].
+//!
+//! With the `host` feature, [`host`] runs the engine over a tty on a host, which is the other end
+//! of a board's USB link.
+
+extern crate alloc;
+#[cfg(feature = "host")]
+extern crate std;
+
+#[cfg(feature = "host")]
+pub mod host;
+
+use alloc::{format, vec::Vec};
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use embassy_futures::{
+    join::join,
+    select::{select, Either},
+};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::{Error as _, Read, Write};
+pub use fountain_framing::FrameError;
+use fountain_framing::{
+    max_frame_size, Decoder, Encoder, EncoderCache, FecConfig, LossReports, Packet, RepairRequest,
+    StreamReader,
+};
+use net_interface::{NetworkError, NetworkInterface, MAX_MESSAGE_LEN};
+
+const UART_PACKET_QUEUE_SIZE: usize = 4;
+type Mutex<T> = embassy_sync::mutex::Mutex<CriticalSectionRawMutex, T>;
+type Channel<T> =
+    embassy_sync::channel::Channel<CriticalSectionRawMutex, T, UART_PACKET_QUEUE_SIZE>;
+type Sender<'a, T> =
+    embassy_sync::channel::Sender<'a, CriticalSectionRawMutex, T, UART_PACKET_QUEUE_SIZE>;
+type Receiver<'a, T> =
+    embassy_sync::channel::Receiver<'a, CriticalSectionRawMutex, T, UART_PACKET_QUEUE_SIZE>;
+
+/// The chunk size. The same as on the IR link, so the two share a packet format.
+pub const UART_CHUNK_SIZE: usize = 64;
+const UART_FRAME_SIZE: usize = max_frame_size(UART_CHUNK_SIZE);
+/// The largest message worth sending in one piece. Even at 115200 baud this takes under a
+/// second.
+pub const UART_MTU: usize = 8 * 1024;
+/// The broadcast address.
+pub const BROADCAST: u16 = 0;
+/// A wire rarely loses anything, so send just enough repair to ride out line noise.
+const UART_FEC: FecConfig = FecConfig {
+    min_overhead: 5,
+    max_overhead: 50,
+    min_repair: 1,
+};
+
+/// How long to wait before reading again after the UART reports an error or end of file.
+const READ_RETRY_DELAY_MS: u64 = 50;
+/// How often to ask for repairs to messages that have stopped arriving. Packets follow each
+/// other as fast as the line allows, so a short gap means something went wrong.
+const REPAIR_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A message received from the link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub sender: u16,
+    pub recipient: u16,
+    pub contents: Vec<u8>,
+}
+
+/// `UartEngine` turns messages into frames on a UART and back again.
+pub struct UartEngine<'a, R, W> {
+    rx: Mutex<R>,
+    tx: Mutex<W>,
+    /// This device's address, which may change while running.
+    address: &'a AtomicU16,
+    send_channel: Channel<Packet>,
+    receive_channel: Channel<Packet>,
+    /// Repair requests from other devices for messages we sent.
+    repair_channel: Channel<RepairRequest>,
+    /// The encoders of the last few messages we sent, to answer repair requests.
+    encoders: Mutex<EncoderCache<UART_CHUNK_SIZE>>,
+}
+
+impl<'a, R: Read, W: Write> UartEngine<'a, R, W> {
+    /// Create a new `UartEngine` reading from `rx` and writing to `tx`. Frames are accepted
+    /// if they are for `address` or broadcast.
+    pub fn new(rx: R, tx: W, address: &'a AtomicU16) -> UartEngine<'a, R, W> {
+        UartEngine {
+            rx: Mutex::new(rx),
+            tx: Mutex::new(tx),
+            address,
+            send_channel: Channel::new(),
+            receive_channel: Channel::new(),
+            repair_channel: Channel::new(),
+            encoders: Mutex::new(EncoderCache::new()),
+        }
+    }
+
+    pub fn interface(&self) -> UartInterface<'_> {
+        UartInterface {
+            address: self.address,
+            send_tx: self.send_channel.sender(),
+            receive_rx: self.receive_channel.receiver(),
+            encoders: &self.encoders,
+            message_seq: 0,
+            decoder: Decoder::with_max_len(MAX_MESSAGE_LEN),
+            reported_loss: LossReports::new(),
+            next_repair_poll: Instant::now() + REPAIR_POLL_INTERVAL,
+        }
+    }
+
+    /// Run the engine. This never returns.
+    pub async fn run(&self) -> ! {
+        join(self.run_receiver(), self.run_sender()).await;
+        // This tells the compiler to not worry about the return type
+        unreachable!();
+    }
+
+    /// Write a packet to the UART.
+    async fn send_packet(&self, packet: Packet) -> Result<(), W::Error> {
+        let mut frame = [0u8; UART_FRAME_SIZE];
+        let len = match packet.encode(&mut frame) {
+            Ok(len) => len,
+            Err(e) => {
+                log::error!("uart: could not frame packet: {e}");
+                return Ok(());
+            }
+        };
+        let mut tx = self.tx.lock().await;
+        tx.write_all(&frame[..len]).await?;
+        tx.flush().await
+    }
+
+    async fn send_and_log(&self, packet: Packet) {
+        if let Err(e) = self.send_packet(packet).await {
+            log::error!("uart send error: {:?}", e.kind());
+        }
+    }
+
+    async fn run_sender(&self) -> ! {
+        loop {
+            match select(self.send_channel.receive(), self.repair_channel.receive()).await {
+                Either::First(packet) => self.send_and_log(packet).await,
+                Either::Second(request) => {
+                    let packets = self.encoders.lock().await.answer(&request);
+                    log::debug!(
+                        "uart: sending {} repair packets to {}",
+                        packets.len(),
+                        request.requester
+                    );
+                    for packet in packets {
+                        self.send_and_log(packet).await;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn run_receiver(&self) -> ! {
+        let mut rx = self.rx.lock().await;
+        let mut reader = StreamReader::<UART_CHUNK_SIZE>::new();
+        let mut buf = [0u8; 32];
+        loop {
+            let count = match rx.read(&mut buf).await {
+                Ok(0) => {
+                    // Nothing on the other end, as with a host pty that was closed
+                    Timer::after_millis(READ_RETRY_DELAY_MS).await;
+                    continue;
+                }
+                Ok(count) => count,
+                Err(e) => {
+                    log::error!("uart recv error: {:?}", e.kind());
+                    Timer::after_millis(READ_RETRY_DELAY_MS).await;
+                    continue;
+                }
+            };
+            for byte in &buf[..count] {
+                match reader.push(*byte) {
+                    None => (),
+                    Some(Ok(packet)) => self.accept(packet).await,
+                    Some(Err(e)) => log::debug!("uart: {e}"),
+                }
+            }
+        }
+    }
+
+    /// Pass on a received packet, if it is for us.
+    async fn accept(&self, packet: Packet) {
+        let recipient = packet.header.recipient;
+        let my_address = self.address.load(Ordering::Relaxed);
+        if recipient != my_address && recipient != BROADCAST {
+            log::debug!("uart: packet not for me (address: {my_address}); for {recipient}");
+            return;
+        }
+        if let Some(request) = RepairRequest::from_packet(&packet) {
+            if self.repair_channel.try_send(request).is_err() {
+                log::debug!("uart: too busy to repair for {}", request.requester);
+            }
+            return;
+        }
+        self.receive_channel.send(packet).await;
+    }
+}
+
+pub struct UartInterface<'a> {
+    address: &'a AtomicU16,
+    send_tx: Sender<'a, Packet>,
+    receive_rx: Receiver<'a, Packet>,
+    encoders: &'a Mutex<EncoderCache<UART_CHUNK_SIZE>>,
+    message_seq: u16,
+    decoder: Decoder<UART_CHUNK_SIZE>,
+    /// The loss each peer reports for our packets.
+    reported_loss: LossReports,
+    /// When to next ask for repairs. Kept here rather than in [`recv`](Self::recv), which may be
+    /// cancelled and restarted at any time.
+    next_repair_poll: Instant,
+}
+
+impl UartInterface<'_> {
+    /// This device's address.
+    pub fn address(&self) -> u16 {
+        self.address.load(Ordering::Relaxed)
+    }
+
+    /// Send `contents` to `recipient`. Messages longer than [`MAX_MESSAGE_LEN`] are refused.
+    pub async fn send(&mut self, recipient: u16, contents: &[u8]) -> Result<(), FrameError> {
+        if contents.len() > MAX_MESSAGE_LEN {
+            return Err(FrameError::MessageTooLong(contents.len()));
+        }
+        let (message_seq, _) = self.message_seq.overflowing_add(1);
+        self.message_seq = message_seq;
+        let mut encoder = Encoder::<UART_CHUNK_SIZE>::with_fec(
+            recipient,
+            self.address(),
+            message_seq,
+            contents,
+            &UART_FEC,
+            self.planned_loss(recipient),
+        )?;
+        for packet in encoder.by_ref() {
+            self.send_tx.send(packet).await;
+        }
+        self.encoders.lock().await.insert(encoder);
+        Ok(())
+    }
+
+    /// The loss to plan for when sending to `recipient`. Broadcasts plan for the worst
+    /// neighbor.
+    fn planned_loss(&self, recipient: u16) -> Option<u8> {
+        if recipient == BROADCAST {
+            self.reported_loss.worst(Instant::now().as_millis())
+        } else {
+            self.reported_loss
+                .get(recipient, Instant::now().as_millis())
+        }
+    }
+
+    /// Read packets until we assemble a message, then return it.
+    pub async fn recv(&mut self) -> Message {
+        loop {
+            let packet =
+                match select(self.receive_rx.receive(), Timer::at(self.next_repair_poll)).await {
+                    Either::First(packet) => packet,
+                    Either::Second(()) => {
+                        self.request_repairs();
+                        continue;
+                    }
+                };
+            match self.decoder.add_packet(&packet) {
+                Ok(Some(contents)) => {
+                    return Message {
+                        sender: packet.header.sender,
+                        recipient: packet.header.recipient,
+                        contents,
+                    };
+                }
+                Ok(None) => (),
+                Err(e) => log::info!("uart: dropped packet from {}: {e}", packet.header.sender),
+            }
+        }
+    }
+
+    /// Ask for repairs to messages that have stopped arriving. Requests are dropped if the send
+    /// queue is full; they'll be asked again next time.
+    fn request_repairs(&mut self) {
+        self.next_repair_poll = Instant::now() + REPAIR_POLL_INTERVAL;
+        for request in self.decoder.repair_requests(self.address()) {
+            log::debug!(
+                "uart: asking {} for {} repair packets",
+                request.sender,
+                request.count
+            );
+            if self.send_tx.try_send(request.to_packet()).is_err() {
+                log::debug!("uart: send queue full; dropped repair request");
+            }
+        }
+    }
+
+    /// Change the longest message we'll start reconstructing, up to [`MAX_MESSAGE_LEN`]. Space
+    /// for a message is set aside as soon as its first packet arrives, so a board that is short
+    /// of memory should lower this.
+    pub fn set_max_message_len(&mut self, max_len: usize) {
+        self.decoder.set_max_len(max_len.min(MAX_MESSAGE_LEN));
+    }
+
+    /// Answer repair requests from `peer`, which sent us a message we know to be genuine.
+    /// Requests from anyone else are ignored. If the engine is busy answering a request, this
+    /// is skipped; the peer is added with its next message.
+    pub fn add_peer(&self, peer: u16) {
+        match self.encoders.try_lock() {
+            Ok(mut encoders) => encoders.add_peer(peer),
+            Err(_) => log::debug!("uart: too busy to add peer {peer}"),
+        }
+    }
+
+    /// Our estimate of the percentage of `peer`'s packets we lose.
+    pub fn rx_loss(&self, peer: &u16) -> Option<u8> {
+        self.decoder.loss(*peer)
+    }
+
+    /// `peer` reports losing `loss` percent of our packets.
+    pub fn set_tx_loss(&mut self, peer: u16, loss: u8) {
+        self.reported_loss
+            .insert(peer, loss, Instant::now().as_millis());
+    }
+}
+
+impl NetworkInterface for UartInterface<'_> {
+    type Addr = u16;
+    const BROADCAST: u16 = BROADCAST;
+    const NAME: &'static str = "uart";
+    // There's one device on the other end of the cable
+    const BROADCASTS: bool = false;
+
+    async fn send_message(&mut self, msg: net_interface::Message<u16>) -> Result<(), NetworkError> {
+        self.send(msg.recipient, &msg.contents)
+            .await
+            .map_err(|e| NetworkError::Send(format!("uart: {e}")))
+    }
+
+    async fn recv_message(&mut self) -> Result<net_interface::Message<u16>, NetworkError> {
+        let msg = self.recv().await;
+        Ok(net_interface::Message::new(
+            msg.sender,
+            msg.recipient,
+            msg.contents,
+        ))
+    }
+
+    fn my_address(&self) -> u16 {
+        self.address()
+    }
+
+    fn mtu(&self, _peer: &u16) -> usize {
+        UART_MTU
+    }
+
+    fn rx_loss(&self, peer: &u16) -> Option<u8> {
+        UartInterface::rx_loss(self, peer)
+    }
+
+    fn set_tx_loss(&mut self, peer: u16, loss: u8) {
+        UartInterface::set_tx_loss(self, peer, loss)
+    }
+
+    fn authenticated(&mut self, peer: u16) {
+        self.add_peer(peer)
+    }
+}
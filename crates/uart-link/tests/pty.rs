@@ -0,0 +1,194 @@
+//! Two engines talking over a Linux pty pair, standing in for two boards and a cable.
+
+use std::{
+    fs::File,
+    future::Future,
+    io,
+    os::fd::{AsRawFd, FromRawFd},
+    sync::atomic::AtomicU16,
+    time::Duration,
+};
+
+use net_interface::{NetworkInterface, MAX_MESSAGE_LEN};
+use uart_link::{
+    host::{make_raw, Tty, TtyEngine},
+    FrameError, Message, UartEngine, BROADCAST,
+};
+
+/// Open a raw, non-blocking pty pair.
+fn openpty() -> (File, File) {
+    let (mut master, mut slave) = (0, 0);
+    let ret = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    assert_eq!(ret, 0, "openpty: {}", io::Error::last_os_error());
+    let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+    make_raw(&slave).unwrap();
+    unsafe {
+        for fd in [master.as_raw_fd(), slave.as_raw_fd()] {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            assert_eq!(libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK), 0);
+        }
+    }
+    (master, slave)
+}
+
+/// An engine on each end of a pty. Also returns a handle to write to the first engine's end
+/// of the line directly.
+fn cable(a: u16, b: u16) -> (TtyEngine<'static>, TtyEngine<'static>, File) {
+    let (master, slave) = openpty();
+    let engine = |file: &File, address| {
+        UartEngine::new(
+            Tty::from_file(file.try_clone().unwrap()).unwrap(),
+            Tty::from_file(file.try_clone().unwrap()).unwrap(),
+            Box::leak(Box::new(AtomicU16::new(address))),
+        )
+    };
+    (engine(&master, a), engine(&slave, b), master)
+}
+
+/// Run both engines while `test` runs.
+async fn with_engines<T>(
+    a: &TtyEngine<'static>,
+    b: &TtyEngine<'static>,
+    test: impl Future<Output = T>,
+) -> T {
+    let test = tokio::time::timeout(Duration::from_secs(10), test);
+    tokio::select! {
+        _ = a.run() => unreachable!(),
+        _ = b.run() => unreachable!(),
+        result = test => result.expect("timed out"),
+    }
+}
+
+fn message(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+        .collect()
+}
+
+#[tokio::test]
+async fn messages_cross_a_pty() {
+    let (a, b, _) = cable(1, 2);
+    let (mut ia, mut ib) = (a.interface(), b.interface());
+    with_engines(&a, &b, async {
+        let short = message(100, 1);
+        ia.send(2, &short).await.unwrap();
+        assert_eq!(
+            ib.recv().await,
+            Message {
+                sender: 1,
+                recipient: 2,
+                contents: short,
+            }
+        );
+
+        let long = message(5000, 2);
+        ib.send(BROADCAST, &long).await.unwrap();
+        let received = ia.recv().await;
+        assert_eq!((received.sender, received.recipient), (2, BROADCAST));
+        assert_eq!(received.contents, long);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn messages_over_64k_cross_a_pty() {
+    let (a, b, _) = cable(1, 2);
+    let (mut ia, mut ib) = (a.interface(), b.interface());
+    with_engines(&a, &b, async {
+        // More than one source block, and more than the old 16-bit length allowed
+        let huge = message(200_000, 3);
+        // Far more than the line and the queues hold, so receive while sending
+        let (sent, received) = tokio::join!(ia.send(2, &huge), ib.recv());
+        sent.unwrap();
+        assert_eq!(received.contents, huge);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn messages_too_long_for_any_link_are_refused() {
+    let (a, _, _) = cable(1, 2);
+    let mut ia = a.interface();
+    let len = MAX_MESSAGE_LEN + 1;
+    assert_eq!(
+        ia.send(2, &message(len, 4)).await,
+        Err(FrameError::MessageTooLong(len))
+    );
+    assert!(ia
+        .send_message(net_interface::Message::new(1, 2, message(len, 4)))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn messages_for_others_are_ignored() {
+    let (a, b, _) = cable(1, 2);
+    let (mut ia, mut ib) = (a.interface(), b.interface());
+    with_engines(&a, &b, async {
+        ia.send(3, &message(200, 1)).await.unwrap();
+        let mine = message(200, 2);
+        ia.send(2, &mine).await.unwrap();
+        assert_eq!(ib.recv().await.contents, mine);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn interfaces_work_as_network_interfaces() {
+    let (a, b, _) = cable(1, 2);
+    let (mut ia, mut ib) = (a.interface(), b.interface());
+    with_engines(&a, &b, async {
+        let msg = message(400, 5);
+        ia.send_message(net_interface::Message::new(1, 2, msg.clone()))
+            .await
+            .unwrap();
+        let received = ib.recv_message().await.unwrap();
+        assert_eq!((received.sender, received.recipient), (1, 2));
+        assert_eq!(*received.contents, *msg);
+        assert_eq!(ib.my_address(), 2);
+        // The address is the engine's, so there's nothing to move
+        assert_eq!(ib.reassign_address(), 2);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn line_noise_is_skipped() {
+    let (a, b, mut line) = cable(1, 2);
+    let (mut ia, mut ib) = (a.interface(), b.interface());
+    with_engines(&a, &b, async {
+        // Garbage, including a partial magic and a truncated frame, before the real thing
+        io::Write::write_all(&mut line, &[0x00, 0xF0, 0x0F, 0x55, 0xF0, 0x0F, 0xF0, 0x01]).unwrap();
+        let msg = message(300, 3);
+        ia.send(2, &msg).await.unwrap();
+        assert_eq!(ib.recv().await.contents, msg);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn ttys_open_by_path() {
+    let (master, slave) = openpty();
+    let path = std::fs::read_link(format!("/proc/self/fd/{}", slave.as_raw_fd())).unwrap();
+    let a = uart_link::host::open(&path, Box::leak(Box::new(AtomicU16::new(1)))).unwrap();
+    let b = UartEngine::new(
+        Tty::from_file(master.try_clone().unwrap()).unwrap(),
+        Tty::from_file(master).unwrap(),
+        Box::leak(Box::new(AtomicU16::new(2))),
+    );
+    let (mut ia, mut ib) = (a.interface(), b.interface());
+    with_engines(&a, &b, async {
+        let msg = message(1000, 4);
+        ib.send(1, &msg).await.unwrap();
+        assert_eq!(ia.recv().await.contents, msg);
+    })
+    .await;
+}
@@ -0,0 +1,384 @@
+//! A network link over UDP multicast, so that processes on a host can take part in the mesh.
+//!
+//! ## Theory of Operation
+//!
+//! Every node on the link joins the same multicast group, and everything is sent to the group.
+//! The group stands in for the air: all nodes hear all packets, and each keeps only those
+//! addressed to it or broadcast, along with dropping its own, which multicast loops back.
+//!
+//! Messages are framed with [`fountain_framing`] as on the radio links, one frame per datagram,
+//! and carry the same `u16` sender and recipient addresses. What's in a message is up to the
+//! caller; the firmware's sync layer sends its sealed sync messages as is, and a host node that
+//! does the same can sync with it through a gateway. Chunks are much bigger than on the radios,
+//! since a datagram can carry a kilobyte without being fragmented on any ordinary network.
+//!
+//! Loopback and wired networks rarely lose datagrams, so little redundancy is sent. Messages
+//! that stall anyway are finished with [`RepairRequest`]s, answered from an [`EncoderCache`] of
+//! the last few messages sent. Only requests from [peers](UdpInterface::add_peer) are answered.
+//!
+//! There is no engine to run. A [`UdpInterface`] owns its socket, and does everything from
+//! [`send`](UdpInterface::send) and [`recv`](UdpInterface::recv). Repair requests from other
+//! nodes are answered while receiving, so keep a `recv` going.
+//!
+//! [`UdpInterface`] also implements [`NetworkInterface`], so sync code written for the firmware's
+//! links runs over it unchanged.
+
+use std::{
+    fmt, io,
+    net::{Ipv4Addr, SocketAddrV4},
+};
+
+pub use fountain_framing::FrameError;
+use fountain_framing::{
+    max_frame_size, Decoder, Encoder, EncoderCache, FecConfig, LossReports, Packet, RepairRequest,
+};
+use net_interface::{NetworkError, NetworkInterface, MAX_MESSAGE_LEN};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::UdpSocket,
+    time::{sleep_until, Duration, Instant},
+};
+
+/// The chunk size. A frame fits in a datagram under the 1500-byte Ethernet MTU.
+pub const UDP_CHUNK_SIZE: usize = 1024;
+const UDP_FRAME_SIZE: usize = max_frame_size(UDP_CHUNK_SIZE);
+/// The largest message that can be sent, which is the most any link carries, so that a host can
+/// pass on whatever it gets to a board.
+pub const UDP_MTU: usize = MAX_MESSAGE_LEN;
+/// The broadcast address.
+pub const BROADCAST: u16 = 0;
+/// The multicast group nodes join unless told otherwise. It's in the organization-local scope,
+/// so it won't be routed off site.
+pub const DEFAULT_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 65, 82), 4265);
+/// Datagrams are rarely lost, so send just enough repair to ride out the odd one.
+const UDP_FEC: FecConfig = FecConfig {
+    min_overhead: 5,
+    max_overhead: 50,
+    min_repair: 1,
+};
+/// How big a receive buffer to ask for, so that a burst of packets from several nodes isn't
+/// dropped before we get to it.
+const RECV_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// How often to ask for repairs to messages that have stopped arriving. Packets follow each
+/// other as fast as the network allows, so a short gap means something went wrong.
+const REPAIR_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Where a [`UdpInterface`] sends and listens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpConfig {
+    /// The multicast group and port shared by every node on the link.
+    pub group: SocketAddrV4,
+    /// The address of the local network interface to join the group on.
+    pub interface: Ipv4Addr,
+    /// How many routers packets may cross. 1 keeps them on the local network.
+    pub ttl: u32,
+}
+
+impl UdpConfig {
+    /// Join `group` on the loopback interface only, for nodes on one host.
+    pub const fn loopback(group: SocketAddrV4) -> UdpConfig {
+        UdpConfig {
+            group,
+            interface: Ipv4Addr::LOCALHOST,
+            ttl: 0,
+        }
+    }
+}
+
+impl Default for UdpConfig {
+    fn default() -> UdpConfig {
+        UdpConfig {
+            group: DEFAULT_GROUP,
+            interface: Ipv4Addr::UNSPECIFIED,
+            ttl: 1,
+        }
+    }
+}
+
+/// Errors sending a message.
+#[derive(Debug)]
+pub enum UdpError {
+    /// The message could not be framed.
+    Frame(FrameError),
+    /// The socket failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for UdpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UdpError::Frame(e) => write!(f, "framing: {e}"),
+            UdpError::Io(e) => write!(f, "socket: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for UdpError {}
+
+impl From<FrameError> for UdpError {
+    fn from(e: FrameError) -> UdpError {
+        UdpError::Frame(e)
+    }
+}
+
+impl From<io::Error> for UdpError {
+    fn from(e: io::Error) -> UdpError {
+        UdpError::Io(e)
+    }
+}
+
+/// A message received from the link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub sender: u16,
+    pub recipient: u16,
+    pub contents: Vec<u8>,
+}
+
+/// One node's connection to the link.
+pub struct UdpInterface {
+    socket: UdpSocket,
+    group: SocketAddrV4,
+    /// This node's address.
+    address: u16,
+    message_seq: u16,
+    decoder: Decoder<UDP_CHUNK_SIZE>,
+    /// The encoders of the last few messages we sent, to answer repair requests.
+    encoders: EncoderCache<UDP_CHUNK_SIZE>,
+    /// The loss each peer reports for our packets.
+    reported_loss: LossReports,
+    /// When to next ask for repairs. Kept here rather than in [`recv`](Self::recv), which may be
+    /// cancelled and restarted at any time.
+    next_repair_poll: Instant,
+    /// When we joined, which loss reports are timed from.
+    joined: Instant,
+}
+
+impl UdpInterface {
+    /// Join the link described by `config` as `address`. Any number of nodes on a host can
+    /// join the same group. This must be called from within a Tokio runtime.
+    pub fn bind(config: &UdpConfig, address: u16) -> io::Result<UdpInterface> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        // Let every node on this host bind the group's port
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.set_recv_buffer_size(RECV_BUFFER_SIZE)?;
+        // Bound to the group address, the socket won't see other traffic to the same port
+        socket.bind(&SocketAddrV4::new(*config.group.ip(), config.group.port()).into())?;
+        socket.join_multicast_v4(config.group.ip(), &config.interface)?;
+        socket.set_multicast_if_v4(&config.interface)?;
+        // Other nodes on this host hear us through the loop
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_multicast_ttl_v4(config.ttl)?;
+        Ok(UdpInterface {
+            socket: UdpSocket::from_std(socket.into())?,
+            group: config.group,
+            address,
+            message_seq: 0,
+            decoder: Decoder::with_max_len(UDP_MTU),
+            encoders: EncoderCache::new(),
+            reported_loss: LossReports::new(),
+            next_repair_poll: Instant::now() + REPAIR_POLL_INTERVAL,
+            joined: Instant::now(),
+        })
+    }
+
+    /// Milliseconds since we joined.
+    fn now_ms(&self) -> u64 {
+        self.joined.elapsed().as_millis() as u64
+    }
+
+    /// This node's address.
+    pub fn address(&self) -> u16 {
+        self.address
+    }
+
+    /// Change this node's address, as when another node turns out to have the same one.
+    pub fn set_address(&mut self, address: u16) {
+        self.address = address;
+    }
+
+    /// Send `contents` to `recipient`.
+    pub async fn send(&mut self, recipient: u16, contents: &[u8]) -> Result<(), UdpError> {
+        if contents.len() > UDP_MTU {
+            return Err(FrameError::MessageTooLong(contents.len()).into());
+        }
+        let (message_seq, _) = self.message_seq.overflowing_add(1);
+        self.message_seq = message_seq;
+        let mut encoder = Encoder::<UDP_CHUNK_SIZE>::with_fec(
+            recipient,
+            self.address,
+            message_seq,
+            contents,
+            &UDP_FEC,
+            self.planned_loss(recipient),
+        )?;
+        for packet in encoder.by_ref() {
+            self.send_packet(&packet).await?;
+        }
+        self.encoders.insert(encoder);
+        Ok(())
+    }
+
+    /// The loss to plan for when sending to `recipient`. Broadcasts plan for the worst
+    /// neighbor.
+    fn planned_loss(&self, recipient: u16) -> Option<u8> {
+        if recipient == BROADCAST {
+            self.reported_loss.worst(self.now_ms())
+        } else {
+            self.reported_loss.get(recipient, self.now_ms())
+        }
+    }
+
+    /// Send a packet to the group.
+    async fn send_packet(&self, packet: &Packet) -> Result<(), UdpError> {
+        let mut frame = [0u8; UDP_FRAME_SIZE];
+        let len = packet.encode(&mut frame)?;
+        self.socket.send_to(&frame[..len], self.group).await?;
+        Ok(())
+    }
+
+    /// Read packets until we assemble a message, then return it.
+    pub async fn recv(&mut self) -> io::Result<Message> {
+        // Room for a frame and then some, so oversized datagrams are seen as such
+        let mut buf = [0u8; UDP_FRAME_SIZE + 1];
+        loop {
+            let len = tokio::select! {
+                result = self.socket.recv(&mut buf) => result?,
+                () = sleep_until(self.next_repair_poll) => {
+                    self.request_repairs().await;
+                    continue;
+                }
+            };
+            let packet = match Packet::decode::<UDP_CHUNK_SIZE>(&buf[..len]) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    log::debug!("udp: bad frame: {e}");
+                    continue;
+                }
+            };
+            if let Some(msg) = self.accept(packet).await {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Handle a received packet, returning a message if it finishes one.
+    async fn accept(&mut self, packet: Packet) -> Option<Message> {
+        let (sender, recipient) = (packet.header.sender, packet.header.recipient);
+        if sender == self.address {
+            // Our own, looped back
+            return None;
+        }
+        if recipient != self.address && recipient != BROADCAST {
+            log::trace!(
+                "udp: packet not for me (address: {}); for {recipient}",
+                self.address
+            );
+            return None;
+        }
+        if let Some(request) = RepairRequest::from_packet(&packet) {
+            let packets = self.encoders.answer(&request);
+            log::debug!(
+                "udp: sending {} repair packets to {}",
+                packets.len(),
+                request.requester
+            );
+            for packet in packets {
+                if let Err(e) = self.send_packet(&packet).await {
+                    log::error!("udp: repair send failed: {e}");
+                    break;
+                }
+            }
+            return None;
+        }
+        match self.decoder.add_packet(&packet) {
+            Ok(Some(contents)) => Some(Message {
+                sender,
+                recipient,
+                contents,
+            }),
+            Ok(None) => None,
+            Err(e) => {
+                log::info!("udp: dropped packet from {sender}: {e}");
+                None
+            }
+        }
+    }
+
+    /// Ask for repairs to messages that have stopped arriving.
+    async fn request_repairs(&mut self) {
+        self.next_repair_poll = Instant::now() + REPAIR_POLL_INTERVAL;
+        for request in self.decoder.repair_requests(self.address) {
+            log::debug!(
+                "udp: asking {} for {} repair packets",
+                request.sender,
+                request.count
+            );
+            if let Err(e) = self.send_packet(&request.to_packet()).await {
+                log::error!("udp: repair request failed: {e}");
+            }
+        }
+    }
+
+    /// Answer repair requests from `peer`, which sent us a message we know to be genuine.
+    /// Requests from anyone else are ignored.
+    pub fn add_peer(&mut self, peer: u16) {
+        self.encoders.add_peer(peer);
+    }
+
+    /// Our estimate of the percentage of `peer`'s packets we lose.
+    pub fn rx_loss(&self, peer: &u16) -> Option<u8> {
+        self.decoder.loss(*peer)
+    }
+
+    /// `peer` reports losing `loss` percent of our packets.
+    pub fn set_tx_loss(&mut self, peer: u16, loss: u8) {
+        self.reported_loss.insert(peer, loss, self.now_ms());
+    }
+}
+
+impl NetworkInterface for UdpInterface {
+    type Addr = u16;
+    const BROADCAST: u16 = BROADCAST;
+    const NAME: &'static str = "udp";
+
+    async fn send_message(&mut self, msg: net_interface::Message<u16>) -> Result<(), NetworkError> {
+        self.send(msg.recipient, &msg.contents)
+            .await
+            .map_err(|e| NetworkError::Send(format!("udp: {e}")))
+    }
+
+    async fn recv_message(&mut self) -> Result<net_interface::Message<u16>, NetworkError> {
+        let msg = self
+            .recv()
+            .await
+            .map_err(|e| NetworkError::Receive(format!("udp: {e}")))?;
+        Ok(net_interface::Message::new(
+            msg.sender,
+            msg.recipient,
+            msg.contents,
+        ))
+    }
+
+    fn my_address(&self) -> u16 {
+        self.address
+    }
+
+    fn mtu(&self, _peer: &u16) -> usize {
+        UDP_MTU
+    }
+
+    fn rx_loss(&self, peer: &u16) -> Option<u8> {
+        UdpInterface::rx_loss(self, peer)
+    }
+
+    fn set_tx_loss(&mut self, peer: u16, loss: u8) {
+        UdpInterface::set_tx_loss(self, peer, loss)
+    }
+
+    fn authenticated(&mut self, peer: u16) {
+        self.add_peer(peer)
+    }
+}
@@ -0,0 +1,185 @@
+//! Nodes talking over multicast on loopback, standing in for hosts on a LAN. Each test uses its
+//! own port so that tests running at once don't hear each other.
+
+use std::{
+    future::Future,
+    net::{Ipv4Addr, SocketAddrV4},
+    process::Command,
+    time::Duration,
+};
+
+use net_interface::NetworkInterface;
+use udp_link::{Message, UdpConfig, UdpInterface, BROADCAST};
+
+/// Set in the environment of a child process started by `processes_form_a_mesh`, to the
+/// child's address and the number of nodes.
+const NODE_VAR: &str = "UDP_LINK_TEST_NODE";
+
+fn config(port: u16) -> UdpConfig {
+    UdpConfig::loopback(SocketAddrV4::new(Ipv4Addr::new(239, 255, 65, 82), port))
+}
+
+fn node(port: u16, address: u16) -> UdpInterface {
+    UdpInterface::bind(&config(port), address).expect("can join the group")
+}
+
+async fn timeout<T>(test: impl Future<Output = T>) -> T {
+    tokio::time::timeout(Duration::from_secs(10), test)
+        .await
+        .expect("timed out")
+}
+
+fn message(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+        .collect()
+}
+
+#[tokio::test]
+async fn messages_cross_loopback() {
+    let (mut a, mut b, mut c) = (node(42651, 1), node(42651, 2), node(42651, 3));
+    timeout(async {
+        let short = message(100, 1);
+        a.send(2, &short).await.unwrap();
+        assert_eq!(
+            b.recv().await.unwrap(),
+            Message {
+                sender: 1,
+                recipient: 2,
+                contents: short,
+            }
+        );
+
+        // Several chunks, to everyone
+        let long = message(20_000, 2);
+        b.send(BROADCAST, &long).await.unwrap();
+        for node in [&mut a, &mut c] {
+            let received = node.recv().await.unwrap();
+            assert_eq!((received.sender, received.recipient), (2, BROADCAST));
+            assert_eq!(received.contents, long);
+        }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn messages_over_64k_cross_loopback() {
+    let (mut a, mut b) = (node(42655, 1), node(42655, 2));
+    timeout(async {
+        // More than one source block
+        let huge = message(400_000, 4);
+        a.send(2, &huge).await.unwrap();
+        assert_eq!(b.recv().await.unwrap().contents, huge);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn interfaces_work_as_network_interfaces() {
+    let (mut a, mut b) = (node(42656, 1), node(42656, 2));
+    timeout(async {
+        let msg = message(3000, 5);
+        a.send_message(net_interface::Message::new(1, 2, msg.clone()))
+            .await
+            .unwrap();
+        let received = b.recv_message().await.unwrap();
+        assert_eq!((received.sender, received.recipient), (1, 2));
+        assert_eq!(*received.contents, *msg);
+        assert_eq!(b.my_address(), 2);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn messages_for_others_are_ignored() {
+    let (mut a, mut b) = (node(42652, 1), node(42652, 2));
+    timeout(async {
+        a.send(3, &message(200, 1)).await.unwrap();
+        // Our own broadcasts come back to us, and must be dropped too
+        b.send(BROADCAST, &message(200, 2)).await.unwrap();
+        let mine = message(200, 3);
+        a.send(2, &mine).await.unwrap();
+        assert_eq!(b.recv().await.unwrap().contents, mine);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn garbage_is_skipped() {
+    let (mut a, mut b) = (node(42653, 1), node(42653, 2));
+    let group = config(42653).group;
+    let noise = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    timeout(async {
+        for junk in [
+            &[0x00, 0xF0, 0x0F][..],
+            &[0xF0, 0x0F, 0xF0, 0, 2, 0, 1],
+            &[0x55; 2000],
+        ] {
+            noise.send_to(junk, group).unwrap();
+        }
+        let msg = message(300, 3);
+        a.send(2, &msg).await.unwrap();
+        assert_eq!(b.recv().await.unwrap().contents, msg);
+    })
+    .await;
+}
+
+/// Run as a child of `processes_form_a_mesh`. Broadcasts its address until it has heard from
+/// every other node, then keeps going a little longer for anyone who started late.
+#[tokio::test]
+async fn mesh_node() {
+    let Ok(var) = std::env::var(NODE_VAR) else {
+        return;
+    };
+    let (address, nodes) = var.split_once('/').unwrap();
+    let (address, nodes): (u16, u16) = (address.parse().unwrap(), nodes.parse().unwrap());
+    let mut me = node(42654, address);
+    let mut heard = vec![false; nodes.into()];
+    heard[usize::from(address) - 1] = true;
+    let mut done_at = None;
+    let mut interval = tokio::time::interval(Duration::from_millis(100));
+    timeout(async {
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    me.send(BROADCAST, &address.to_be_bytes()).await.unwrap();
+                    match done_at {
+                        Some(0) => break,
+                        Some(ref mut ticks) => *ticks -= 1,
+                        None => (),
+                    }
+                }
+                msg = me.recv() => {
+                    let msg = msg.unwrap();
+                    assert_eq!(msg.contents, msg.sender.to_be_bytes());
+                    heard[usize::from(msg.sender) - 1] = true;
+                    if done_at.is_none() && heard.iter().all(|&h| h) {
+                        done_at = Some(10);
+                    }
+                }
+            }
+        }
+    })
+    .await;
+}
+
+#[test]
+fn processes_form_a_mesh() {
+    if std::env::var(NODE_VAR).is_ok() {
+        return;
+    }
+    const NODES: u16 = 3;
+    let exe = std::env::current_exe().unwrap();
+    let children: Vec<_> = (1..=NODES)
+        .map(|address| {
+            Command::new(&exe)
+                .args(["mesh_node", "--exact", "--nocapture"])
+                .env(NODE_VAR, format!("{address}/{NODES}"))
+                .spawn()
+                .expect("can start a node")
+        })
+        .collect();
+    for mut child in children {
+        assert!(child.wait().unwrap().success(), "a node failed");
+    }
+}